@@ -1,24 +1,150 @@
-use value::ValueType;
+use std::cmp;
+use std::collections::HashSet;
+use std::mem;
 
-#[derive(Debug, Clone, Copy)]
+use heapsize::HeapSizeOf;
+
+use hyperloglog::HyperLogLog;
+use moments::{CoMoments, Moments};
+use space_saving::SpaceSaving;
+use tdigest::{exact_quantile, TDigest};
+use value::{OrderedFloat, ValueType};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Aggregator {
     Sum,
     Count,
+    Min,
+    Max,
+    Avg,
+    CountDistinct,
+    ApproxCountDistinct(usize),
+    Median,
+    Percentile(f64),
+    ExactPercentile(f64),
+    VarPop,
+    VarSamp,
+    Stddev,
+    Covar,
+    Corr,
+    ApproxTopK(usize),
+    /// Sum of the squared values, which estimates the variance of sums over sampled rows.
+    SumOfSquares,
+}
+
+/// Intermediate state of an aggregator for one group. Accumulators of different batches are merged
+/// before the final value is computed.
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Value(ValueType),
+    Average(ValueType, i64),
+    Distinct(HashSet<ValueType>),
+    Sketch(HyperLogLog),
+    Digest(TDigest),
+    Values(Vec<f64>),
+    Moments(Moments),
+    CoMoments(CoMoments),
+    HeavyHitters(SpaceSaving),
+}
+
+impl Accumulator {
+    /// Rough number of bytes taken by the accumulator, computed in constant time.
+    pub fn estimated_bytes(&self) -> usize {
+        let value_size = mem::size_of::<ValueType>();
+        mem::size_of::<Accumulator>() +
+            match self {
+                &Accumulator::Value(ref value) | &Accumulator::Average(ref value, _) => value.heap_size_of_children(),
+                &Accumulator::Distinct(ref set) => set.capacity() * (value_size + mem::size_of::<u64>()),
+                &Accumulator::Sketch(ref sketch) => sketch.registers(),
+                &Accumulator::Digest(ref digest) => digest.len() * 2 * mem::size_of::<f64>(),
+                &Accumulator::Values(ref values) => values.capacity() * mem::size_of::<f64>(),
+                &Accumulator::Moments(_) | &Accumulator::CoMoments(_) => 0,
+                // Counters are kept in a hash map and a sorted set
+                &Accumulator::HeavyHitters(ref summary) => summary.len() * 2 * (value_size + 2 * mem::size_of::<u64>()),
+            }
+    }
 }
 
 impl Aggregator {
-    pub fn zero(self) -> ValueType {
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregator::Sum => "sum",
+            Aggregator::Count => "count",
+            Aggregator::Min => "min",
+            Aggregator::Max => "max",
+            Aggregator::Avg => "avg",
+            Aggregator::CountDistinct => "count_distinct",
+            Aggregator::ApproxCountDistinct(_) => "approx_count_distinct",
+            Aggregator::Median => "median",
+            Aggregator::Percentile(_) => "percentile",
+            Aggregator::ExactPercentile(_) => "exact_percentile",
+            Aggregator::VarPop => "var_pop",
+            Aggregator::VarSamp => "var_samp",
+            Aggregator::Stddev => "stddev",
+            Aggregator::Covar => "covar",
+            Aggregator::Corr => "corr",
+            Aggregator::ApproxTopK(_) => "approx_top_k",
+            Aggregator::SumOfSquares => "sum_of_squares",
+        }
+    }
+
+    pub fn zero(self) -> Accumulator {
         match self {
-            Aggregator::Sum | Aggregator::Count => ValueType::Integer(0),
+            Aggregator::Sum | Aggregator::Count => Accumulator::Value(ValueType::Integer(0)),
+            Aggregator::SumOfSquares => Accumulator::Value(ValueType::Float(OrderedFloat(0.0))),
+            Aggregator::Min | Aggregator::Max => Accumulator::Value(ValueType::Null),
+            Aggregator::Avg => Accumulator::Average(ValueType::Null, 0),
+            Aggregator::CountDistinct => Accumulator::Distinct(HashSet::new()),
+            Aggregator::ApproxCountDistinct(precision) => Accumulator::Sketch(HyperLogLog::new(precision)),
+            Aggregator::Median | Aggregator::Percentile(_) => Accumulator::Digest(TDigest::new()),
+            Aggregator::ExactPercentile(_) => Accumulator::Values(Vec::new()),
+            Aggregator::VarPop | Aggregator::VarSamp | Aggregator::Stddev => Accumulator::Moments(Moments::new()),
+            Aggregator::Covar | Aggregator::Corr => Accumulator::CoMoments(CoMoments::new()),
+            Aggregator::ApproxTopK(k) => Accumulator::HeavyHitters(SpaceSaving::new(cmp::max(10 * k, 100))),
         }
     }
 
-    pub fn reduce(self, accumulator: &ValueType, elem: &ValueType) -> ValueType {
+    pub fn reduce(self, accumulator: &mut Accumulator, elem: &ValueType) {
         match (self, accumulator, elem) {
-            (Aggregator::Sum, &ValueType::Integer(i1), &ValueType::Integer(i2)) => ValueType::Integer(i1 + i2),
-            (Aggregator::Sum, &ValueType::Integer(i1), &ValueType::Null) => ValueType::Integer(i1),
-            (Aggregator::Count, accumulator, &ValueType::Null) => accumulator.clone(),
-            (Aggregator::Count, &ValueType::Integer(i1), _) => ValueType::Integer(i1 + 1),
+            (_, _, &ValueType::Null) => {}
+            (Aggregator::Sum, &mut Accumulator::Value(ref mut sum), elem) => *sum = add(self, sum, elem),
+            (Aggregator::Count, &mut Accumulator::Value(ValueType::Integer(ref mut count)), _) => *count += 1,
+            (Aggregator::SumOfSquares, &mut Accumulator::Value(ValueType::Float(OrderedFloat(ref mut sum))), elem)
+                if elem.as_f64().is_some() => {
+                let x = elem.as_f64().unwrap();
+                *sum += x * x
+            }
+            (Aggregator::Min, &mut Accumulator::Value(ref mut min), elem) => {
+                if *min == ValueType::Null || elem < min {
+                    *min = elem.clone();
+                }
+            }
+            (Aggregator::Max, &mut Accumulator::Value(ref mut max), elem) => {
+                if *max == ValueType::Null || elem > max {
+                    *max = elem.clone();
+                }
+            }
+            (Aggregator::Avg, &mut Accumulator::Average(ref mut sum, ref mut count), elem) if elem.as_f64().is_some() => {
+                *sum = add(self, sum, elem);
+                *count += 1;
+            }
+            (Aggregator::CountDistinct, &mut Accumulator::Distinct(ref mut set), elem) => {
+                if !set.contains(elem) {
+                    set.insert(elem.clone());
+                }
+            }
+            (Aggregator::ApproxCountDistinct(_), &mut Accumulator::Sketch(ref mut sketch), elem) => sketch.insert(elem),
+            (Aggregator::Median, &mut Accumulator::Digest(ref mut digest), elem) |
+            (Aggregator::Percentile(_), &mut Accumulator::Digest(ref mut digest), elem) if elem.as_f64().is_some() => {
+                digest.insert(elem.as_f64().unwrap())
+            }
+            (Aggregator::ExactPercentile(_), &mut Accumulator::Values(ref mut values), elem) if elem.as_f64().is_some() => {
+                values.push(elem.as_f64().unwrap())
+            }
+            (_, &mut Accumulator::Moments(ref mut moments), elem) if elem.as_f64().is_some() => {
+                moments.insert(elem.as_f64().unwrap())
+            }
+            (_, &mut Accumulator::HeavyHitters(ref mut summary), elem) => summary.insert(elem),
             (aggregator, accumulator, elem) => {
                 panic!(
                     "Type error: aggregator {:?} not defined for values {:?} and {:?}",
@@ -29,4 +155,187 @@ impl Aggregator {
             }
         }
     }
+
+    /// Adds a row to the accumulator of a two-argument aggregator like `covar(x, y)`.
+    pub fn reduce_pair(self, accumulator: &mut Accumulator, x: &ValueType, y: &ValueType) {
+        match (accumulator, x.as_f64(), y.as_f64()) {
+            _ if *x == ValueType::Null || *y == ValueType::Null => {}
+            (&mut Accumulator::CoMoments(ref mut comoments), Some(x), Some(y)) => comoments.insert(x, y),
+            (accumulator, _, _) => {
+                panic!(
+                    "Type error: aggregator {:?} not defined for values {:?}, {:?} and {:?}",
+                    self,
+                    *accumulator,
+                    *x,
+                    *y
+                )
+            }
+        }
+    }
+
+    pub fn merge(self, accumulator: &mut Accumulator, other: Accumulator) {
+        match (self, accumulator, other) {
+            (Aggregator::Sum, &mut Accumulator::Value(ref mut sum1), Accumulator::Value(ref sum2)) |
+            (Aggregator::Count, &mut Accumulator::Value(ref mut sum1), Accumulator::Value(ref sum2)) |
+            (Aggregator::SumOfSquares, &mut Accumulator::Value(ref mut sum1), Accumulator::Value(ref sum2)) => {
+                *sum1 = add(self, sum1, sum2)
+            }
+            (Aggregator::Min, accumulator, Accumulator::Value(ref value)) |
+            (Aggregator::Max, accumulator, Accumulator::Value(ref value)) => self.reduce(accumulator, value),
+            (Aggregator::Avg,
+             &mut Accumulator::Average(ref mut sum1, ref mut count1),
+             Accumulator::Average(ref sum2, count2)) => {
+                *sum1 = add(self, sum1, sum2);
+                *count1 += count2;
+            }
+            (Aggregator::CountDistinct, &mut Accumulator::Distinct(ref mut set1), Accumulator::Distinct(set2)) => {
+                set1.extend(set2)
+            }
+            (Aggregator::ApproxCountDistinct(_),
+             &mut Accumulator::Sketch(ref mut sketch1),
+             Accumulator::Sketch(ref sketch2)) => sketch1.merge(sketch2),
+            (Aggregator::Median, &mut Accumulator::Digest(ref mut digest1), Accumulator::Digest(digest2)) |
+            (Aggregator::Percentile(_), &mut Accumulator::Digest(ref mut digest1), Accumulator::Digest(digest2)) => {
+                digest1.merge(digest2)
+            }
+            (Aggregator::ExactPercentile(_), &mut Accumulator::Values(ref mut values1), Accumulator::Values(values2)) => {
+                values1.extend(values2)
+            }
+            (_, &mut Accumulator::Moments(ref mut moments1), Accumulator::Moments(ref moments2)) => {
+                moments1.merge(moments2)
+            }
+            (_, &mut Accumulator::CoMoments(ref mut comoments1), Accumulator::CoMoments(ref comoments2)) => {
+                comoments1.merge(comoments2)
+            }
+            (_, &mut Accumulator::HeavyHitters(ref mut summary1), Accumulator::HeavyHitters(summary2)) => {
+                summary1.merge(summary2)
+            }
+            (aggregator, accumulator1, accumulator2) => {
+                panic!(
+                    "Type error: cannot merge {:?} accumulators {:?} and {:?}",
+                    aggregator,
+                    *accumulator1,
+                    accumulator2
+                )
+            }
+        }
+    }
+
+    pub fn finalize(self, accumulator: Accumulator) -> ValueType {
+        match accumulator {
+            Accumulator::Value(value) => value,
+            Accumulator::Distinct(set) => ValueType::Integer(set.len() as i64),
+            Accumulator::Sketch(sketch) => ValueType::Integer(sketch.estimate() as i64),
+            Accumulator::Digest(digest) => float_or_null(digest.quantile(self.quantile())),
+            Accumulator::Values(values) => float_or_null(exact_quantile(values, self.quantile())),
+            Accumulator::Moments(moments) => {
+                float_or_null(match self {
+                    Aggregator::VarPop => moments.var_pop(),
+                    Aggregator::Stddev => moments.var_samp().map(f64::sqrt),
+                    _ => moments.var_samp(),
+                })
+            }
+            Accumulator::CoMoments(comoments) => {
+                float_or_null(match self {
+                    Aggregator::Corr => comoments.corr(),
+                    _ => comoments.covar_samp(),
+                })
+            }
+            Accumulator::HeavyHitters(_) => panic!("approx_top_k is finalized into a row per value by finalize_group"),
+            Accumulator::Average(_, 0) => ValueType::Null,
+            Accumulator::Average(ValueType::Timestamp(t), count) => ValueType::Timestamp(t / count as u64),
+            Accumulator::Average(sum, count) => {
+                match sum.as_f64() {
+                    Some(sum) => ValueType::Float(OrderedFloat(sum / count as f64)),
+                    None => ValueType::Null,
+                }
+            }
+        }
+    }
+
+    /// Whether the aggregator yields a row per value rather than a single value, see `finalize_group`.
+    pub fn is_top_k(self) -> bool {
+        match self {
+            Aggregator::ApproxTopK(_) => true,
+            _ => false,
+        }
+    }
+
+    fn quantile(self) -> f64 {
+        match self {
+            Aggregator::Percentile(q) | Aggregator::ExactPercentile(q) => q,
+            _ => 0.5,
+        }
+    }
+}
+
+/// Finalizes the accumulators of a group into rows that start with the group `key`, followed by the value of each
+/// aggregator. An approx_top_k aggregator yields a row for each of its most frequent values, with the value in its
+/// column and the value's count and maximum overestimation appended after all aggregates. A query computes at most
+/// one approx_top_k aggregate.
+pub fn finalize_group(
+    aggregators: &Vec<Aggregator>,
+    mut key: Vec<ValueType>,
+    accumulators: Vec<Accumulator>,
+) -> Vec<Vec<ValueType>> {
+    let mut top = None;
+    for (&aggregator, accumulator) in aggregators.iter().zip(accumulators) {
+        match (aggregator, accumulator) {
+            (Aggregator::ApproxTopK(k), Accumulator::HeavyHitters(summary)) => {
+                top = Some((key.len(), summary.top(k)));
+                key.push(ValueType::Null);
+            }
+            (aggregator, accumulator) => key.push(aggregator.finalize(accumulator)),
+        }
+    }
+    match top {
+        None => vec![key],
+        Some((_, ref values)) if values.is_empty() => {
+            key.push(ValueType::Null);
+            key.push(ValueType::Null);
+            vec![key]
+        }
+        Some((column, values)) => {
+            values
+                .into_iter()
+                .map(|(value, count, error)| {
+                    let mut row = key.clone();
+                    row[column] = value;
+                    row.push(ValueType::Integer(count as i64));
+                    row.push(ValueType::Integer(error as i64));
+                    row
+                })
+                .collect()
+        }
+    }
+}
+
+fn float_or_null(value: Option<f64>) -> ValueType {
+    match value {
+        Some(x) => ValueType::Float(OrderedFloat(x)),
+        None => ValueType::Null,
+    }
+}
+
+fn add(aggregator: Aggregator, value1: &ValueType, value2: &ValueType) -> ValueType {
+    use value::ValueType::*;
+    match (value1, value2) {
+        (&Integer(i1), &Integer(i2)) => Integer(i1 + i2),
+        (&Float(OrderedFloat(f1)), &Float(OrderedFloat(f2))) => Float(OrderedFloat(f1 + f2)),
+        (&Integer(i), &Float(OrderedFloat(f))) |
+        (&Float(OrderedFloat(f)), &Integer(i)) => Float(OrderedFloat(i as f64 + f)),
+        (&Timestamp(t1), &Timestamp(t2)) => Timestamp(t1 + t2),
+        // A sum of timestamps starts out at the integer zero and stays a timestamp
+        (&Integer(0), &Timestamp(t)) | (&Timestamp(t), &Integer(0)) => Timestamp(t),
+        (&Integer(i), &Timestamp(t)) | (&Timestamp(t), &Integer(i)) => Integer(i + t as i64),
+        (&Null, value) | (value, &Null) => value.clone(),
+        (value1, value2) => {
+            panic!(
+                "Type error: aggregator {:?} not defined for values {:?} and {:?}",
+                aggregator,
+                *value1,
+                *value2
+            )
+        }
+    }
 }
@@ -0,0 +1,459 @@
+use arrow_array::builder::{
+    BooleanBuilder,
+    Float64Builder,
+    Int64Builder,
+    ListBuilder,
+    StringBuilder,
+    TimestampSecondBuilder,
+};
+use arrow_array::cast::AsArray;
+use arrow_array::types::*;
+use arrow_array::{new_null_array, Array, ArrayRef, ArrowPrimitiveType, NullArray, RecordBatch};
+use arrow_buffer::ArrowNativeType;
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_ipc::writer::{FileWriter, StreamWriter};
+use arrow_schema::{ArrowError, DataType, Field, Schema, TimeUnit};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use batch_store::BatchStore;
+use columns::{nullable_column, Batch, Column, ColumnType, VecType};
+use query_engine::QueryResult;
+use value::{OrderedFloat, ValueType};
+
+fn data_type(column_type: ColumnType) -> DataType {
+    match column_type {
+        ColumnType::Null => DataType::Null,
+        ColumnType::Bool => DataType::Boolean,
+        ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Second, None),
+        ColumnType::Integer => DataType::Int64,
+        ColumnType::Float => DataType::Float64,
+        ColumnType::Str | ColumnType::Mixed => DataType::Utf8,
+        ColumnType::Set => DataType::new_list(DataType::Utf8, true),
+    }
+}
+
+/// Type of a column holding `values`, for query results that have no column types.
+fn infer_type<I: Iterator<Item = ValueType>>(values: I) -> ColumnType {
+    values.fold(ColumnType::Null, |column_type, value| column_type.merge(ColumnType::of(&value)))
+}
+
+pub fn is_arrow_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        ext == "arrow" || ext == "arrows" || ext == "ipc" || ext == "feather"
+    })
+}
+
+fn is_stream(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "arrows")
+}
+
+/// Reads an Arrow IPC file (or stream, if the path ends in `.arrows`) and converts every record batch
+/// into a `Batch`.
+pub fn read_file(path: &Path) -> Result<Box<dyn Iterator<Item = Result<Batch, ArrowError>>>, ArrowError> {
+    let file = BufReader::new(File::open(path)?);
+    let record_batches: Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>> = if is_stream(path) {
+        Box::new(StreamReader::try_new(file, None)?)
+    } else {
+        Box::new(FileReader::try_new(file, None)?)
+    };
+    Ok(Box::new(record_batches.map(|batch| batch.and_then(|batch| to_batch(&batch)))))
+}
+
+fn to_batch(record_batch: &RecordBatch) -> Result<Batch, ArrowError> {
+    let schema = record_batch.schema();
+    let mut cols = Vec::with_capacity(record_batch.num_columns());
+    for (field, array) in schema.fields().iter().zip(record_batch.columns()) {
+        cols.push(to_column(field.name().clone(), array)?);
+    }
+    Ok(Batch { cols })
+}
+
+fn to_column(name: String, array: &ArrayRef) -> Result<Box<dyn Column>, ArrowError> {
+    let valid: Vec<usize> = (0..array.len()).filter(|&i| array.is_valid(i)).collect();
+    let vec = match *array.data_type() {
+        DataType::Null => return Ok(VecType::NullVec(array.len()).to_column(name)),
+        DataType::Boolean => {
+            let array = array.as_boolean();
+            VecType::BoolVec(valid.iter().map(|&i| array.value(i)).collect())
+        }
+        DataType::Int8 => VecType::IntegerVec(integers::<Int8Type>(array, &valid)?),
+        DataType::Int16 => VecType::IntegerVec(integers::<Int16Type>(array, &valid)?),
+        DataType::Int32 => VecType::IntegerVec(integers::<Int32Type>(array, &valid)?),
+        DataType::Int64 => VecType::IntegerVec(integers::<Int64Type>(array, &valid)?),
+        DataType::UInt8 => VecType::IntegerVec(integers::<UInt8Type>(array, &valid)?),
+        DataType::UInt16 => VecType::IntegerVec(integers::<UInt16Type>(array, &valid)?),
+        DataType::UInt32 => VecType::IntegerVec(integers::<UInt32Type>(array, &valid)?),
+        DataType::UInt64 => VecType::IntegerVec(integers::<UInt64Type>(array, &valid)?),
+        DataType::Float32 => {
+            let array = array.as_primitive::<Float32Type>();
+            VecType::FloatVec(valid.iter().map(|&i| array.value(i) as f64).collect())
+        }
+        DataType::Float64 => {
+            let array = array.as_primitive::<Float64Type>();
+            VecType::FloatVec(valid.iter().map(|&i| array.value(i)).collect())
+        }
+        DataType::Timestamp(unit, _) => {
+            let (values, per_second) = match unit {
+                TimeUnit::Second => (integers::<TimestampSecondType>(array, &valid)?, 1),
+                TimeUnit::Millisecond => (integers::<TimestampMillisecondType>(array, &valid)?, 1_000),
+                TimeUnit::Microsecond => (integers::<TimestampMicrosecondType>(array, &valid)?, 1_000_000),
+                TimeUnit::Nanosecond => (integers::<TimestampNanosecondType>(array, &valid)?, 1_000_000_000),
+            };
+            // Timestamps are whole seconds, so rather than silently truncating sub-second times the file is rejected
+            if let Some(t) = values.iter().find(|&&t| t % per_second != 0) {
+                return Err(ArrowError::NotYetImplemented(format!(
+                    "Column {} has timestamp {} ({:?}) with a fraction of a second, which is not supported",
+                    name, t, unit
+                )));
+            }
+            let seconds: Vec<i64> = values.into_iter().map(|t| t / per_second).collect();
+            if seconds.iter().all(|&t| t >= 0) {
+                VecType::TimestampVec(seconds.into_iter().map(|t| t as u64).collect())
+            } else {
+                // Timestamps are unsigned, so times before 1970 are kept as integers like in `time_bucket`
+                VecType::MixedVec(
+                    seconds
+                        .into_iter()
+                        .map(|t| if t >= 0 { ValueType::Timestamp(t as u64) } else { ValueType::Integer(t) })
+                        .collect(),
+                )
+            }
+        }
+        DataType::Utf8 => {
+            let array = array.as_string::<i32>();
+            VecType::StringVec(valid.iter().map(|&i| array.value(i).to_string()).collect())
+        }
+        DataType::LargeUtf8 => {
+            let array = array.as_string::<i64>();
+            VecType::StringVec(valid.iter().map(|&i| array.value(i).to_string()).collect())
+        }
+        DataType::List(ref item) if *item.data_type() == DataType::Utf8 => {
+            let array = array.as_list::<i32>();
+            VecType::SetVec(
+                valid
+                    .iter()
+                    .map(|&i| {
+                        let set = array.value(i);
+                        let set = set.as_string::<i32>();
+                        set.iter().filter_map(|s| s.map(|s| s.to_string())).collect()
+                    })
+                    .collect(),
+            )
+        }
+        ref other => {
+            return Err(ArrowError::NotYetImplemented(
+                format!("Column {} has unsupported type {}", name, other),
+            ))
+        }
+    };
+    if valid.len() == array.len() {
+        Ok(vec.to_column(name))
+    } else {
+        let present = (0..array.len()).map(|i| array.is_valid(i)).collect();
+        Ok(nullable_column(name, present, vec))
+    }
+}
+
+fn integers<T: ArrowPrimitiveType>(array: &ArrayRef, valid: &[usize]) -> Result<Vec<i64>, ArrowError> {
+    let array = array.as_primitive::<T>();
+    valid
+        .iter()
+        .map(|&i| {
+            array.value(i).to_i64().ok_or_else(|| {
+                ArrowError::ComputeError(format!("Value {:?} does not fit into a 64-bit integer", array.value(i)))
+            })
+        })
+        .collect()
+}
+
+/// Writes all batches as Arrow record batches. Paths ending in `.arrows` are written in the IPC
+/// streaming format, everything else in the IPC file format.
+pub fn write_table(path: &Path, batches: &BatchStore) -> Result<(), ArrowError> {
+    let schema = Arc::new(Schema::new(
+        batches
+            .column_names()
+            .iter()
+            .zip(batches.column_types())
+            .map(|(name, &column_type)| Field::new(name.clone(), data_type(column_type), true))
+            .collect::<Vec<_>>(),
+    ));
+
+    let mut writer = IpcWriter::try_new(path, &schema)?;
+    for i in 0..batches.len() {
+        let batch = batches.get(i).map_err(ArrowError::ComputeError)?;
+        let record_batch = to_record_batch(&batch, &schema, batches.column_types())?;
+        writer.write(&record_batch)?;
+    }
+    writer.finish()
+}
+
+pub fn write_query_result(path: &Path, result: &QueryResult) -> Result<(), ArrowError> {
+    let columns: Vec<Vec<ValueType>> = (0..result.colnames.len())
+        .map(|i| result.rows.iter().map(|row| row[i].clone()).collect())
+        .collect();
+    let types: Vec<ColumnType> = columns
+        .iter()
+        .map(|values| infer_type(values.iter().cloned()))
+        .collect();
+    let schema = Arc::new(Schema::new(
+        result
+            .colnames
+            .iter()
+            .zip(types.iter())
+            .map(|(name, &column_type)| Field::new(name.as_str(), data_type(column_type), true))
+            .collect::<Vec<_>>(),
+    ));
+    let arrays = columns
+        .into_iter()
+        .zip(types)
+        .map(|(values, column_type)| build_array(column_type, values.len(), values.into_iter()))
+        .collect();
+
+    let mut writer = IpcWriter::try_new(path, &schema)?;
+    writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?;
+    writer.finish()
+}
+
+fn to_record_batch(batch: &Batch, schema: &Arc<Schema>, types: &Vec<ColumnType>) -> Result<RecordBatch, ArrowError> {
+    let len = batch.cols.iter().map(|col| col.len()).max().unwrap_or(0);
+    let arrays = schema
+        .fields()
+        .iter()
+        .zip(types)
+        .map(|(field, &column_type)| match batch.cols.iter().find(|col| col.get_name() == field.name()) {
+            Some(col) => build_array(column_type, len, col.iter()),
+            None => new_null_array(&data_type(column_type), len),
+        })
+        .collect();
+    RecordBatch::try_new(schema.clone(), arrays)
+}
+
+fn build_array<I: Iterator<Item = ValueType>>(column_type: ColumnType, len: usize, values: I) -> ArrayRef {
+    match column_type {
+        ColumnType::Null => Arc::new(NullArray::new(len)),
+        ColumnType::Bool => {
+            let mut builder = BooleanBuilder::with_capacity(len);
+            for value in values {
+                match value {
+                    ValueType::Bool(b) => builder.append_value(b),
+                    _ => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Timestamp => {
+            let mut builder = TimestampSecondBuilder::with_capacity(len);
+            for value in values {
+                match value {
+                    ValueType::Timestamp(t) => builder.append_value(t as i64),
+                    _ => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Integer => {
+            let mut builder = Int64Builder::with_capacity(len);
+            for value in values {
+                match value {
+                    ValueType::Integer(i) => builder.append_value(i),
+                    _ => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Float => {
+            let mut builder = Float64Builder::with_capacity(len);
+            for value in values {
+                match value {
+                    ValueType::Float(OrderedFloat(x)) => builder.append_value(x),
+                    ValueType::Integer(i) => builder.append_value(i as f64),
+                    _ => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Str | ColumnType::Mixed => {
+            let mut builder = StringBuilder::with_capacity(len, len * 8);
+            for value in values {
+                match value {
+                    ValueType::Null => builder.append_null(),
+                    ValueType::Str(s) => builder.append_value(s.as_str()),
+                    other => builder.append_value(format!("{}", other)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Set => {
+            let mut builder = ListBuilder::new(StringBuilder::new());
+            for value in values {
+                match value {
+                    ValueType::Set(set) => {
+                        for s in set.iter() {
+                            builder.values().append_value(s);
+                        }
+                        builder.append(true);
+                    }
+                    _ => builder.append_null(),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+    }
+}
+
+enum IpcWriter {
+    File(FileWriter<File>),
+    Stream(StreamWriter<File>),
+}
+
+impl IpcWriter {
+    fn try_new(path: &Path, schema: &Schema) -> Result<IpcWriter, ArrowError> {
+        let file = File::create(path)?;
+        if is_stream(path) {
+            Ok(IpcWriter::Stream(StreamWriter::try_new(file, schema)?))
+        } else {
+            Ok(IpcWriter::File(FileWriter::try_new(file, schema)?))
+        }
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ArrowError> {
+        match *self {
+            IpcWriter::File(ref mut writer) => writer.write(batch),
+            IpcWriter::Stream(ref mut writer) => writer.write(batch),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), ArrowError> {
+        match *self {
+            IpcWriter::File(ref mut writer) => writer.finish(),
+            IpcWriter::Stream(ref mut writer) => writer.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{
+        TimestampMicrosecondArray,
+        TimestampMillisecondArray,
+        TimestampNanosecondArray,
+        TimestampSecondArray,
+    };
+    use columns::columnarize;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn temp_file(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rividb-{}-{}", process::id(), name))
+    }
+
+    /// Reads back the schema and the values of every column of an Arrow IPC file.
+    fn read_back(path: &Path) -> (Vec<(String, DataType)>, Vec<Vec<ValueType>>) {
+        let reader = FileReader::try_new(File::open(path).unwrap(), None).unwrap();
+        let fields = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| (field.name().clone(), field.data_type().clone()))
+            .collect();
+        let mut columns = Vec::new();
+        for batch in read_file(path).unwrap() {
+            columns = batch.unwrap().cols.iter().map(|col| col.iter().collect()).collect();
+        }
+        (fields, columns)
+    }
+
+    #[test]
+    fn exports_columns_with_nulls_as_their_value_type() {
+        let record = |i: ValueType, f: ValueType, s: ValueType, n: ValueType| {
+            vec![
+                ("i".to_string(), i),
+                ("f".to_string(), f),
+                ("s".to_string(), s),
+                ("n".to_string(), n),
+            ]
+        };
+        let mut table = BatchStore::new(None);
+        table
+            .push(columnarize(vec![
+                record(
+                    ValueType::Integer(1),
+                    ValueType::Float(OrderedFloat(0.5)),
+                    ValueType::Null,
+                    ValueType::Integer(1),
+                ),
+                record(ValueType::Null, ValueType::Null, ValueType::Str(Arc::new("a".to_string())), ValueType::Null),
+                record(
+                    ValueType::Integer(3),
+                    ValueType::Float(OrderedFloat(1.5)),
+                    ValueType::Null,
+                    ValueType::Float(OrderedFloat(2.5)),
+                ),
+            ]))
+            .unwrap();
+        let path = temp_file("nulls.arrow");
+        write_table(&path, &table).unwrap();
+        let (fields, columns) = read_back(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            fields,
+            vec![
+                ("f".to_string(), DataType::Float64),
+                ("i".to_string(), DataType::Int64),
+                ("n".to_string(), DataType::Float64),
+                ("s".to_string(), DataType::Utf8),
+            ]
+        );
+        assert_eq!(
+            columns,
+            vec![
+                vec![ValueType::Float(OrderedFloat(0.5)), ValueType::Null, ValueType::Float(OrderedFloat(1.5))],
+                vec![ValueType::Integer(1), ValueType::Null, ValueType::Integer(3)],
+                vec![ValueType::Float(OrderedFloat(1.0)), ValueType::Null, ValueType::Float(OrderedFloat(2.5))],
+                vec![ValueType::Null, ValueType::Str(Arc::new("a".to_string())), ValueType::Null],
+            ]
+        );
+    }
+
+    fn import_timestamps(unit: TimeUnit, values: Vec<i64>) -> Result<Vec<ValueType>, ArrowError> {
+        let array: ArrayRef = match unit {
+            TimeUnit::Second => Arc::new(TimestampSecondArray::from(values)),
+            TimeUnit::Millisecond => Arc::new(TimestampMillisecondArray::from(values)),
+            TimeUnit::Microsecond => Arc::new(TimestampMicrosecondArray::from(values)),
+            TimeUnit::Nanosecond => Arc::new(TimestampNanosecondArray::from(values)),
+        };
+        let schema = Arc::new(Schema::new(vec![Field::new("ts", array.data_type().clone(), true)]));
+        let batch = to_batch(&RecordBatch::try_new(schema, vec![array])?)?;
+        Ok(batch.cols[0].iter().collect())
+    }
+
+    #[test]
+    fn imports_timestamps_of_every_unit() {
+        let units = vec![
+            (TimeUnit::Second, 1),
+            (TimeUnit::Millisecond, 1_000),
+            (TimeUnit::Microsecond, 1_000_000),
+            (TimeUnit::Nanosecond, 1_000_000_000),
+        ];
+        for (unit, per_second) in units {
+            let values = vec![1_500_000_000 * per_second, 0, -3 * per_second];
+            assert_eq!(
+                import_timestamps(unit, values).unwrap(),
+                vec![ValueType::Timestamp(1_500_000_000), ValueType::Timestamp(0), ValueType::Integer(-3)]
+            );
+            if per_second > 1 {
+                for &t in &[1_500_000_000 * per_second + 1, -per_second / 2] {
+                    let err = import_timestamps(unit, vec![0, t]).unwrap_err();
+                    assert!(format!("{}", err).contains("fraction of a second"));
+                }
+            }
+        }
+    }
+}
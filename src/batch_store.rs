@@ -0,0 +1,238 @@
+use heapsize::HeapSizeOf;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use columns::{Batch, ColumnType};
+use spill;
+use zone_map::ZoneMap;
+
+static NEXT_STORE_ID: AtomicUsize = AtomicUsize::new(0);
+static NEXT_BATCH_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps the heap size of all loaded batches below an optional memory limit by spilling
+/// least recently queried batches to disk and reading them back in on access.
+/// Batches that are evicted while a query thread still scans them stay alive until that scan finishes.
+pub struct BatchStore {
+    slots: Vec<Mutex<Slot>>,
+    zone_maps: Vec<ZoneMap>,
+    /// Ids of the batches, unique across all tables.
+    batch_ids: Vec<usize>,
+    rows: usize,
+    column_names: Vec<String>,
+    /// Type of each of `column_names`, merged over all batches.
+    column_types: Vec<ColumnType>,
+    memory_limit: Option<usize>,
+    spill_dir: PathBuf,
+    clock: AtomicUsize,
+    eviction: Mutex<()>,
+}
+
+struct Slot {
+    batch: Option<Arc<Batch>>,
+    spill_file: Option<PathBuf>,
+    heap_size: usize,
+    last_used: usize,
+}
+
+impl BatchStore {
+    pub fn new(memory_limit: Option<usize>) -> BatchStore {
+        BatchStore {
+            slots: Vec::new(),
+            zone_maps: Vec::new(),
+            batch_ids: Vec::new(),
+            rows: 0,
+            column_names: Vec::new(),
+            column_types: Vec::new(),
+            memory_limit: memory_limit,
+            spill_dir: env::temp_dir().join(format!(
+                "rividb-{}-{}",
+                process::id(),
+                NEXT_STORE_ID.fetch_add(1, Ordering::SeqCst)
+            )),
+            clock: AtomicUsize::new(0),
+            eviction: Mutex::new(()),
+        }
+    }
+
+    pub fn push(&mut self, batch: Batch) -> Result<(), String> {
+        let heap_size = batch.heap_size_of_children();
+        self.zone_maps.push(ZoneMap::new(&batch));
+        self.batch_ids.push(NEXT_BATCH_ID.fetch_add(1, Ordering::SeqCst));
+        self.rows += batch.cols.first().map_or(0, |col| col.len());
+        for col in &batch.cols {
+            match self.column_names.iter().position(|name| name == col.get_name()) {
+                Some(i) => self.column_types[i] = self.column_types[i].merge(col.column_type()),
+                None => {
+                    self.column_names.push(col.get_name().to_string());
+                    self.column_types.push(col.column_type());
+                }
+            }
+        }
+        let last_used = self.tick();
+        self.slots.push(Mutex::new(Slot {
+            batch: Some(Arc::new(batch)),
+            spill_file: None,
+            heap_size: heap_size,
+            last_used: last_used,
+        }));
+        let index = self.slots.len() - 1;
+        self.enforce_memory_limit(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Names of all columns that occur in any batch, in order of first occurrence.
+    pub fn column_names(&self) -> &Vec<String> {
+        &self.column_names
+    }
+
+    /// Type of each column in `column_names`, which is known without reading spilled batches.
+    pub fn column_types(&self) -> &Vec<ColumnType> {
+        &self.column_types
+    }
+
+    /// Zone map of the batch at `index`, which stays in memory when the batch is spilled.
+    pub fn zone_map(&self, index: usize) -> &ZoneMap {
+        &self.zone_maps[index]
+    }
+
+    /// Ids of all batches, which identify a batch for as long as the process runs. A table loaded again gets new ids.
+    pub fn batch_ids(&self) -> &Vec<usize> {
+        &self.batch_ids
+    }
+
+    /// Returns the batch at `index`, reading it back from disk if it was spilled.
+    pub fn get(&self, index: usize) -> Result<Arc<Batch>, String> {
+        let batch = {
+            let mut slot = self.slots[index].lock().unwrap();
+            slot.last_used = self.tick();
+            if slot.batch.is_none() {
+                let path = slot.spill_file.clone().unwrap();
+                let batch = spill::read_batch(&path)
+                    .map_err(|err| format!("Failed to read spilled batch {}: {}", path.display(), err))?;
+                slot.batch = Some(Arc::new(batch));
+            }
+            slot.batch.clone().unwrap()
+        };
+        self.enforce_memory_limit(index)?;
+        Ok(batch)
+    }
+
+    pub fn bytes_in_ram(&self) -> usize {
+        self.slots
+            .iter()
+            .map(|slot| slot.lock().unwrap())
+            .filter(|slot| slot.batch.is_some())
+            .map(|slot| slot.heap_size)
+            .sum()
+    }
+
+    pub fn batches_in_ram(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.lock().unwrap().batch.is_some())
+            .count()
+    }
+
+    fn tick(&self) -> usize {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Evicts least recently used batches until the memory limit is met. The batch at `pinned` is never evicted.
+    fn enforce_memory_limit(&self, pinned: usize) -> Result<(), String> {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let _guard = self.eviction.lock().unwrap();
+        let mut bytes_in_ram = self.bytes_in_ram();
+        while bytes_in_ram > limit {
+            let victim = self.slots
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != pinned)
+                .filter_map(|(i, slot)| {
+                    let slot = slot.lock().unwrap();
+                    slot.batch.as_ref().map(|_| (i, slot.last_used))
+                })
+                .min_by_key(|&(_, last_used)| last_used)
+                .map(|(i, _)| i);
+            match victim {
+                Some(i) => bytes_in_ram -= self.evict(i)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn evict(&self, index: usize) -> Result<usize, String> {
+        let mut slot = self.slots[index].lock().unwrap();
+        if slot.batch.is_none() {
+            return Ok(0);
+        }
+        if slot.spill_file.is_none() {
+            fs::create_dir_all(&self.spill_dir).map_err(|err| {
+                format!("Failed to create spill directory {}: {}", self.spill_dir.display(), err)
+            })?;
+            let path = self.spill_dir.join(format!("batch_{}.bin", index));
+            spill::write_batch(&path, slot.batch.as_ref().unwrap())
+                .map_err(|err| format!("Failed to spill batch to {}: {}", path.display(), err))?;
+            slot.spill_file = Some(path);
+        }
+        slot.batch = None;
+        Ok(slot.heap_size)
+    }
+}
+
+impl Drop for BatchStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.spill_dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use columns::columnarize;
+    use value::ValueType;
+
+    fn batch(x: i64) -> Batch {
+        columnarize(vec![vec![("x".to_string(), ValueType::Integer(x))]])
+    }
+
+    #[test]
+    fn reports_missing_spill_files() {
+        let mut store = BatchStore::new(Some(0));
+        store.push(batch(1)).unwrap();
+        store.push(batch(2)).unwrap();
+        assert_eq!(store.batches_in_ram(), 1);
+        fs::remove_dir_all(&store.spill_dir).unwrap();
+        match store.get(0) {
+            Err(err) => assert!(err.starts_with("Failed to read spilled batch")),
+            Ok(_) => panic!("Read a batch whose spill file is missing"),
+        }
+        assert_eq!(store.get(1).unwrap().cols[0].len(), 1);
+    }
+
+    #[test]
+    fn reports_failed_spills() {
+        let mut store = BatchStore::new(Some(0));
+        let file = env::temp_dir().join(format!("rividb-{}-not-a-directory", process::id()));
+        fs::write(&file, b"").unwrap();
+        store.spill_dir = file.join("spill");
+        store.push(batch(1)).unwrap();
+        let err = store.push(batch(2)).unwrap_err();
+        fs::remove_file(&file).unwrap();
+        assert!(err.starts_with("Failed to create spill directory"));
+    }
+}
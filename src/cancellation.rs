@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use time::precise_time_ns;
+
+/// Stops a running query when it is cancelled, e.g. by Ctrl-C, or exceeds its time limit. Queries check it before
+/// every batch and every few thousand rows and then fail with an error.
+#[derive(Clone)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    timeout: Option<f64>,
+    deadline_ns: Option<u64>,
+}
+
+impl Cancellation {
+    /// Starts the time limit of `timeout` seconds for a query, which is also cancelled once `cancelled` is set.
+    pub fn new(cancelled: Arc<AtomicBool>, timeout: Option<f64>) -> Cancellation {
+        Cancellation {
+            cancelled: cancelled,
+            timeout: timeout,
+            deadline_ns: timeout.map(|seconds| precise_time_ns() + (seconds * 1e9) as u64),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err("Query cancelled".to_string());
+        }
+        match (self.deadline_ns, self.timeout) {
+            (Some(deadline_ns), Some(timeout)) if precise_time_ns() > deadline_ns => {
+                Err(format!("Query timed out after {} seconds", timeout))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use batch_store::BatchStore;
+
+/// Tables that can be queried by name. The first table that was added is queried when no table is named.
+/// Clones share the tables, so that queries can add temporary tables for derived tables.
+#[derive(Clone)]
+pub struct Catalog {
+    tables: Vec<(String, Arc<BatchStore>)>,
+}
+
+impl Catalog {
+    pub fn new() -> Catalog {
+        Catalog { tables: Vec::new() }
+    }
+
+    /// Adds a table, replacing any table of the same name.
+    pub fn add(&mut self, name: String, table: BatchStore) {
+        match self.tables.iter().position(|&(ref existing, _)| *existing == name) {
+            Some(i) => self.tables[i].1 = Arc::new(table),
+            None => self.tables.push((name, Arc::new(table))),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BatchStore> {
+        self.tables
+            .iter()
+            .find(|&&(ref existing, _)| existing == name)
+            .map(|&(_, ref table)| table.as_ref())
+    }
+
+    /// Mutable access to a table, unless a clone of the catalog still shares it.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut BatchStore> {
+        self.tables
+            .iter_mut()
+            .find(|&&mut (ref existing, _)| existing == name)
+            .and_then(|&mut (_, ref mut table)| Arc::get_mut(table))
+    }
+
+    pub fn default_table(&self) -> Option<&str> {
+        self.tables.first().map(|&(ref name, _)| name.as_ref())
+    }
+
+    /// Ids of the batches of all tables.
+    pub fn batch_ids(&self) -> HashSet<usize> {
+        self.tables
+            .iter()
+            .flat_map(|&(_, ref table)| table.batch_ids().iter().cloned())
+            .collect()
+    }
+
+    pub fn tables(&self) -> &Vec<(String, Arc<BatchStore>)> {
+        &self.tables
+    }
+}
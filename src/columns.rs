@@ -3,22 +3,76 @@ use std::boxed::Box;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::iter;
-use std::rc::Rc;
-use value::{RecordType, ValueType};
+use std::mem;
+use std::sync::Arc;
+use value::{OrderedFloat, RecordType, ValueType};
 
 pub struct Batch {
     pub cols: Vec<Box<Column>>,
 }
 
-pub trait Column: HeapSizeOf {
+pub trait Column: HeapSizeOf + Send + Sync {
     fn get_name(&self) -> &str;
     fn iter(&self) -> ColIter;
+    /// Reads the values at `positions`, which must be in increasing order.
+    fn select(&self, positions: &[usize]) -> Vec<ValueType>;
+    fn len(&self) -> usize;
+    fn column_type(&self) -> ColumnType;
+}
+
+/// Type of the values of a column. Nullable columns have the type of their present values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Null,
+    Bool,
+    Timestamp,
+    Integer,
+    Float,
+    Str,
+    Set,
+    Mixed,
+}
+
+impl ColumnType {
+    pub fn of(value: &ValueType) -> ColumnType {
+        match value {
+            &ValueType::Null => ColumnType::Null,
+            &ValueType::Bool(_) => ColumnType::Bool,
+            &ValueType::Timestamp(_) => ColumnType::Timestamp,
+            &ValueType::Integer(_) => ColumnType::Integer,
+            &ValueType::Float(_) => ColumnType::Float,
+            &ValueType::Str(_) => ColumnType::Str,
+            &ValueType::Set(_) => ColumnType::Set,
+        }
+    }
+
+    /// Type of a column that holds values of both types, e.g. a column of a table whose batches have different types.
+    pub fn merge(self, other: ColumnType) -> ColumnType {
+        match (self, other) {
+            (ColumnType::Null, t) | (t, ColumnType::Null) => t,
+            (t1, t2) if t1 == t2 => t1,
+            (ColumnType::Integer, ColumnType::Float) | (ColumnType::Float, ColumnType::Integer) => ColumnType::Float,
+            _ => ColumnType::Mixed,
+        }
+    }
 }
 
 pub struct ColIter<'a> {
     iter: Box<Iterator<Item = ValueType> + 'a>,
 }
 
+impl<'a> ColIter<'a> {
+    /// Iterates over `len` nulls, for columns that are missing from a batch.
+    pub fn nulls(len: usize) -> ColIter<'a> {
+        ColIter { iter: Box::new(iter::repeat(ValueType::Null).take(len)) }
+    }
+
+    /// Iterates over values that have already been read from a column.
+    pub fn from_values(values: Vec<ValueType>) -> ColIter<'a> {
+        ColIter { iter: Box::new(values.into_iter()) }
+    }
+}
+
 impl<'a> Iterator for ColIter<'a> {
     type Item = ValueType;
 
@@ -50,6 +104,18 @@ impl Column for NullColumn {
         let iter = iter::repeat(ValueType::Null).take(self.length);
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        vec![ValueType::Null; positions.len()]
+    }
+
+    fn len(&self) -> usize {
+        self.length
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Null
+    }
 }
 
 struct BoolColumn {
@@ -75,6 +141,18 @@ impl Column for BoolColumn {
         let iter = self.values.iter().map(|&b| ValueType::Bool(b));
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| ValueType::Bool(self.values[i])).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Bool
+    }
 }
 
 struct TimestampColumn {
@@ -100,6 +178,18 @@ impl Column for TimestampColumn {
         let iter = self.values.iter().map(|&t| ValueType::Timestamp(t));
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| ValueType::Timestamp(self.values[i])).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Timestamp
+    }
 }
 
 struct IntegerColumn {
@@ -125,6 +215,55 @@ impl Column for IntegerColumn {
         let iter = self.values.iter().map(|&i| ValueType::Integer(i));
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| ValueType::Integer(self.values[i])).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Integer
+    }
+}
+
+struct FloatColumn {
+    name: String,
+    values: Vec<f64>,
+}
+
+impl FloatColumn {
+    fn new(name: String, values: Vec<f64>) -> FloatColumn {
+        FloatColumn {
+            name: name,
+            values: values,
+        }
+    }
+}
+
+impl Column for FloatColumn {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn iter<'a>(&'a self) -> ColIter<'a> {
+        let iter = self.values.iter().map(|&x| ValueType::Float(OrderedFloat(x)));
+        ColIter { iter: Box::new(iter) }
+    }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| ValueType::Float(OrderedFloat(self.values[i]))).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Float
+    }
 }
 
 struct StringColumn {
@@ -148,10 +287,22 @@ impl Column for StringColumn {
 
     fn iter<'a>(&'a self) -> ColIter<'a> {
         let iter = self.values.iter().map(
-            |s| ValueType::Str(Rc::new(s.clone())),
+            |s| ValueType::Str(Arc::new(s.clone())),
         );
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| ValueType::Str(Arc::new(self.values[i].clone()))).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Str
+    }
 }
 
 struct SetColumn {
@@ -175,22 +326,40 @@ impl Column for SetColumn {
 
     fn iter<'a>(&'a self) -> ColIter<'a> {
         let iter = self.values.iter().map(
-            |s| ValueType::Set(Rc::new(s.clone())),
+            |s| ValueType::Set(Arc::new(s.clone())),
         );
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| ValueType::Set(Arc::new(self.values[i].clone()))).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        ColumnType::Set
+    }
 }
 
 struct MixedColumn {
     name: String,
     values: Vec<ValueType>,
+    /// Merged type of the values, e.g. Integer for integers with nulls in between.
+    column_type: ColumnType,
 }
 
 impl MixedColumn {
     fn new(name: String, values: Vec<ValueType>) -> MixedColumn {
+        let column_type = values
+            .iter()
+            .fold(ColumnType::Null, |column_type, value| column_type.merge(ColumnType::of(value)));
         MixedColumn {
             name: name,
             values: values,
+            column_type: column_type,
         }
     }
 }
@@ -204,6 +373,81 @@ impl Column for MixedColumn {
         let iter = self.values.iter().cloned();
         ColIter { iter: Box::new(iter) }
     }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        positions.iter().map(|&i| self.values[i].clone()).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        self.column_type
+    }
+}
+
+struct NullableColumn {
+    name: String,
+    present: Vec<bool>,
+    values: Box<Column>,
+}
+
+impl NullableColumn {
+    fn new(name: String, present: Vec<bool>, values: Box<Column>) -> NullableColumn {
+        NullableColumn {
+            name: name,
+            present: present,
+            values: values,
+        }
+    }
+}
+
+impl Column for NullableColumn {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn iter<'a>(&'a self) -> ColIter<'a> {
+        let mut values = self.values.iter();
+        let iter = self.present.iter().map(move |&present| if present {
+            values.next().unwrap()
+        } else {
+            ValueType::Null
+        });
+        ColIter { iter: Box::new(iter) }
+    }
+
+    fn select(&self, positions: &[usize]) -> Vec<ValueType> {
+        // Present values are stored densely, so translate positions into indices among them
+        let mut present_before = 0;
+        let mut counted = 0;
+        let mut indices = Vec::new();
+        for &position in positions {
+            present_before += self.present[counted..position].iter().filter(|&&present| present).count();
+            counted = position;
+            if self.present[position] {
+                indices.push(present_before);
+            }
+        }
+        let mut values = self.values.select(&indices).into_iter();
+        positions
+            .iter()
+            .map(|&position| if self.present[position] {
+                values.next().unwrap()
+            } else {
+                ValueType::Null
+            })
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.present.len()
+    }
+
+    fn column_type(&self) -> ColumnType {
+        self.values.column_type()
+    }
 }
 
 impl HeapSizeOf for Batch {
@@ -230,6 +474,12 @@ impl HeapSizeOf for IntegerColumn {
     }
 }
 
+impl HeapSizeOf for FloatColumn {
+    fn heap_size_of_children(&self) -> usize {
+        self.name.heap_size_of_children() + self.values.heap_size_of_children()
+    }
+}
+
 impl HeapSizeOf for TimestampColumn {
     fn heap_size_of_children(&self) -> usize {
         self.name.heap_size_of_children() + self.values.heap_size_of_children()
@@ -254,11 +504,19 @@ impl HeapSizeOf for MixedColumn {
     }
 }
 
-enum VecType {
+impl HeapSizeOf for NullableColumn {
+    fn heap_size_of_children(&self) -> usize {
+        self.name.heap_size_of_children() + self.present.heap_size_of_children() +
+            self.values.heap_size_of_children()
+    }
+}
+
+pub enum VecType {
     NullVec(usize),
     BoolVec(Vec<bool>),
     TimestampVec(Vec<u64>),
     IntegerVec(Vec<i64>),
+    FloatVec(Vec<f64>),
     StringVec(Vec<String>),
     SetVec(Vec<Vec<String>>),
     MixedVec(Vec<ValueType>),
@@ -272,8 +530,9 @@ impl VecType {
             ValueType::Bool(b) => BoolVec(vec![b]),
             ValueType::Timestamp(t) => TimestampVec(vec![t]),
             ValueType::Integer(i) => IntegerVec(vec![i]),
-            ValueType::Str(s) => StringVec(vec![Rc::try_unwrap(s).unwrap()]),
-            ValueType::Set(s) => SetVec(vec![Rc::try_unwrap(s).unwrap()]),
+            ValueType::Float(OrderedFloat(x)) => FloatVec(vec![x]),
+            ValueType::Str(s) => StringVec(vec![Arc::try_unwrap(s).unwrap_or_else(|s| (*s).clone())]),
+            ValueType::Set(s) => SetVec(vec![Arc::try_unwrap(s).unwrap_or_else(|s| (*s).clone())]),
         }
     }
 
@@ -315,10 +574,19 @@ impl VecType {
                     _ => Some(value),
                 }
             }
+            &mut VecType::FloatVec(ref mut v) => {
+                match value {
+                    ValueType::Float(OrderedFloat(x)) => {
+                        v.push(x);
+                        None
+                    }
+                    _ => Some(value),
+                }
+            }
             &mut VecType::StringVec(ref mut v) => {
                 match value {
                     ValueType::Str(s) => {
-                        v.push(Rc::try_unwrap(s).unwrap());
+                        v.push(Arc::try_unwrap(s).unwrap_or_else(|s| (*s).clone()));
                         None
                     }
                     _ => Some(value),
@@ -327,7 +595,7 @@ impl VecType {
             &mut VecType::SetVec(ref mut v) => {
                 match value {
                     ValueType::Set(s) => {
-                        v.push(Rc::try_unwrap(s).unwrap());
+                        v.push(Arc::try_unwrap(s).unwrap_or_else(|s| (*s).clone()));
                         None
                     }
                     _ => Some(value),
@@ -346,18 +614,20 @@ impl VecType {
             VecType::BoolVec(v) => VecType::MixedVec(v.into_iter().map(|b| ValueType::Bool(b)).collect()),
             VecType::TimestampVec(v) => VecType::MixedVec(v.into_iter().map(|t| ValueType::Timestamp(t)).collect()),
             VecType::IntegerVec(v) => VecType::MixedVec(v.into_iter().map(|i| ValueType::Integer(i)).collect()),
-            VecType::StringVec(v) => VecType::MixedVec(v.into_iter().map(|s| ValueType::Str(Rc::new(s))).collect()),
-            VecType::SetVec(v) => VecType::MixedVec(v.into_iter().map(|s| ValueType::Set(Rc::new(s))).collect()),
+            VecType::FloatVec(v) => VecType::MixedVec(v.into_iter().map(|x| ValueType::Float(OrderedFloat(x))).collect()),
+            VecType::StringVec(v) => VecType::MixedVec(v.into_iter().map(|s| ValueType::Str(Arc::new(s))).collect()),
+            VecType::SetVec(v) => VecType::MixedVec(v.into_iter().map(|s| ValueType::Set(Arc::new(s))).collect()),
             vec @ VecType::MixedVec(_) => vec,
         }
     }
 
-    fn to_column(self, name: String) -> Box<Column> {
+    pub fn to_column(self, name: String) -> Box<Column> {
         match self {
             VecType::NullVec(n) => Box::new(NullColumn::new(name, n)),
             VecType::BoolVec(v) => Box::new(BoolColumn::new(name, v)),
             VecType::TimestampVec(v) => Box::new(TimestampColumn::new(name, v)),
             VecType::IntegerVec(v) => Box::new(IntegerColumn::new(name, v)),
+            VecType::FloatVec(v) => Box::new(FloatColumn::new(name, v)),
             VecType::StringVec(v) => Box::new(StringColumn::new(name, v)),
             VecType::SetVec(v) => Box::new(SetColumn::new(name, v)),
             VecType::MixedVec(v) => Box::new(MixedColumn::new(name, v)),
@@ -398,3 +668,40 @@ pub fn columnarize(records: Vec<RecordType>) -> Batch {
 
     Batch { cols: columns }
 }
+
+pub fn nullable_column(name: String, present: Vec<bool>, values: VecType) -> Box<Column> {
+    let values = values.to_column(name.clone());
+    Box::new(NullableColumn::new(name, present, values))
+}
+
+pub fn rebuild_column(name: String, values: Vec<ValueType>) -> Box<Column> {
+    let present: Vec<bool> = values.iter().map(|value| *value != ValueType::Null).collect();
+    let mut types = values
+        .iter()
+        .filter(|value| **value != ValueType::Null)
+        .map(mem::discriminant);
+    let single_type = match types.next() {
+        Some(first) => types.all(|t| t == first),
+        None => false,
+    };
+    if single_type && present.iter().any(|&p| !p) {
+        let vec = build_vec(values.into_iter().filter(|value| *value != ValueType::Null));
+        nullable_column(name, present, vec)
+    } else {
+        build_vec(values.into_iter()).to_column(name)
+    }
+}
+
+fn build_vec<I: Iterator<Item = ValueType>>(mut values: I) -> VecType {
+    let mut vec = match values.next() {
+        Some(value) => VecType::new_with_value(value),
+        None => VecType::NullVec(0),
+    };
+    for value in values {
+        if let Some(value) = vec.push(value) {
+            vec = vec.to_mixed();
+            vec.push(value);
+        }
+    }
+    vec
+}
@@ -1,40 +1,46 @@
 use std::fs::File;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::iter;
-use std::rc::Rc;
+use std::sync::Arc;
 
+use value::OrderedFloat;
 use value::RecordType;
 use value::ValueType;
 
 pub struct CSVIter<'a> {
-    iter: Box<Iterator<Item = RecordType> + 'a>,
+    iter: Box<Iterator<Item = io::Result<RecordType>> + 'a>,
 }
 
 impl<'a> Iterator for CSVIter<'a> {
-    type Item = RecordType;
+    type Item = io::Result<RecordType>;
 
-    fn next(&mut self) -> Option<RecordType> {
+    fn next(&mut self) -> Option<io::Result<RecordType>> {
         self.iter.next()
     }
 }
 
-pub fn load_csv_file(filename: &str) -> CSVIter {
-    let mut file = BufReader::new(File::open(filename).unwrap());
+/// Opens a CSV file whose first line holds the column names. Lines that cannot be read are returned as errors.
+pub fn load_csv_file(filename: &str) -> io::Result<CSVIter> {
+    let file = BufReader::new(File::open(filename)?);
     let mut lines_iter = file.lines();
 
-    let first_line = lines_iter.next().unwrap().unwrap();
+    let first_line = match lines_iter.next() {
+        Some(line) => line?,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "missing header line")),
+    };
     let headers: Vec<String> = first_line.split(",").map(|s| s.to_owned()).collect();
 
     let iter = lines_iter.map(move |line| {
-        let l = line.unwrap();
+        let l = line?;
         let record: RecordType = l.split(",")
             .zip(headers.iter())
             .map(|(val, col)| parse_value(col, val))
             .collect();
-        record
+        Ok(record)
     });
-    CSVIter { iter: Box::new(iter) }
+    Ok(CSVIter { iter: Box::new(iter) })
 }
 
 fn parse_value(colname: &str, value: &str) -> (String, ValueType) {
@@ -43,8 +49,22 @@ fn parse_value(colname: &str, value: &str) -> (String, ValueType) {
     } else {
         match value.parse::<i64>() {
             Ok(int) => ValueType::Integer(int),
-            Err(_) => ValueType::Str(Rc::new(value.to_string())),
+            // Floats also parse from strings like "nan", "inf" or "1e5", which are kept as strings
+            Err(_) if is_decimal(value) => {
+                match value.parse::<f64>() {
+                    Ok(x) => ValueType::Float(OrderedFloat(x)),
+                    Err(_) => ValueType::Str(Arc::new(value.to_string())),
+                }
+            }
+            Err(_) => ValueType::Str(Arc::new(value.to_string())),
         }
     };
     (colname.to_string(), val)
 }
+
+/// Whether `value` consists of digits with an optional sign and at most one decimal point.
+fn is_decimal(value: &str) -> bool {
+    let digits = if value.starts_with('+') || value.starts_with('-') { &value[1..] } else { value };
+    digits.chars().any(|c| c.is_ascii_digit()) && digits.chars().all(|c| c.is_ascii_digit() || c == '.') &&
+        digits.matches('.').count() <= 1
+}
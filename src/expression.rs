@@ -1,18 +1,34 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::rc::Rc;
+use std::fmt;
+use std::sync::Arc;
 
+use aggregator::Aggregator;
+use itertools::Itertools;
+use query_engine::Query;
+use time_bucket::Interval;
 use value::ValueType;
+use window::Window;
+use zone_map::{DEFAULT_SELECTIVITY, EQUALITY_SELECTIVITY};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
-    ColName(Rc<String>),
+    ColName(Arc<String>),
     ColIndex(usize),
     Func(FuncType, Box<Expr>, Box<Expr>),
     Const(ValueType),
+    Aggregate(Aggregator, Vec<Expr>),
+    TimeBucket(Interval, Box<Expr>),
+    Window(Box<Window>),
+    /// Scalar subquery, replaced by its result before the query runs.
+    Subquery(Box<Query>),
+    /// `expr in (subquery)`, replaced by an `InSet` of the subquery results before the query runs.
+    In(Box<Expr>, Box<Query>),
+    InSet(Box<Expr>, Arc<HashSet<ValueType>>),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FuncType {
     Equals,
     LT,
@@ -25,34 +41,88 @@ use self::Expr::*;
 use self::FuncType::*;
 use self::ValueType::*;
 
+/// Formats expressions in query syntax, with compiled column references shown as `#index`.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ColName(ref name) => write!(f, "{}", name),
+            &ColIndex(index) => write!(f, "#{}", index),
+            &Func(ftype, ref expr1, ref expr2) => {
+                let name = match ftype {
+                    Equals => "=",
+                    LT => "<",
+                    GT => ">",
+                    And => "and",
+                    Or => "or",
+                };
+                write!(f, "{}({}, {})", name, expr1, expr2)
+            }
+            &Const(ref value) => write!(f, "{}", value),
+            &Aggregate(agg, ref args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", agg.name(), args.join(", "))
+            }
+            &TimeBucket(ref interval, ref expr) => write!(f, "time_bucket({:?}, {})", interval, expr),
+            &Window(ref window) => write!(f, "{:?} over (...)", window.function),
+            &Subquery(_) => write!(f, "(subquery)"),
+            &In(ref expr, _) => write!(f, "{} in (subquery)", expr),
+            &InSet(ref expr, ref values) => write!(f, "{} in ({} values)", expr, values.len()),
+        }
+    }
+}
+
 impl Expr {
     pub fn eval(&self, record: &Vec<ValueType>) -> ValueType {
         match self {
-            &Func(ref functype, ref exp1, ref exp2) => {
-                match (functype, exp1.eval(record), exp2.eval(record)) {
-                    (&Equals, v1, v2) => Bool(v1 == v2),
-                    (_, Null, _) => Null,
-                    (_, _, Null) => Null,
-                    (&And, Bool(b1), Bool(b2)) => Bool(b1 && b2),
-                    (&Or, Bool(b1), Bool(b2)) => Bool(b1 || b2),
-                    (&LT, Integer(i1), Integer(i2)) => Bool(i1 < i2),
-                    (&LT, Timestamp(t1), Timestamp(t2)) => Bool(t1 < t2),
-                    (&GT, Integer(i1), Integer(i2)) => Bool(i1 > i2),
-                    (&GT, Timestamp(t1), Timestamp(t2)) => Bool(t1 > t2),
-                    (&GT, Integer(i), Timestamp(t)) if i >= 0 => Bool(i as u64 > t),
-                    (functype, v1, v2) => {
-                        panic!(
-                            "Type error: function {:?} not defined for values {:?} and {:?}",
-                            functype,
-                            v1,
-                            v2
-                        )
+            // `and` and `or` skip their second operand when the first one decides the result
+            &Func(And, ref exp1, ref exp2) => {
+                match exp1.eval(record) {
+                    Bool(false) => Bool(false),
+                    v1 => {
+                        match exp2.eval(record) {
+                            Bool(false) => Bool(false),
+                            v2 => apply(And, v1, v2).unwrap_or_else(|message| panic!("{}", message)),
+                        }
+                    }
+                }
+            }
+            &Func(Or, ref exp1, ref exp2) => {
+                match exp1.eval(record) {
+                    Bool(true) => Bool(true),
+                    v1 => {
+                        match exp2.eval(record) {
+                            Bool(true) => Bool(true),
+                            v2 => apply(Or, v1, v2).unwrap_or_else(|message| panic!("{}", message)),
+                        }
                     }
                 }
             }
+            &Func(functype, ref exp1, ref exp2) => {
+                apply(functype, exp1.eval(record), exp2.eval(record)).unwrap_or_else(|message| panic!("{}", message))
+            }
+            &TimeBucket(ref interval, ref expr) => {
+                let bucket = match expr.eval(record) {
+                    Null => return Null,
+                    Timestamp(t) => interval.bucket(t as i64),
+                    Integer(i) => interval.bucket(i),
+                    v => panic!("Type error: time_bucket not defined for value {:?}", v),
+                };
+                if bucket >= 0 { Timestamp(bucket as u64) } else { Integer(bucket) }
+            }
+            &InSet(ref expr, ref values) => {
+                match expr.eval(record) {
+                    Null => Null,
+                    ref value if values.contains(value) => Bool(true),
+                    _ if values.contains(&Null) => Null,
+                    _ => Bool(false),
+                }
+            }
             &ColIndex(col) => record[col].clone(),
             &Const(ref value) => value.clone(),
             &ColName(_) => panic!("Trying to evaluate ColumnName expression. Compile this expression before evaluating."),
+            &Aggregate(..) => panic!("Trying to evaluate Aggregate expression. Replace aggregates with their result columns."),
+            &Window(..) => panic!("Trying to evaluate Window expression. Replace windows with their result columns."),
+            &Subquery(..) | &In(..) => panic!("Trying to evaluate subquery. Replace subqueries with their results."),
         }
     }
 
@@ -73,17 +143,44 @@ impl Expr {
                     expr2.compile(column_names),
                 )
             }
+            &TimeBucket(interval, ref expr) => TimeBucket(interval, Box::new(expr.compile(column_names))),
+            &InSet(ref expr, ref values) => InSet(Box::new(expr.compile(column_names)), values.clone()),
             &ColIndex(_) => panic!("Uncompiled Expr should not contain ColumnIndex."),
+            &Aggregate(..) => panic!("Aggregates must be replaced with their result columns before compiling."),
+            // Replaced by their result columns after compiling
+            &Window(ref window) => Window(window.clone()),
+            &Subquery(..) | &In(..) => panic!("Subqueries must be replaced with their results before compiling."),
         }
     }
 
-    pub fn find_colnames(&self) -> HashSet<Rc<String>> {
+    pub fn find_colnames(&self) -> HashSet<Arc<String>> {
         let mut result = HashSet::new();
         self.add_colnames(&mut result);
         result
     }
 
-    pub fn add_colnames(&self, result: &mut HashSet<Rc<String>>) {
+    /// Indices of the record columns a compiled expression reads.
+    pub fn find_col_indices(&self) -> HashSet<usize> {
+        let mut result = HashSet::new();
+        self.add_col_indices(&mut result);
+        result
+    }
+
+    fn add_col_indices(&self, result: &mut HashSet<usize>) {
+        match self {
+            &ColIndex(index) => {
+                result.insert(index);
+            }
+            &Func(_, ref expr1, ref expr2) => {
+                expr1.add_col_indices(result);
+                expr2.add_col_indices(result);
+            }
+            &TimeBucket(_, ref expr) | &InSet(ref expr, _) => expr.add_col_indices(result),
+            _ => (),
+        }
+    }
+
+    pub fn add_colnames(&self, result: &mut HashSet<Arc<String>>) {
         match self {
             &ColName(ref name) => {
                 result.insert(name.clone());
@@ -92,15 +189,387 @@ impl Expr {
                 expr1.add_colnames(result);
                 expr2.add_colnames(result);
             }
+            &TimeBucket(_, ref expr) | &In(ref expr, _) | &InSet(ref expr, _) => expr.add_colnames(result),
+            &Window(ref window) => {
+                for expr in window.exprs() {
+                    expr.add_colnames(result);
+                }
+            }
+            &Aggregate(_, ref args) => {
+                for arg in args {
+                    arg.add_colnames(result);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn add_aggregates(&self, result: &mut Vec<(Aggregator, Vec<Expr>)>) {
+        match self {
+            &Aggregate(agg, ref args) => {
+                if !result.iter().any(|&(a, ref e)| a == agg && e == args) {
+                    result.push((agg, args.clone()));
+                }
+            }
+            &Func(_, ref expr1, ref expr2) => {
+                expr1.add_aggregates(result);
+                expr2.add_aggregates(result);
+            }
+            &TimeBucket(_, ref expr) | &In(ref expr, _) | &InSet(ref expr, _) => expr.add_aggregates(result),
+            &Window(ref window) => {
+                for expr in window.exprs() {
+                    expr.add_aggregates(result);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn contains_aggregate(&self) -> bool {
+        match self {
+            &Aggregate(..) => true,
+            &Func(_, ref expr1, ref expr2) => expr1.contains_aggregate() || expr2.contains_aggregate(),
+            &TimeBucket(_, ref expr) | &In(ref expr, _) | &InSet(ref expr, _) => expr.contains_aggregate(),
+            &Window(ref window) => window.exprs().into_iter().any(|expr| expr.contains_aggregate()),
+            _ => false,
+        }
+    }
+
+    pub fn add_windows(&self, result: &mut Vec<Window>) {
+        match self {
+            &Window(ref window) => {
+                if !result.contains(window) {
+                    result.push((**window).clone());
+                }
+            }
+            &Func(_, ref expr1, ref expr2) => {
+                expr1.add_windows(result);
+                expr2.add_windows(result);
+            }
+            &TimeBucket(_, ref expr) | &In(ref expr, _) | &InSet(ref expr, _) => expr.add_windows(result),
+            _ => (),
+        }
+    }
+
+    /// Replaces every window in `windows` by the column holding its result, starting at `first_column`.
+    pub fn replace_windows(&self, windows: &Vec<Window>, first_column: usize) -> Expr {
+        match self {
+            &Window(ref window) => ColIndex(first_column + windows.iter().position(|w| w == window.as_ref()).unwrap()),
+            &Func(ftype, ref expr1, ref expr2) => {
+                Expr::func(
+                    ftype,
+                    expr1.replace_windows(windows, first_column),
+                    expr2.replace_windows(windows, first_column),
+                )
+            }
+            &TimeBucket(interval, ref expr) => TimeBucket(interval, Box::new(expr.replace_windows(windows, first_column))),
+            &In(ref expr, ref query) => In(Box::new(expr.replace_windows(windows, first_column)), query.clone()),
+            &InSet(ref expr, ref values) => InSet(Box::new(expr.replace_windows(windows, first_column)), values.clone()),
+            other => other.clone(),
+        }
+    }
+
+    /// Collects the queries of scalar subqueries and of `in` subqueries.
+    pub fn add_subqueries(&self, scalars: &mut Vec<Query>, sets: &mut Vec<Query>) {
+        match self {
+            &Subquery(ref query) => {
+                if !scalars.contains(query) {
+                    scalars.push((**query).clone());
+                }
+            }
+            &In(ref expr, ref query) => {
+                expr.add_subqueries(scalars, sets);
+                if !sets.contains(query) {
+                    sets.push((**query).clone());
+                }
+            }
+            &Func(_, ref expr1, ref expr2) => {
+                expr1.add_subqueries(scalars, sets);
+                expr2.add_subqueries(scalars, sets);
+            }
+            &TimeBucket(_, ref expr) | &InSet(ref expr, _) => expr.add_subqueries(scalars, sets),
+            &Aggregate(_, ref args) => {
+                for arg in args {
+                    arg.add_subqueries(scalars, sets);
+                }
+            }
+            &Window(ref window) => {
+                for expr in window.exprs() {
+                    expr.add_subqueries(scalars, sets);
+                }
+            }
             _ => (),
         }
     }
 
+    /// Replaces subqueries by their materialized results.
+    pub fn replace_subqueries(
+        &self,
+        scalars: &Vec<(Query, ValueType)>,
+        sets: &Vec<(Query, Arc<HashSet<ValueType>>)>,
+    ) -> Expr {
+        match self {
+            &Subquery(ref query) => {
+                let &(_, ref value) = scalars.iter().find(|&&(ref q, _)| q == query.as_ref()).unwrap();
+                Const(value.clone())
+            }
+            &In(ref expr, ref query) => {
+                let &(_, ref values) = sets.iter().find(|&&(ref q, _)| q == query.as_ref()).unwrap();
+                InSet(Box::new(expr.replace_subqueries(scalars, sets)), values.clone())
+            }
+            &Func(ftype, ref expr1, ref expr2) => {
+                Expr::func(
+                    ftype,
+                    expr1.replace_subqueries(scalars, sets),
+                    expr2.replace_subqueries(scalars, sets),
+                )
+            }
+            &TimeBucket(interval, ref expr) => TimeBucket(interval, Box::new(expr.replace_subqueries(scalars, sets))),
+            &InSet(ref expr, ref values) => InSet(Box::new(expr.replace_subqueries(scalars, sets)), values.clone()),
+            &Aggregate(agg, ref args) => Aggregate(agg, args.iter().map(|arg| arg.replace_subqueries(scalars, sets)).collect()),
+            &Window(ref window) => Window(Box::new(window.map_exprs(|expr| expr.replace_subqueries(scalars, sets)))),
+            other => other.clone(),
+        }
+    }
+
+    pub fn contains_window(&self) -> bool {
+        let mut windows = Vec::new();
+        self.add_windows(&mut windows);
+        !windows.is_empty()
+    }
+
+    /// Simplifies a compiled expression: folds constant subexpressions, drops operands of `and` and `or` that do
+    /// not affect the result and orders the remaining ones so that the operands most likely to decide the result
+    /// at the lowest cost are evaluated first. `selectivity` may estimate the fraction of rows satisfying a
+    /// condition better than the defaults, e.g. from zone maps.
+    pub fn optimize<F: Fn(&Expr) -> Option<f64>>(&self, selectivity: &F) -> Expr {
+        match self {
+            &Func(ftype, ref expr1, ref expr2) if ftype == And || ftype == Or => {
+                let mut operands = Vec::new();
+                add_operands(ftype, expr1.optimize(selectivity), &mut operands);
+                add_operands(ftype, expr2.optimize(selectivity), &mut operands);
+                // `true` decides `or` and is neutral for `and`, `false` the other way round
+                let decisive = Const(Bool(ftype == Or));
+                if operands.contains(&decisive) {
+                    return decisive;
+                }
+                operands.retain(|operand| *operand != Const(Bool(ftype == And)));
+                let mut ranked: Vec<(f64, Expr)> = operands
+                    .into_iter()
+                    .map(|operand| {
+                        let matching = operand.selectivity(selectivity);
+                        let deciding = if ftype == And { 1.0 - matching } else { matching };
+                        (operand.cost() / deciding.max(1e-9), operand)
+                    })
+                    .collect();
+                ranked.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                ranked
+                    .into_iter()
+                    .map(|(_, operand)| operand)
+                    .fold1(|expr1, expr2| Expr::func(ftype, expr1, expr2))
+                    .unwrap_or(Const(Bool(ftype == And)))
+            }
+            &Func(ftype, ref expr1, ref expr2) => {
+                match (expr1.optimize(selectivity), expr2.optimize(selectivity)) {
+                    (Const(v1), Const(v2)) => {
+                        match apply(ftype, v1.clone(), v2.clone()) {
+                            Ok(value) => Const(value),
+                            // Left for evaluation to report
+                            Err(_) => Expr::func(ftype, Const(v1), Const(v2)),
+                        }
+                    }
+                    (expr1, expr2) => Expr::func(ftype, expr1, expr2),
+                }
+            }
+            &TimeBucket(interval, ref expr) => {
+                match expr.optimize(selectivity) {
+                    Const(value @ Null) | Const(value @ Timestamp(_)) | Const(value @ Integer(_)) => {
+                        Const(TimeBucket(interval, Box::new(Const(value))).eval(&vec![]))
+                    }
+                    expr => TimeBucket(interval, Box::new(expr)),
+                }
+            }
+            &InSet(ref expr, ref values) => {
+                match expr.optimize(selectivity) {
+                    Const(value) => Const(InSet(Box::new(Const(value)), values.clone()).eval(&vec![])),
+                    expr => InSet(Box::new(expr), values.clone()),
+                }
+            }
+            other => other.clone(),
+        }
+    }
+
+    /// Optimizes a compiled filter. Rows pass a filter only if it is true, so null operands of `and` and `or`,
+    /// like comparisons with columns missing from the table, count as false. Filters that can never be true
+    /// become `Const(Bool(false))`.
+    pub fn optimize_filter<F: Fn(&Expr) -> Option<f64>>(&self, selectivity: &F) -> Expr {
+        fn null_as_false(expr: Expr) -> Expr {
+            match expr {
+                Func(ftype, expr1, expr2) if ftype == And || ftype == Or => {
+                    Expr::func(ftype, null_as_false(*expr1), null_as_false(*expr2))
+                }
+                Const(Bool(b)) => Const(Bool(b)),
+                Const(_) => Const(Bool(false)),
+                other => other,
+            }
+        }
+        null_as_false(self.optimize(selectivity)).optimize(selectivity)
+    }
+
+    /// Canonical form of the expression, in which constant parts are folded and the operands of `and` and `or` are
+    /// sorted and deduplicated, so that equivalent conditions written in a different order are equal.
+    pub fn normalize(&self) -> Expr {
+        match self.optimize(&|_| None) {
+            Func(ftype, expr1, expr2) => {
+                if ftype != And && ftype != Or {
+                    return Expr::func(ftype, expr1.normalize(), expr2.normalize());
+                }
+                let mut operands = Vec::new();
+                add_operands(ftype, *expr1, &mut operands);
+                add_operands(ftype, *expr2, &mut operands);
+                let mut operands: Vec<(String, Expr)> = operands
+                    .into_iter()
+                    .map(|operand| {
+                        let operand = operand.normalize();
+                        (format!("{:?}", operand), operand)
+                    })
+                    .collect();
+                operands.sort_by(|a, b| a.0.cmp(&b.0));
+                operands.dedup_by(|a, b| a.0 == b.0);
+                operands
+                    .into_iter()
+                    .map(|(_, operand)| operand)
+                    .fold1(|expr1, expr2| Expr::func(ftype, expr1, expr2))
+                    .unwrap()
+            }
+            expr => expr,
+        }
+    }
+
+    /// Estimated fraction of rows for which the expression is true.
+    fn selectivity<F: Fn(&Expr) -> Option<f64>>(&self, estimate: &F) -> f64 {
+        if let Some(selectivity) = estimate(self) {
+            return selectivity;
+        }
+        match self {
+            &Const(ref value) => if *value == Bool(true) { 1.0 } else { 0.0 },
+            &Func(And, ref expr1, ref expr2) => expr1.selectivity(estimate) * expr2.selectivity(estimate),
+            &Func(Or, ref expr1, ref expr2) => {
+                let (s1, s2) = (expr1.selectivity(estimate), expr2.selectivity(estimate));
+                s1 + s2 - s1 * s2
+            }
+            &Func(Equals, _, _) => EQUALITY_SELECTIVITY,
+            &InSet(_, ref values) => (values.len() as f64 * EQUALITY_SELECTIVITY).min(0.5),
+            _ => DEFAULT_SELECTIVITY,
+        }
+    }
+
+    /// Rough relative cost of evaluating the expression once.
+    fn cost(&self) -> f64 {
+        match self {
+            &ColIndex(_) | &Const(_) => 0.0,
+            &Func(_, ref expr1, ref expr2) => 1.0 + expr1.cost() + expr2.cost(),
+            &InSet(ref expr, _) => 2.0 + expr.cost(),
+            &TimeBucket(_, ref expr) => 4.0 + expr.cost(),
+            _ => 1.0,
+        }
+    }
+
     pub fn func(ftype: FuncType, expr1: Expr, expr2: Expr) -> Expr {
         Func(ftype, Box::new(expr1), Box::new(expr2))
     }
 
     pub fn col(name: &str) -> Expr {
-        ColName(Rc::new(name.to_string()))
+        ColName(Arc::new(name.to_string()))
+    }
+}
+
+/// Collects the operands of nested `ftype` functions, which are `and` or `or`.
+fn add_operands(ftype: FuncType, expr: Expr, operands: &mut Vec<Expr>) {
+    match expr {
+        Func(f, expr1, expr2) if f == ftype => {
+            add_operands(ftype, *expr1, operands);
+            add_operands(ftype, *expr2, operands);
+        }
+        expr => operands.push(expr),
+    }
+}
+
+fn apply(functype: FuncType, v1: ValueType, v2: ValueType) -> Result<ValueType, String> {
+    Ok(match (functype, v1, v2) {
+        (Equals, v1, v2) => Bool(v1 == v2),
+        (_, Null, _) => Null,
+        (_, _, Null) => Null,
+        (And, Bool(b1), Bool(b2)) => Bool(b1 && b2),
+        (Or, Bool(b1), Bool(b2)) => Bool(b1 || b2),
+        (LT, Integer(i1), Integer(i2)) => Bool(i1 < i2),
+        (LT, Timestamp(t1), Timestamp(t2)) => Bool(t1 < t2),
+        (GT, Integer(i1), Integer(i2)) => Bool(i1 > i2),
+        (GT, Timestamp(t1), Timestamp(t2)) => Bool(t1 > t2),
+        (LT, ref v1, ref v2) if v1.as_f64().is_some() && v2.as_f64().is_some() => {
+            Bool(v1.numeric_cmp(v2) == Some(Ordering::Less))
+        }
+        (GT, ref v1, ref v2) if v1.as_f64().is_some() && v2.as_f64().is_some() => {
+            Bool(v1.numeric_cmp(v2) == Some(Ordering::Greater))
+        }
+        (functype, v1, v2) => {
+            return Err(format!(
+                "Type error: function {:?} not defined for values {:?} and {:?}",
+                functype,
+                v1,
+                v2
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> Expr {
+        Const(Integer(i))
+    }
+
+    #[test]
+    fn folds_constants() {
+        let x = Expr::func(GT, Expr::col("x"), int(1));
+        let no_estimates = |_: &Expr| None;
+        assert_eq!(Expr::func(And, Expr::func(GT, int(3), int(2)), x.clone()).optimize(&no_estimates), x);
+        assert_eq!(
+            Expr::func(Or, x.clone(), Expr::func(Equals, int(1), int(1))).optimize(&no_estimates),
+            Const(Bool(true))
+        );
+        assert_eq!(
+            Expr::func(And, x.clone(), Expr::func(LT, int(3), int(2))).optimize(&no_estimates),
+            Const(Bool(false))
+        );
+        // Type errors are left for evaluation to report
+        let invalid = Expr::func(LT, int(1), Const(Bool(true)));
+        assert_eq!(invalid.optimize(&no_estimates), invalid);
+        // Comparisons with null are null, which filters treat as false
+        let null = Expr::func(And, x.clone(), Expr::func(LT, Const(Null), int(2)));
+        assert_eq!(null.optimize_filter(&no_estimates), Const(Bool(false)));
+    }
+
+    #[test]
+    fn orders_operands_by_cost_and_selectivity() {
+        let rare = Expr::func(GT, Expr::col("x"), int(99));
+        let common = Expr::func(GT, Expr::col("y"), int(1));
+        let estimate = |expr: &Expr| if *expr == rare {
+            Some(0.01)
+        } else if *expr == common {
+            Some(0.99)
+        } else {
+            None
+        };
+        // `and` evaluates the operand most likely to be false first, `or` the one most likely to be true
+        let and = Expr::func(And, common.clone(), rare.clone());
+        assert_eq!(and.optimize(&estimate), Expr::func(And, rare.clone(), common.clone()));
+        let or = Expr::func(Or, rare.clone(), common.clone());
+        assert_eq!(or.optimize(&estimate), Expr::func(Or, common.clone(), rare.clone()));
+        // Normalization ignores the order and duplicates of operands
+        let reordered = Expr::func(And, Expr::func(And, rare.clone(), common.clone()), rare.clone());
+        assert_eq!(reordered.normalize(), and.normalize());
     }
 }
@@ -0,0 +1,328 @@
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use aggregator::{finalize_group, Accumulator, Aggregator};
+use heapsize::HeapSizeOf;
+use spill;
+use value::ValueType;
+
+/// Number of partitions that spilled groups are split into by the hash of their key.
+const PARTITIONS: usize = 16;
+
+/// Number of times a partition that still exceeds the budget is split again, with a different hash seed each time,
+/// before it is read back whole. Splitting cannot shrink a partition that consists of a single large group.
+const MAX_LEVELS: u64 = 4;
+
+static NEXT_SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Groups of an aggregation with their accumulators, kept within a memory budget. Once the groups in memory take
+/// more than the budget, they are hash partitioned by key into temporary files and removed from memory. In the end
+/// each partition is read back and merged separately, so only the groups of one partition have to fit into memory
+/// at a time. Partitions that exceed the budget are split again in the same way.
+pub struct GroupTable {
+    aggregators: Vec<Aggregator>,
+    groups: HashMap<Vec<ValueType>, Vec<Accumulator>>,
+    /// Estimated memory taken by `groups`.
+    bytes: usize,
+    budget: Option<usize>,
+    /// Spill files of each partition, including those merged from other tables.
+    partitions: Vec<Vec<PathBuf>>,
+    /// Spill files this table appends to, one per partition.
+    spill_files: Vec<PathBuf>,
+    /// Directories holding the spill files in `partitions`, removed with the table.
+    spill_dirs: Vec<PathBuf>,
+    /// Seed of the hash that partitions groups, which is the number of times the groups have been split before.
+    level: u64,
+}
+
+impl GroupTable {
+    pub fn new(aggregators: Vec<Aggregator>, budget: Option<usize>) -> GroupTable {
+        GroupTable::with_level(aggregators, budget, 0)
+    }
+
+    fn with_level(aggregators: Vec<Aggregator>, budget: Option<usize>, level: u64) -> GroupTable {
+        GroupTable {
+            aggregators: aggregators,
+            groups: HashMap::new(),
+            bytes: 0,
+            budget: budget,
+            partitions: vec![Vec::new(); PARTITIONS],
+            spill_files: Vec::new(),
+            spill_dirs: Vec::new(),
+            level: level,
+        }
+    }
+
+    /// Applies `update` to the accumulators of the group `key`, adding the group if it is new.
+    pub fn update<F: FnOnce(&mut Vec<Accumulator>)>(&mut self, key: Vec<ValueType>, update: F) -> Result<(), String> {
+        let aggregators = &self.aggregators;
+        let (bytes_before, accumulators) = match self.groups.entry(key) {
+            Entry::Occupied(entry) => {
+                let accumulators = entry.into_mut();
+                (accumulators_bytes(accumulators), accumulators)
+            }
+            Entry::Vacant(entry) => {
+                self.bytes += key_bytes(entry.key());
+                (0, entry.insert(aggregators.iter().map(|aggregator| aggregator.zero()).collect()))
+            }
+        };
+        update(accumulators);
+        self.bytes = (self.bytes + accumulators_bytes(accumulators)).saturating_sub(bytes_before);
+        self.enforce_budget()
+    }
+
+    /// Moves the groups of `other` into this table, merging the accumulators of groups present in both.
+    pub fn merge(&mut self, mut other: GroupTable) -> Result<(), String> {
+        for (files, other_files) in self.partitions.iter_mut().zip(other.partitions.iter_mut()) {
+            files.extend(other_files.drain(..));
+        }
+        self.spill_dirs.extend(other.spill_dirs.drain(..));
+        self.extend(other.groups.drain())
+    }
+
+    /// Adds groups with their accumulators, merging those of groups already present.
+    pub fn extend<I>(&mut self, groups: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = (Vec<ValueType>, Vec<Accumulator>)>,
+    {
+        for (key, accumulators) in groups {
+            self.merge_group(key, accumulators);
+            self.enforce_budget()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the groups and their accumulators, unless some of them were spilled to disk.
+    pub fn to_groups(&self) -> Option<Vec<(Vec<ValueType>, Vec<Accumulator>)>> {
+        if self.partitions.iter().any(|files| !files.is_empty()) {
+            return None;
+        }
+        Some(self.groups.iter().map(|(key, accumulators)| (key.clone(), accumulators.clone())).collect())
+    }
+
+    /// Estimated memory taken by the groups in memory.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Finalizes the accumulators and returns the rows of each group, see `finalize_group`.
+    pub fn into_rows(mut self) -> Result<Vec<Vec<ValueType>>, String> {
+        let aggregators = self.aggregators.clone();
+        let finalize = |(key, accumulators): (Vec<ValueType>, Vec<Accumulator>)| {
+            finalize_group(&aggregators, key, accumulators)
+        };
+        if self.partitions.iter().all(|files| files.is_empty()) {
+            return Ok(self.groups.drain().flat_map(finalize).collect());
+        }
+
+        // Groups still in memory are merged with the spilled groups of their partition, in a table that spills and
+        // splits them again if they exceed the budget
+        let level = self.level + 1;
+        let budget = if level < MAX_LEVELS { self.budget } else { None };
+        let mut partitions: Vec<GroupTable> = (0..PARTITIONS)
+            .map(|_| GroupTable::with_level(aggregators.clone(), budget, level))
+            .collect();
+        for (key, accumulators) in self.groups.drain() {
+            partitions[partition_of(&key, self.level)].merge_group(key, accumulators);
+        }
+        let mut rows = Vec::new();
+        for (partition, mut table) in partitions.into_iter().enumerate() {
+            for path in mem::replace(&mut self.partitions[partition], Vec::new()) {
+                let result = table.read_spill_file(&path);
+                let _ = fs::remove_file(&path);
+                result.map_err(|err| format!("Failed to read spilled groups: {}", err))?;
+            }
+            rows.extend(table.into_rows()?);
+        }
+        Ok(rows)
+    }
+
+    fn merge_group(&mut self, key: Vec<ValueType>, accumulators: Vec<Accumulator>) {
+        match self.groups.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let existing = entry.get_mut();
+                let bytes_before = accumulators_bytes(existing);
+                for (i, accumulator) in accumulators.into_iter().enumerate() {
+                    self.aggregators[i].merge(&mut existing[i], accumulator);
+                }
+                self.bytes = (self.bytes + accumulators_bytes(existing)).saturating_sub(bytes_before);
+            }
+            Entry::Vacant(entry) => {
+                self.bytes += key_bytes(entry.key()) + accumulators_bytes(&accumulators);
+                entry.insert(accumulators);
+            }
+        }
+    }
+
+    fn enforce_budget(&mut self) -> Result<(), String> {
+        match self.budget {
+            Some(budget) if self.bytes > budget => {
+                self.spill().map_err(|err| format!("Failed to spill groups to disk: {}", err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends all groups in memory to the spill file of their partition. Each group is preceded by a 1 byte.
+    fn spill(&mut self) -> io::Result<()> {
+        if self.spill_files.is_empty() {
+            // Every table spills into a directory of its own, so that no table removes a directory another one
+            // is about to write to
+            let dir = env::temp_dir().join(format!(
+                "rividb-{}-groups-{}",
+                process::id(),
+                NEXT_SPILL_ID.fetch_add(1, Ordering::SeqCst)
+            ));
+            fs::create_dir_all(&dir)?;
+            self.spill_dirs.push(dir.clone());
+            for (partition, files) in self.partitions.iter_mut().enumerate() {
+                let path = dir.join(format!("groups_{}.bin", partition));
+                files.push(path.clone());
+                self.spill_files.push(path);
+            }
+        }
+        let mut writers = Vec::with_capacity(PARTITIONS);
+        for path in &self.spill_files {
+            writers.push(BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?));
+        }
+        for (key, accumulators) in self.groups.drain() {
+            let writer = &mut writers[partition_of(&key, self.level)];
+            writer.write_all(&[1])?;
+            spill::write_u64(writer, key.len() as u64)?;
+            for value in &key {
+                spill::write_value(writer, value)?;
+            }
+            for accumulator in &accumulators {
+                spill::write_accumulator(writer, accumulator)?;
+            }
+        }
+        for mut writer in writers {
+            writer.flush()?;
+        }
+        self.bytes = 0;
+        Ok(())
+    }
+
+    fn read_spill_file(&mut self, path: &PathBuf) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut marker = [0u8];
+        loop {
+            if reader.read(&mut marker)? == 0 {
+                return Ok(());
+            }
+            if marker[0] != 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown group marker {}", marker[0])));
+            }
+            let len = spill::read_u64(&mut reader)?;
+            let mut key = Vec::with_capacity(spill::capacity(len));
+            for _ in 0..len {
+                key.push(spill::read_value(&mut reader)?);
+            }
+            let mut accumulators = Vec::with_capacity(self.aggregators.len());
+            for _ in 0..self.aggregators.len() {
+                accumulators.push(spill::read_accumulator(&mut reader)?);
+            }
+            self.merge_group(key, accumulators);
+            self.enforce_budget().map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+    }
+}
+
+impl Drop for GroupTable {
+    fn drop(&mut self) {
+        for path in self.partitions.iter().flat_map(|files| files.iter()) {
+            let _ = fs::remove_file(path);
+        }
+        for dir in &self.spill_dirs {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+fn partition_of(key: &Vec<ValueType>, level: u64) -> usize {
+    // `DefaultHasher::new` uses fixed keys, so a group lands in the same partition in every table of a level
+    let mut hasher = DefaultHasher::new();
+    level.hash(&mut hasher);
+    key.hash(&mut hasher);
+    (hasher.finish() % PARTITIONS as u64) as usize
+}
+
+pub fn key_bytes(key: &Vec<ValueType>) -> usize {
+    mem::size_of::<Vec<ValueType>>() + key.capacity() * mem::size_of::<ValueType>() +
+        key.iter().map(|value| value.heap_size_of_children()).sum::<usize>()
+}
+
+pub fn accumulators_bytes(accumulators: &Vec<Accumulator>) -> usize {
+    mem::size_of::<Vec<Accumulator>>() + accumulators.iter().map(|accumulator| accumulator.estimated_bytes()).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts `keys` in a table that spills once its groups take more than `budget` bytes.
+    fn count(keys: &[i64], budget: usize) -> GroupTable {
+        let mut table = GroupTable::new(vec![Aggregator::Count], Some(budget));
+        for &key in keys {
+            table
+                .update(vec![ValueType::Integer(key)], |accumulators| {
+                    Aggregator::Count.reduce(&mut accumulators[0], &ValueType::Integer(key))
+                })
+                .unwrap();
+        }
+        table
+    }
+
+    #[test]
+    fn splits_partitions_that_exceed_the_budget() {
+        // Each of the 16 partitions holds far more groups than fit into the budget
+        let keys: Vec<i64> = (0..40000).map(|i| i % 20000).collect();
+        let budget = 200 * (key_bytes(&vec![ValueType::Integer(0)]) + 64);
+        let mut table = count(&keys[..20000], budget);
+        table.merge(count(&keys[20000..], budget)).unwrap();
+        let spill_dirs = table.spill_dirs.clone();
+        assert_eq!(spill_dirs.len(), 2);
+        assert!(spill_dirs.iter().all(|dir| dir.exists()));
+        let mut rows = table.into_rows().unwrap();
+        rows.sort();
+        let expected: Vec<Vec<ValueType>> = (0..20000)
+            .map(|key| vec![ValueType::Integer(key), ValueType::Integer(2)])
+            .collect();
+        assert_eq!(rows, expected);
+        assert!(spill_dirs.iter().all(|dir| !dir.exists()));
+    }
+
+    #[test]
+    fn rejects_corrupt_spill_files() {
+        let keys: Vec<i64> = (0..1000).collect();
+        let budget = 100 * (key_bytes(&vec![ValueType::Integer(0)]) + 64);
+        let huge_key = [1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        let huge_string = [1, 1, 0, 0, 0, 0, 0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        let corruptions: Vec<(&str, Box<dyn Fn(&PathBuf)>)> = vec![
+            ("truncated", Box::new(|path: &PathBuf| {
+                let len = fs::metadata(path).unwrap().len();
+                OpenOptions::new().write(true).open(path).unwrap().set_len(len - 3).unwrap();
+            })),
+            ("garbage", Box::new(|path: &PathBuf| fs::write(path, b"\x07garbage").unwrap())),
+            ("huge key", Box::new(move |path: &PathBuf| fs::write(path, &huge_key[..]).unwrap())),
+            ("huge string", Box::new(move |path: &PathBuf| fs::write(path, &huge_string[..]).unwrap())),
+        ];
+        for (name, corrupt) in corruptions {
+            let table = count(&keys, budget);
+            let spilled = table.partitions.iter().find(|files| !files.is_empty()).unwrap()[0].clone();
+            corrupt(&spilled);
+            let spill_dirs = table.spill_dirs.clone();
+            let err = table.into_rows().unwrap_err();
+            assert!(err.starts_with("Failed to read spilled groups"), "{}: {}", name, err);
+            assert!(spill_dirs.iter().all(|dir| !dir.exists()), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use spill::{read_u64, write_u64};
+use value::ValueType;
+
+pub const DEFAULT_PRECISION: usize = 14;
+pub const MIN_PRECISION: usize = 4;
+pub const MAX_PRECISION: usize = 16;
+
+/// HyperLogLog sketch for estimating the number of distinct values using 2^precision one-byte registers.
+/// The standard error of the estimate is about 1.04 / sqrt(2^precision), e.g. 0.81% for the default precision of 14.
+/// Sketches with the same precision can be merged, so batches can be counted independently.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    precision: usize,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: usize) -> HyperLogLog {
+        HyperLogLog {
+            precision: precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn insert(&mut self, value: &ValueType) {
+        // `DefaultHasher::new` uses fixed keys, so every thread hashes a value to the same register
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as usize).min(64 - self.precision) as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        assert_eq!(self.precision, other.precision);
+        for (register, &other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if other > *register {
+                *register = other;
+            }
+        }
+    }
+
+    pub fn registers(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_u64(writer, self.precision as u64)?;
+        writer.write_all(&self.registers)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<HyperLogLog> {
+        let precision = read_u64(reader)?;
+        if precision < MIN_PRECISION as u64 || precision > MAX_PRECISION as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid precision {}", precision)));
+        }
+        let mut sketch = HyperLogLog::new(precision as usize);
+        reader.read_exact(&mut sketch.registers)?;
+        Ok(sketch)
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small cardinalities
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(precision: usize, values: ::std::ops::Range<i64>) -> HyperLogLog {
+        let mut sketch = HyperLogLog::new(precision);
+        for i in values {
+            sketch.insert(&ValueType::Integer(i));
+        }
+        sketch
+    }
+
+    /// Asserts that the estimate is within three standard errors of `count`.
+    fn assert_close(sketch: &HyperLogLog, count: u64) {
+        let error = 3.0 * 1.04 / (sketch.registers() as f64).sqrt();
+        let estimate = sketch.estimate() as f64;
+        assert!(
+            (estimate - count as f64).abs() <= error * count as f64,
+            "estimated {} for {} distinct values",
+            estimate,
+            count
+        );
+    }
+
+    #[test]
+    fn estimates_within_error_bounds() {
+        for &count in &[1000, 20_000, 200_000] {
+            assert_close(&sketch(DEFAULT_PRECISION, 0..count), count as u64);
+        }
+        for precision in MIN_PRECISION..MAX_PRECISION + 1 {
+            assert_close(&sketch(precision, 0..50_000), 50_000);
+        }
+    }
+
+    #[test]
+    fn counts_small_cardinalities_almost_exactly() {
+        assert_eq!(sketch(DEFAULT_PRECISION, 0..0).estimate(), 0);
+        let estimate = sketch(DEFAULT_PRECISION, 0..100).estimate();
+        assert!(estimate >= 99 && estimate <= 101, "estimated {}", estimate);
+    }
+
+    #[test]
+    fn ignores_duplicates() {
+        let mut duplicated = sketch(DEFAULT_PRECISION, 0..10_000);
+        for i in 0..10_000 {
+            duplicated.insert(&ValueType::Integer(i));
+        }
+        assert_eq!(duplicated.estimate(), sketch(DEFAULT_PRECISION, 0..10_000).estimate());
+    }
+
+    #[test]
+    fn merge_estimates_union() {
+        let mut merged = sketch(DEFAULT_PRECISION, 0..60_000);
+        merged.merge(&sketch(DEFAULT_PRECISION, 40_000..100_000));
+        assert_eq!(merged.registers, sketch(DEFAULT_PRECISION, 0..100_000).registers);
+        assert_close(&merged, 100_000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn merge_requires_same_precision() {
+        sketch(10, 0..10).merge(&sketch(12, 0..10));
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let original = sketch(12, 0..5000);
+        let mut bytes = Vec::new();
+        original.write_to(&mut bytes).unwrap();
+        let read = HyperLogLog::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read.precision, original.precision);
+        assert_eq!(read.registers, original.registers);
+    }
+}
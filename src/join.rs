@@ -0,0 +1,879 @@
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::Arc;
+
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use batch_store::BatchStore;
+use cancellation::Cancellation;
+use catalog::Catalog;
+use columns::{Batch, ColIter, Column};
+use expression::{Expr, FuncType};
+use query_engine::{Query, RecordSource};
+use value::ValueType;
+use zone_map::{ColumnPredicate, ZoneMap, DEFAULT_SELECTIVITY};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: String,
+    pub on: Expr,
+}
+
+/// Number of rows of a probe batch whose filter columns are read before the matching rows are materialized.
+const SCAN_CHUNK_SIZE: usize = 4096;
+
+/// A column of one of the tables of a query, which are numbered in the order they appear in the from clause.
+type TableColumn = (usize, String);
+
+/// Describes how records are produced for a query. The batches of the probe table are scanned and filtered, and
+/// every row is then extended with the matching rows of each joined table, which are loaded into hash tables up front.
+/// Records hold the referenced columns of the probe table followed by those of each joined table.
+///
+/// Columns can be qualified by their table as in `events.url`. Unqualified names refer to the first table in the
+/// from clause that has a column of that name.
+pub struct JoinPlan {
+    probe_table: String,
+    probe_columns: Vec<String>,
+    probe_filter: Expr,
+    /// Conditions on the probe table that zone maps can evaluate, and the number of other conditions.
+    probe_predicates: Vec<ColumnPredicate>,
+    unindexed_conditions: usize,
+    column_indices: HashMap<String, usize>,
+    filter: Expr,
+    joins: Vec<HashJoin>,
+    /// Fraction of the probe rows that are randomly sampled.
+    sample: Option<f64>,
+}
+
+/// Hash table over the rows of a joined table, keyed by the values of its join columns.
+struct HashJoin {
+    kind: JoinKind,
+    width: usize,
+    /// Description for explain, with columns numbered as in joined records.
+    description: String,
+    /// Evaluated on the record before the join to look up matching rows.
+    probe_keys: Vec<Expr>,
+    /// Remaining conditions of the on clause, evaluated on the joined record.
+    condition: Expr,
+    rows: HashMap<Vec<ValueType>, Vec<Vec<ValueType>>>,
+}
+
+impl JoinPlan {
+    /// Resolves the tables of `query` and builds the hash tables of all joins unless `build_hash_tables` is false,
+    /// which leaves them empty. Returns the table to probe.
+    pub fn new<'a>(
+        query: &Query,
+        catalog: &'a Catalog,
+        build_hash_tables: bool,
+        cancel: &Cancellation,
+    ) -> Result<(&'a BatchStore, JoinPlan), String> {
+        let from = match query.from {
+            Some(ref table) => table.clone(),
+            None => catalog.default_table().ok_or("No table loaded")?.to_string(),
+        };
+        let names: Vec<String> = iter::once(from)
+            .chain(query.joins.iter().map(|join| join.table.clone()))
+            .collect();
+        let mut tables = Vec::new();
+        for name in &names {
+            if names.iter().filter(|other| *other == name).count() > 1 {
+                return Err(format!("Table {} can only be used once per query", name));
+            }
+            tables.push(catalog.get(name).ok_or(format!("Unknown table {}", name))?);
+        }
+        let plan = JoinPlan::build(query, &names, &tables, build_hash_tables, cancel)?;
+        Ok((tables[plan.1], plan.0))
+    }
+
+    fn build(
+        query: &Query,
+        names: &Vec<String>,
+        tables: &Vec<&BatchStore>,
+        build_hash_tables: bool,
+        cancel: &Cancellation,
+    ) -> Result<(JoinPlan, usize), String> {
+        let columns = tables.iter().map(|table| table.column_names().clone()).collect();
+        let rows = tables.iter().map(|table| table.rows()).collect();
+        let (resolved, order, layout) = JoinPlan::resolve(query, names, &columns, &rows)?;
+        let plan = JoinPlan::plan(query, names, &resolved, &order, layout, tables, build_hash_tables, cancel)?;
+        Ok((plan, order[0]))
+    }
+
+    /// Maps every referenced column name to its table and determines the order in which tables are joined, starting
+    /// with the probed table, and the columns of each table in records.
+    fn resolve(
+        query: &Query,
+        names: &Vec<String>,
+        columns: &Vec<Vec<String>>,
+        rows: &Vec<usize>,
+    ) -> Result<(HashMap<Arc<String>, TableColumn>, Vec<usize>, Vec<Vec<TableColumn>>), String> {
+        let mut referenced = query.find_referenced_cols();
+        for join in &query.joins {
+            join.on.add_colnames(&mut referenced);
+        }
+        let mut resolved = HashMap::new();
+        for name in referenced {
+            if let Some(column) = resolve_column(&name, names, columns)? {
+                resolved.insert(name, column);
+            }
+        }
+
+        // A single inner join can be computed in either direction, so the larger table is probed, unless the first
+        // table is sampled
+        let probe = match query.joins.first() {
+            Some(join) if query.joins.len() == 1 && join.kind == JoinKind::Inner && rows[1] > rows[0] &&
+                query.sample.is_none() => 1,
+            _ => 0,
+        };
+        let order: Vec<usize> = iter::once(probe).chain((0..names.len()).filter(|&table| table != probe)).collect();
+        let mut layout = Vec::new();
+        for &table in &order {
+            let mut table_columns: Vec<TableColumn> = resolved
+                .values()
+                .filter(|&&(t, _)| t == table)
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            table_columns.sort();
+            if table == probe && table_columns.is_empty() {
+                // Still scan one column so that rows get counted
+                table_columns.extend(columns[probe].first().map(|name| (probe, name.clone())));
+            }
+            layout.push(table_columns);
+        }
+        Ok((resolved, order, layout))
+    }
+
+    fn plan(
+        query: &Query,
+        names: &Vec<String>,
+        resolved: &HashMap<Arc<String>, TableColumn>,
+        order: &Vec<usize>,
+        layout: Vec<Vec<TableColumn>>,
+        tables: &Vec<&BatchStore>,
+        build_hash_tables: bool,
+        cancel: &Cancellation,
+    ) -> Result<JoinPlan, String> {
+        let probe = order[0];
+        let record: Vec<&TableColumn> = layout.iter().flat_map(|columns| columns.iter()).collect();
+        let column_indices = column_map(resolved, &record);
+        let tables_of = |expr: &Expr| -> HashSet<usize> {
+            expr.find_colnames()
+                .iter()
+                .filter_map(|name| resolved.get(name).map(|&(table, _)| table))
+                .collect()
+        };
+        let kind_of = |table: usize| if table == 0 { JoinKind::Inner } else { query.joins[table - 1].kind };
+        // Zone maps of the probe table estimate the fraction of rows that comparisons of its columns let through
+        let probe_rows = tables.get(probe).map_or(0, |table| table.rows());
+        let estimate = |expr: &Expr| -> Option<f64> {
+            let table = tables.get(probe).filter(|_| probe_rows > 0)?;
+            let predicate = ColumnPredicate::from_expr(expr, |column| match column {
+                &Expr::ColIndex(index) => layout[0].get(index).map(|&(_, ref name)| name.clone()),
+                _ => None,
+            })?;
+            let matching: f64 = (0..table.len())
+                .map(|i| table.zone_map(i).rows() as f64 * table.zone_map(i).selectivity(&predicate))
+                .sum();
+            Some(matching / probe_rows as f64)
+        };
+        let no_estimate = |_: &Expr| None;
+
+        // Push conditions of the where clause down to the table they refer to, unless rows of that table are
+        // padded with nulls by a left join
+        let mut probe_filter = Vec::new();
+        let mut build_filters = vec![Vec::new(); names.len()];
+        let mut filter = Vec::new();
+        for conjunct in conjuncts(&query.filter) {
+            let tables = tables_of(&conjunct);
+            if tables.iter().all(|&table| table == probe) {
+                probe_filter.push(conjunct);
+            } else if tables.len() == 1 && kind_of(*tables.iter().next().unwrap()) == JoinKind::Inner {
+                build_filters[*tables.iter().next().unwrap()].push(conjunct);
+            } else {
+                filter.push(conjunct);
+            }
+        }
+
+        let mut joins = Vec::new();
+        for (position, &table) in order.iter().enumerate().skip(1) {
+            let earlier: HashSet<usize> = order[..position].iter().cloned().collect();
+            let on = &query.joins[if table == 0 { 0 } else { table - 1 }].on;
+            let mut probe_keys = Vec::new();
+            let mut build_keys = Vec::new();
+            let mut condition = Vec::new();
+            for conjunct in conjuncts(on) {
+                let tables = tables_of(&conjunct);
+                if let Expr::Func(FuncType::Equals, ref expr1, ref expr2) = conjunct {
+                    let (tables1, tables2) = (tables_of(expr1), tables_of(expr2));
+                    let refers_to = |tables: &HashSet<usize>| !tables.is_empty() && tables.is_subset(&earlier);
+                    if tables1 == iter::once(table).collect() && refers_to(&tables2) {
+                        build_keys.push((**expr1).clone());
+                        probe_keys.push((**expr2).clone());
+                        continue;
+                    } else if tables2 == iter::once(table).collect() && refers_to(&tables1) {
+                        build_keys.push((**expr2).clone());
+                        probe_keys.push((**expr1).clone());
+                        continue;
+                    }
+                }
+                if tables.iter().all(|&t| t == table) {
+                    build_filters[table].push(conjunct);
+                } else if tables.iter().all(|&t| t == table || earlier.contains(&t)) {
+                    condition.push(conjunct);
+                } else {
+                    return Err(format!("Condition of join with {} refers to a table that is joined later", names[table]));
+                }
+            }
+            if build_keys.is_empty() {
+                return Err(format!(
+                    "Join with {} requires an equality between its columns and those of the tables before it",
+                    names[table]
+                ));
+            }
+
+            let build_columns: Vec<&TableColumn> = layout[position].iter().collect();
+            let build_indices = column_map(resolved, &build_columns);
+            let keys: Vec<String> = probe_keys
+                .iter()
+                .zip(build_keys.iter())
+                .map(|(probe, build)| format!("{} = {}", probe.compile(&column_indices), build.compile(&column_indices)))
+                .collect();
+            let description = format!(
+                "{:?} hash join with {} ({} rows) on {}, filter on {}: {}, condition: {}",
+                kind_of(table),
+                names[table],
+                tables.get(table).map_or(0, |table| table.rows()),
+                keys.join(" and "),
+                names[table],
+                conjunction(&build_filters[table]).compile(&column_indices).optimize_filter(&no_estimate),
+                conjunction(&condition).compile(&column_indices).optimize_filter(&no_estimate)
+            );
+            let build_keys: Vec<Expr> = build_keys.iter().map(|expr| expr.compile(&build_indices)).collect();
+            let build_filter = conjunction(&build_filters[table]).compile(&build_indices).optimize_filter(&no_estimate);
+            let build_predicates: Vec<ColumnPredicate> = build_filters[table]
+                .iter()
+                .filter_map(|expr| ColumnPredicate::from_expr(expr, |expr| column_of(resolved, table, expr)))
+                .collect();
+            let build_columns: Vec<String> = build_columns.iter().map(|&&(_, ref name)| name.clone()).collect();
+            let rows = if build_hash_tables {
+                build_hash_table(tables[table], &build_columns, &build_filter, &build_predicates, &build_keys, cancel)?
+            } else {
+                HashMap::new()
+            };
+            joins.push(HashJoin {
+                kind: kind_of(table),
+                width: build_columns.len(),
+                description: description,
+                probe_keys: probe_keys.iter().map(|expr| expr.compile(&column_indices)).collect(),
+                condition: conjunction(&condition).compile(&column_indices).optimize_filter(&no_estimate),
+                rows: rows,
+            });
+        }
+
+        let mut probe_predicates = Vec::new();
+        let mut unindexed_conditions = 0;
+        for conjunct in &probe_filter {
+            match ColumnPredicate::from_expr(conjunct, |expr| column_of(resolved, probe, expr)) {
+                Some(predicate) => probe_predicates.push(predicate),
+                None => unindexed_conditions += 1,
+            }
+        }
+
+        Ok(JoinPlan {
+            probe_table: names[probe].clone(),
+            probe_predicates: probe_predicates,
+            unindexed_conditions: unindexed_conditions,
+            probe_columns: layout[0].iter().map(|&(_, ref name)| name.clone()).collect(),
+            probe_filter: conjunction(&probe_filter).compile(&column_indices).optimize_filter(&estimate),
+            filter: conjunction(&filter).compile(&column_indices).optimize_filter(&estimate),
+            column_indices: column_indices,
+            joins: joins,
+            sample: query.sample,
+        })
+    }
+
+    /// Returns false if the filter is never true or zone maps show that no row of a probe batch passes it.
+    pub fn may_match(&self, zone_map: &ZoneMap) -> bool {
+        let never = Expr::Const(ValueType::Bool(false));
+        self.probe_filter != never && self.filter != never &&
+            self.probe_predicates.iter().all(|predicate| zone_map.may_match(predicate))
+    }
+
+    /// Estimated fraction of the rows of a probe batch that pass the filter, assuming independent conditions.
+    pub fn selectivity(&self, zone_map: &ZoneMap) -> f64 {
+        self.probe_predicates
+            .iter()
+            .map(|predicate| zone_map.selectivity(predicate))
+            .product::<f64>() * DEFAULT_SELECTIVITY.powi(self.unindexed_conditions as i32)
+    }
+
+    pub fn probe_table(&self) -> &str {
+        &self.probe_table
+    }
+
+    /// Describes the columns read, the compiled filters and the joins, one line each.
+    pub fn describe(&self) -> Vec<String> {
+        let mut names: Vec<(usize, &str)> = self.column_indices
+            .iter()
+            .map(|(name, &index)| (index, name.as_ref()))
+            .collect();
+        names.sort();
+        let columns: Vec<String> = names.iter().map(|&(index, name)| format!("#{} {}", index, name)).collect();
+        let predicates: Vec<String> = self.probe_predicates.iter().map(|p| p.to_string()).collect();
+        let filter_columns = self.probe_filter.find_col_indices();
+        let (filtered, materialized): (Vec<(usize, &String)>, Vec<(usize, &String)>) = self.probe_columns
+            .iter()
+            .enumerate()
+            .partition(|&(index, _)| filter_columns.contains(&index));
+        let join = |columns: Vec<(usize, &String)>| columns.iter().map(|&(_, name)| name.as_str()).join(", ");
+        let read = if filtered.is_empty() || materialized.is_empty() {
+            format!("Read columns {} of {}", self.probe_columns.join(", "), self.probe_table)
+        } else {
+            format!(
+                "Read columns {} of {}, then {} only for rows passing the filter",
+                join(filtered),
+                self.probe_table,
+                join(materialized)
+            )
+        };
+        let mut lines = vec![read];
+        if let Some(fraction) = self.sample {
+            lines.push(format!("Sample {}% of the rows of {}", fraction * 100.0, self.probe_table));
+        }
+        lines.extend(vec![
+            format!("Record layout: {}", columns.join(", ")),
+            format!(
+                "Zone map conditions: {}",
+                if predicates.is_empty() { "none".to_string() } else { predicates.join(", ") }
+            ),
+        ]);
+        if self.joins.is_empty() {
+            lines.push(format!("Filter: {}", self.probe_filter));
+            return lines;
+        }
+        lines.push(format!("Filter on {}: {}", self.probe_table, self.probe_filter));
+        lines.extend(self.joins.iter().map(|join| join.description.clone()));
+        lines.push(format!("Filter after joins: {}", self.filter));
+        lines
+    }
+
+    pub fn has_joins(&self) -> bool {
+        !self.joins.is_empty()
+    }
+
+    /// Positions of the referenced columns in records, under the names they are referred to by.
+    pub fn column_indices(&self) -> &HashMap<String, usize> {
+        &self.column_indices
+    }
+
+    /// The part of the where clause that is evaluated on joined records.
+    pub fn filter(&self) -> &Expr {
+        &self.filter
+    }
+
+    /// Scans the rows of a probe batch that pass the filter on the probe table. The scan ends early once the query is
+    /// cancelled, which the caller has to check afterwards. A sample is drawn with `seed`, or at random without one.
+    pub fn probe_scan<'a>(&'a self, batch: &'a Batch, seed: Option<u64>, cancel: &'a Cancellation) -> ProbeScan<'a> {
+        let len = batch.cols.first().map_or(0, |col| col.len());
+        let columns: Vec<Option<&Column>> = self.probe_columns
+            .iter()
+            .map(|name| batch.cols.iter().find(|col| col.get_name() == name).map(|col| &**col))
+            .collect();
+        let filter_columns = self.probe_filter
+            .find_col_indices()
+            .into_iter()
+            .map(|index| {
+                let iter = columns[index].map_or_else(|| ColIter::nulls(len), |col| col.iter());
+                (index, iter)
+            })
+            .collect();
+        let mut scan = ProbeScan {
+            filter: &self.probe_filter,
+            filter_record: vec![ValueType::Null; columns.len()],
+            columns: columns,
+            filter_columns: filter_columns,
+            len: len,
+            position: 0,
+            rows_evaluated: 0,
+            sample: self.sample,
+            next_sample: 0,
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            selected: Vec::new(),
+            cancel: cancel,
+        };
+        scan.skip_unsampled();
+        scan
+    }
+
+    pub fn scan<'a>(&'a self, batch: &'a Batch, seed: Option<u64>, cancel: &'a Cancellation) -> JoinScan<'a> {
+        JoinScan {
+            plan: self,
+            probe: self.probe_scan(batch, seed, cancel),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Filters a probe batch in two phases: the columns the filter refers to are read to find the positions of matching
+/// rows, and the other columns are only read at those positions. Rows are processed in chunks, so that queries with
+/// a limit can stop early.
+///
+/// When sampling, each row is included independently with the sample probability, and only the filter columns of
+/// included rows are read.
+pub struct ProbeScan<'a> {
+    filter: &'a Expr,
+    /// Probe columns of the batch, `None` if missing from it.
+    columns: Vec<Option<&'a Column>>,
+    filter_columns: Vec<(usize, ColIter<'a>)>,
+    filter_record: Vec<ValueType>,
+    len: usize,
+    position: usize,
+    rows_evaluated: u64,
+    sample: Option<f64>,
+    /// Position of the next sampled row.
+    next_sample: usize,
+    rng: StdRng,
+    /// Values of the matching rows of the current chunk.
+    selected: Vec<ColIter<'a>>,
+    cancel: &'a Cancellation,
+}
+
+impl<'a> ProbeScan<'a> {
+    /// Number of rows of the batch that the filter has been evaluated on.
+    pub fn rows_scanned(&self) -> u64 {
+        self.rows_evaluated
+    }
+
+    fn next_chunk(&mut self) {
+        let end = (self.position + SCAN_CHUNK_SIZE).min(self.len);
+        let positions: Vec<usize> = if self.sample.is_some() {
+            let mut positions = Vec::new();
+            while self.next_sample < end {
+                positions.push(self.next_sample);
+                self.next_sample += 1;
+                self.skip_unsampled();
+            }
+            let columns = &self.columns;
+            for &mut (index, ref mut col) in &mut self.filter_columns {
+                *col = match columns[index] {
+                    Some(column) => ColIter::from_values(column.select(&positions)),
+                    None => ColIter::nulls(positions.len()),
+                };
+            }
+            positions
+        } else {
+            (self.position..end).collect()
+        };
+        let mut selection = Vec::new();
+        for position in positions {
+            for &mut (index, ref mut col) in &mut self.filter_columns {
+                self.filter_record[index] = col.next().unwrap_or(ValueType::Null);
+            }
+            if self.filter.eval(&self.filter_record) == ValueType::Bool(true) {
+                selection.push(position);
+            }
+            self.rows_evaluated += 1;
+        }
+        self.position = end;
+        self.selected = self.columns
+            .iter()
+            .map(|col| match col {
+                &Some(col) => ColIter::from_values(col.select(&selection)),
+                &None => ColIter::nulls(selection.len()),
+            })
+            .collect();
+    }
+
+    /// Advances the next sampled row past the rows left out, whose number follows a geometric distribution.
+    fn skip_unsampled(&mut self) {
+        if let Some(fraction) = self.sample {
+            if fraction < 1.0 {
+                let uniform: f64 = self.rng.gen();
+                let skipped = ((1.0 - uniform).ln() / (1.0 - fraction).ln()).floor();
+                self.next_sample = self.next_sample.saturating_add(skipped.min(self.len as f64) as usize);
+            }
+        }
+    }
+}
+
+impl<'a> RecordSource for ProbeScan<'a> {
+    fn next_record(&mut self, record: &mut Vec<ValueType>) -> bool {
+        loop {
+            if self.selected.next_record(record) {
+                return true;
+            }
+            // Checked once per chunk, since a selective filter may evaluate many chunks before a record matches
+            if self.position == self.len || self.cancel.check().is_err() {
+                return false;
+            }
+            self.next_chunk();
+        }
+    }
+}
+
+/// Produces the joined records for one batch of the probe table.
+pub struct JoinScan<'a> {
+    plan: &'a JoinPlan,
+    probe: ProbeScan<'a>,
+    pending: Vec<Vec<ValueType>>,
+}
+
+impl<'a> JoinScan<'a> {
+    pub fn rows_scanned(&self) -> u64 {
+        self.probe.rows_scanned()
+    }
+}
+
+impl<'a> RecordSource for JoinScan<'a> {
+    fn next_record(&mut self, record: &mut Vec<ValueType>) -> bool {
+        loop {
+            if let Some(next) = self.pending.pop() {
+                *record = next;
+                return true;
+            }
+            if !self.probe.next_record(record) {
+                return false;
+            }
+            let mut joined = vec![record.clone()];
+            for join in &self.plan.joins {
+                let mut next = Vec::new();
+                for record in joined {
+                    join.probe(record, &mut next);
+                }
+                joined = next;
+            }
+            joined.reverse();
+            self.pending = joined;
+        }
+    }
+}
+
+impl HashJoin {
+    /// Appends `record` extended by each matching row to `output`, or padded with nulls for left joins without match.
+    fn probe(&self, record: Vec<ValueType>, output: &mut Vec<Vec<ValueType>>) {
+        let matched = output.len();
+        let key: Vec<ValueType> = self.probe_keys.iter().map(|expr| expr.eval(&record)).collect();
+        if let Some(rows) = self.rows.get(&key) {
+            for row in rows {
+                let mut joined = record.clone();
+                joined.extend(row.iter().cloned());
+                if self.condition.eval(&joined) == ValueType::Bool(true) {
+                    output.push(joined);
+                }
+            }
+        }
+        if output.len() == matched && self.kind == JoinKind::Left {
+            let mut joined = record;
+            joined.extend(iter::repeat(ValueType::Null).take(self.width));
+            output.push(joined);
+        }
+    }
+}
+
+fn build_hash_table(
+    table: &BatchStore,
+    columns: &Vec<String>,
+    filter: &Expr,
+    predicates: &Vec<ColumnPredicate>,
+    keys: &Vec<Expr>,
+    cancel: &Cancellation,
+) -> Result<HashMap<Vec<ValueType>, Vec<Vec<ValueType>>>, String> {
+    let mut rows = HashMap::new();
+    let mut record = Vec::with_capacity(columns.len());
+    if *filter == Expr::Const(ValueType::Bool(false)) {
+        return Ok(rows);
+    }
+    for i in 0..table.len() {
+        if !predicates.iter().all(|predicate| table.zone_map(i).may_match(predicate)) {
+            continue;
+        }
+        cancel.check()?;
+        let batch = table.get(i)?;
+        let mut source = column_iters(&batch, columns);
+        while source.next_record(&mut record) {
+            if filter.eval(&record) != ValueType::Bool(true) {
+                continue;
+            }
+            let key: Vec<ValueType> = keys.iter().map(|expr| expr.eval(&record)).collect();
+            // Nulls never compare equal in joins
+            if !key.contains(&ValueType::Null) {
+                rows.entry(key).or_insert_with(Vec::new).push(record.clone());
+            }
+        }
+    }
+    Ok(rows)
+}
+
+fn column_iters<'a>(batch: &'a Batch, columns: &Vec<String>) -> Vec<ColIter<'a>> {
+    let len = batch.cols.first().map_or(0, |col| col.len());
+    columns
+        .iter()
+        .map(|name| match batch.cols.iter().find(|col| col.get_name() == name) {
+            Some(col) => col.iter(),
+            None => ColIter::nulls(len),
+        })
+        .collect()
+}
+
+/// The column of `table` that `expr` refers to, if it is a column name.
+fn column_of(resolved: &HashMap<Arc<String>, TableColumn>, table: usize, expr: &Expr) -> Option<String> {
+    let name = match expr {
+        &Expr::ColName(ref name) => name,
+        _ => return None,
+    };
+    resolved
+        .get(name)
+        .filter(|&&(t, _)| t == table)
+        .map(|&(_, ref column)| column.clone())
+}
+
+fn resolve_column(name: &str, tables: &Vec<String>, columns: &Vec<Vec<String>>) -> Result<Option<TableColumn>, String> {
+    if let Some(dot) = name.find('.') {
+        if let Some(table) = tables.iter().position(|table| *table == name[..dot]) {
+            return Ok(Some((table, name[dot + 1..].to_string())));
+        }
+    }
+    match columns.iter().position(|table_columns| table_columns.iter().any(|column| column == name)) {
+        Some(table) => Ok(Some((table, name.to_string()))),
+        None => match name.find('.') {
+            Some(dot) => Err(format!("Unknown table {} in column {}", &name[..dot], name)),
+            None => Ok(None),
+        },
+    }
+}
+
+/// Maps the names of the columns in `record` to their position.
+fn column_map(resolved: &HashMap<Arc<String>, TableColumn>, record: &Vec<&TableColumn>) -> HashMap<String, usize> {
+    resolved
+        .iter()
+        .filter_map(|(name, column)| {
+            record
+                .iter()
+                .position(|&c| c == column)
+                .map(|index| (name.to_string(), index))
+        })
+        .collect()
+}
+
+fn conjuncts(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        &Expr::Func(FuncType::And, ref expr1, ref expr2) => {
+            let mut result = conjuncts(expr1);
+            result.extend(conjuncts(expr2));
+            result
+        }
+        &Expr::Const(ValueType::Bool(true)) => Vec::new(),
+        other => vec![other.clone()],
+    }
+}
+
+fn conjunction(exprs: &Vec<Expr>) -> Expr {
+    let mut exprs = exprs.iter().cloned();
+    match exprs.next() {
+        Some(first) => exprs.fold(first, |acc, expr| Expr::func(FuncType::And, acc, expr)),
+        None => Expr::Const(ValueType::Bool(true)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use columns::{columnarize, nullable_column, VecType};
+    use parser::parse_query;
+    use query_engine::ExecutionOptions;
+    use result_cache::ResultCache;
+    use std::sync::atomic::AtomicBool;
+    use value::ValueType::*;
+
+    fn int(value: Option<i64>) -> ValueType {
+        value.map_or(Null, Integer)
+    }
+
+    fn string(value: &str) -> ValueType {
+        Str(Arc::new(value.to_string()))
+    }
+
+    /// Events with keys 1, 2, 2, null and 3, and pages with keys 1, 2, 2 and null, split into two batches so that
+    /// the duplicate keys of pages come from different batches.
+    fn catalog() -> Catalog {
+        let mut events = BatchStore::new(None);
+        let keys = vec![Some(1), Some(2), Some(2), None, Some(3)];
+        events.push(columnarize(
+            keys.into_iter()
+                .enumerate()
+                .map(|(i, key)| vec![("id".to_string(), Integer(i as i64 + 1)), ("key".to_string(), int(key))])
+                .collect(),
+        )).unwrap();
+        let mut pages = BatchStore::new(None);
+        let page = |key: Option<i64>, name: &str| vec![("key".to_string(), int(key)), ("name".to_string(), string(name))];
+        pages.push(columnarize(vec![page(Some(1), "a"), page(Some(2), "b")])).unwrap();
+        pages.push(columnarize(vec![page(Some(2), "c"), page(None, "n")])).unwrap();
+        let mut catalog = Catalog::new();
+        catalog.add("events".to_string(), events);
+        catalog.add("pages".to_string(), pages);
+        catalog
+    }
+
+    fn run(query: &str) -> Vec<Vec<ValueType>> {
+        run_on(&catalog(), query)
+    }
+
+    fn run_on(catalog: &Catalog, query: &str) -> Vec<Vec<ValueType>> {
+        let query = parse_query(format!("{};", query).as_bytes()).unwrap().1;
+        query.validate().unwrap();
+        let options = ExecutionOptions {
+            threads: 2,
+            memory_limit: None,
+            group_memory_limit: None,
+            sample_seed: None,
+            cancel: Cancellation::new(Arc::new(AtomicBool::new(false)), None),
+            cache: Arc::new(ResultCache::new(0, 0)),
+        };
+        query.execute(catalog, &options).unwrap().rows
+    }
+
+    fn rows(expected: Vec<(i64, Option<&str>)>) -> Vec<Vec<ValueType>> {
+        expected
+            .into_iter()
+            .map(|(id, name)| vec![Integer(id), name.map_or(Null, string)])
+            .collect()
+    }
+
+    #[test]
+    fn inner_join_skips_null_keys_and_pairs_duplicates() {
+        let expected = rows(vec![(1, Some("a")), (2, Some("b")), (2, Some("c")), (3, Some("b")), (3, Some("c"))]);
+        assert_eq!(
+            run("select id, name from events join pages on =(events.key, pages.key) order by id, name"),
+            expected
+        );
+        assert_eq!(
+            run("select id, name from pages join events on =(pages.key, events.key) order by id, name"),
+            expected
+        );
+    }
+
+    #[test]
+    fn left_join_keeps_unmatched_and_null_keys() {
+        assert_eq!(
+            run("select id, name from events left join pages on =(events.key, pages.key) order by id, name"),
+            rows(vec![
+                (1, Some("a")),
+                (2, Some("b")),
+                (2, Some("c")),
+                (3, Some("b")),
+                (3, Some("c")),
+                (4, None),
+                (5, None),
+            ])
+        );
+    }
+
+    #[test]
+    fn left_join_filters_on_padded_rows() {
+        assert_eq!(
+            run("select id, name from events left join pages on =(events.key, pages.key) where =(name, \"c\") order by id"),
+            rows(vec![(2, Some("c")), (3, Some("c"))])
+        );
+        assert_eq!(
+            run("select count(0) from events left join pages on =(events.key, pages.key) where =(name, null)"),
+            vec![vec![Integer(2)]]
+        );
+    }
+
+    #[test]
+    fn materializes_other_columns_only_for_matching_rows() {
+        // A nullable column stores only its present values, so rows must be mapped to the right ones
+        let ids: Vec<i64> = (0..100).collect();
+        let present: Vec<bool> = ids.iter().map(|i| i % 3 != 0).collect();
+        let values: Vec<i64> = ids.iter().filter(|i| *i % 3 != 0).map(|i| i * 10).collect();
+        let mut table = BatchStore::new(None);
+        table
+            .push(Batch {
+                cols: vec![
+                    VecType::IntegerVec(ids.clone()).to_column("id".to_string()),
+                    nullable_column("v".to_string(), present, VecType::IntegerVec(values)),
+                ],
+            })
+            .unwrap();
+        let mut catalog = Catalog::new();
+        catalog.add("n".to_string(), table);
+
+        let expected: Vec<Vec<ValueType>> = ids
+            .iter()
+            .filter(|&&i| i < 10 || (i > 40 && i < 45) || i > 97)
+            .map(|&i| vec![Integer(i), if i % 3 == 0 { Null } else { Integer(i * 10) }])
+            .collect();
+        assert_eq!(
+            run_on(&catalog, "select id, v from n where or(or(<(id, 10), and(>(id, 40), <(id, 45))), >(id, 97))"),
+            expected
+        );
+        assert_eq!(run_on(&catalog, "select id from n where >(v, 970)"), vec![vec![Integer(98)]]);
+    }
+
+    #[test]
+    fn stops_probe_scans_between_chunks_once_cancelled() {
+        let rows = 3 * SCAN_CHUNK_SIZE;
+        let mut table = BatchStore::new(None);
+        table
+            .push(Batch { cols: vec![VecType::IntegerVec((0..rows as i64).collect()).to_column("id".to_string())] })
+            .unwrap();
+        let mut catalog = Catalog::new();
+        catalog.add("big".to_string(), table);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancel = Cancellation::new(cancelled.clone(), None);
+        let mut record = Vec::new();
+
+        let query = parse_query(b"select id from big where >(id, 0);").unwrap().1;
+        let (table, plan) = JoinPlan::new(&query, &catalog, true, &cancel).unwrap();
+        let batch = table.get(0).unwrap();
+        let mut scan = plan.probe_scan(&batch, None, &cancel);
+        assert!(scan.next_record(&mut record));
+        cancelled.store(true, ::std::sync::atomic::Ordering::SeqCst);
+        let mut records = 1;
+        while scan.next_record(&mut record) {
+            records += 1;
+        }
+        // The current chunk is read to its end, but no further chunk is evaluated
+        assert_eq!(records, SCAN_CHUNK_SIZE - 1);
+        assert_eq!(scan.rows_scanned(), SCAN_CHUNK_SIZE as u64);
+
+        // Scans with a filter that never matches check before every chunk as well
+        let query = parse_query(b"select id from big where <(id, 0);").unwrap().1;
+        let running = Cancellation::new(Arc::new(AtomicBool::new(false)), None);
+        let (_, plan) = JoinPlan::new(&query, &catalog, true, &running).unwrap();
+        let mut scan = plan.probe_scan(&batch, None, &running);
+        assert!(!scan.next_record(&mut record));
+        assert_eq!(scan.rows_scanned(), rows as u64);
+        let mut scan = plan.probe_scan(&batch, None, &cancel);
+        assert!(!scan.next_record(&mut record));
+        assert_eq!(scan.rows_scanned(), 0);
+
+        let options = ExecutionOptions {
+            threads: 2,
+            memory_limit: None,
+            group_memory_limit: None,
+            sample_seed: None,
+            cancel: cancel.clone(),
+            cache: Arc::new(ResultCache::new(0, 0)),
+        };
+        let query = parse_query(b"select count(0) from big where >(id, 0);").unwrap().1;
+        match query.execute(&catalog, &options) {
+            Err(err) => assert_eq!(err, "Query cancelled"),
+            Ok(_) => panic!("Cancelled query succeeded"),
+        }
+    }
+}
@@ -5,28 +5,61 @@ extern crate nom;
 extern crate heapsize;
 extern crate itertools;
 extern crate rustyline;
+extern crate arrow_array;
+extern crate arrow_buffer;
+extern crate arrow_ipc;
+extern crate arrow_schema;
+extern crate ctrlc;
+extern crate rand;
 
 mod aggregator;
+mod arrow_io;
+mod batch_store;
+mod cancellation;
+mod catalog;
 mod columns;
 mod csv_loader;
 mod expression;
+mod group_table;
+mod hyperloglog;
+mod join;
+mod moments;
 mod parser;
 mod query_engine;
+mod result_cache;
+mod space_saving;
+mod spill;
+mod tdigest;
+mod time_bucket;
 mod util;
 mod value;
-use columns::{columnarize, Batch, Column};
-use heapsize::HeapSizeOf;
+mod window;
+mod zone_map;
+use batch_store::BatchStore;
+use cancellation::Cancellation;
+use catalog::Catalog;
+use columns::columnarize;
+use query_engine::{ExecutionOptions, Query};
+use result_cache::ResultCache;
 use time::precise_time_s;
 use value::{RecordType, ValueType};
 
 use itertools::Itertools;
 use serde_json::Value;
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
-use std::rc::Rc;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 const LOAD_CHUNK_SIZE: usize = 100_000;
+const DEFAULT_GROUP_MEMORY_LIMIT_MB: usize = 1024;
+const DEFAULT_CACHE_SIZE_MB: usize = 256;
+const DEFAULT_CACHE_ENTRIES: usize = 1000;
 
 fn json_to_value(json: Value) -> ValueType {
     match json {
@@ -38,8 +71,8 @@ fn json_to_value(json: Value) -> ValueType {
                 .or(n.as_f64().map(|f| ValueType::Integer((1000.0 * f) as i64)))
                 .unwrap()
         }
-        Value::String(s) => ValueType::Str(Rc::new(s)),
-        Value::Array(arr) => ValueType::Set(Rc::new(
+        Value::String(s) => ValueType::Str(Arc::new(s)),
+        Value::Array(arr) => ValueType::Set(Arc::new(
             arr.into_iter()
                 .map(|v| match v {
                     Value::String(s) => s,
@@ -72,14 +105,29 @@ fn read_data(filename: &str) -> Vec<RecordType> {
     }
 }
 
-fn repl(datasource: &Vec<Batch>) {
+/// Runs queries until `exit`. Ctrl-C sets `cancelled`, which cancels the running query; queries that run longer
+/// than `timeout` seconds fail.
+fn repl(
+    catalog: &mut Catalog,
+    memory_limit: Option<usize>,
+    group_memory_limit: Option<usize>,
+    threads: usize,
+    cancelled: Arc<AtomicBool>,
+    mut timeout: Option<f64>,
+    cache: Arc<ResultCache>,
+) {
+    use rustyline::error::ReadlineError;
     use std::io::{stdin, stdout, Write};
     let mut rl = rustyline::Editor::<()>::new();
     rl.load_history(".rivi_history");
     loop {
-        let mut s = rl.readline("rivi>> ").expect(
-            "Did not enter a correct string",
-        );
+        let mut s = match rl.readline("rivi>> ") {
+            Ok(s) => s,
+            // Ctrl-C at the prompt discards the line, Ctrl-D exits
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => panic!("Did not enter a correct string: {}", err),
+        };
         if let Some('\n') = s.chars().next_back() {
             s.pop();
         }
@@ -92,43 +140,242 @@ fn repl(datasource: &Vec<Batch>) {
         if s.chars().next_back() != Some(';') {
             s.push(';');
         }
-        rl.add_history_entry(s.as_ref());
-        match parser::parse_query(s.as_bytes()) {
-            Ok((remaining, query)) => {
-                println!("{:?}, {:?}\n", query, remaining);
-                let result = query.run_batches(datasource);
-                query_engine::print_query_result(&result);
-            }
-            err => {
-                println!("Failed to parse query! {:?}", err);
-                println!("Example for supported query:");
-                println!(
-                    "select url, count(1), app_name, sum(events) where and( >(timestamp, 1000), =(version, \"1.5.3\") )\n"
-                );
+        rl.add_history_entry(s.as_str());
+        cancelled.store(false, Ordering::SeqCst);
+        let options = ExecutionOptions {
+            threads: threads,
+            memory_limit: memory_limit,
+            group_memory_limit: group_memory_limit,
+            sample_seed: None,
+            cancel: Cancellation::new(cancelled.clone(), timeout),
+            cache: cache.clone(),
+        };
+        if s.starts_with("export ") {
+            export(catalog, &options, &s["export ".len()..s.len() - 1]);
+        } else if s.starts_with("explain ") {
+            if let Some(query) = parse_query(&s["explain ".len()..]) {
+                match query.explain(catalog, &options) {
+                    Ok(lines) => println!("{}\n", lines.join("\n")),
+                    Err(message) => println!("Query failed: {}\n", message),
+                }
+            }
+        } else if s.starts_with("load ") {
+            let args: Vec<&str> = s["load ".len()..s.len() - 1].split_whitespace().collect();
+            if args.len() == 2 {
+                match load_table(args[1], memory_limit) {
+                    Ok(table) => {
+                        catalog.add(args[0].to_string(), table);
+                        cache.retain(&catalog.batch_ids());
+                    }
+                    Err(message) => println!("{}\n", message),
+                }
+            } else {
+                println!("Usage: load <table> <file>\n");
+            }
+        } else if s.starts_with("append ") {
+            let args: Vec<&str> = s["append ".len()..s.len() - 1].split_whitespace().collect();
+            if args.len() != 2 {
+                println!("Usage: append <table> <file>\n");
+            } else if let Some(table) = catalog.get_mut(args[0]) {
+                if let Err(message) = load_into(table, args[1]) {
+                    println!("{}\n", message);
+                }
+            } else {
+                println!("Unknown table {}\n", args[0]);
+            }
+        } else if s == "cache;" || s == "cache clear;" {
+            if s == "cache clear;" {
+                cache.clear();
+            }
+            println!("Result cache: {}\n", cache.describe());
+        } else if s == "timeout;" || s.starts_with("timeout ") {
+            match s["timeout".len()..s.len() - 1].trim() {
+                "" => {}
+                "off" => timeout = None,
+                seconds => {
+                    match seconds.parse::<f64>() {
+                        Ok(seconds) if seconds > 0.0 => timeout = Some(seconds),
+                        _ => println!("Usage: timeout [<seconds>|off]"),
+                    }
+                }
+            }
+            match timeout {
+                Some(seconds) => println!("Queries time out after {} seconds.\n", seconds),
+                None => println!("Queries do not time out.\n"),
+            }
+        } else if s == "tables;" {
+            for &(ref name, ref table) in catalog.tables() {
+                println!("{} ({} rows): {}", name, table.rows(), table.column_names().join(", "));
+            }
+            println!();
+        } else if let Some(query) = parse_query(&s) {
+            match query.execute(catalog, &options) {
+                Ok(result) => query_engine::print_query_result(&result),
+                Err(message) => println!("Query failed: {}\n", message),
             }
         }
         rl.save_history(".rivi_history").unwrap();
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let data_iter = csv_loader::load_csv_file(&args[1]);
+fn parse_query(s: &str) -> Option<Query> {
+    match parser::parse_query(s.as_bytes()) {
+        Ok((remaining, query)) => {
+            println!("{:?}, {:?}\n", query, remaining);
+            match query.validate() {
+                Ok(()) => Some(query),
+                Err(message) => {
+                    println!("Invalid query: {}\n", message);
+                    None
+                }
+            }
+        }
+        err => {
+            println!("Failed to parse query! {:?}", err);
+            println!("Example for supported query:");
+            println!(
+                "select url, count(1), app_name, sum(events) where and( >(timestamp, 1000), =(version, \"1.5.3\") ) \
+                 group by url, app_name\n"
+            );
+            None
+        }
+    }
+}
+
+fn export(catalog: &Catalog, options: &ExecutionOptions, args: &str) {
+    let args = args.trim();
+    let (path, query) = match args.find(char::is_whitespace) {
+        Some(i) => (&args[..i], args[i..].trim()),
+        None => (args, ""),
+    };
+    let result = if query.is_empty() {
+        match catalog.default_table().and_then(|name| catalog.get(name)) {
+            Some(table) => arrow_io::write_table(Path::new(path), table),
+            None => return println!("No table loaded.\n"),
+        }
+    } else {
+        match parse_query(&format!("{};", query)).map(|query| query.execute(catalog, options)) {
+            Some(Ok(result)) => arrow_io::write_query_result(Path::new(path), &result),
+            Some(Err(message)) => return println!("Query failed: {}\n", message),
+            None => return,
+        }
+    };
+    match result {
+        Ok(()) => println!("Exported to {}.\n", path),
+        Err(err) => println!("Failed to export to {}: {}\n", path, err),
+    }
+}
+
+/// Loads an Arrow IPC or CSV file into batches, keeping at most `memory_limit` bytes of this table in RAM.
+fn load_table(filename: &str, memory_limit: Option<usize>) -> Result<BatchStore, String> {
+    let mut batches = BatchStore::new(memory_limit);
+    load_into(&mut batches, filename)?;
+    Ok(batches)
+}
+
+/// Appends the batches of an Arrow IPC or CSV file to a table. Batches read before an error remain appended.
+fn load_into(batches: &mut BatchStore, filename: &str) -> Result<(), String> {
     let columnarization_start_time = precise_time_s();
-    let batches: Vec<Batch> = data_iter
-        .chunks(LOAD_CHUNK_SIZE)
-        .into_iter()
-        .map(|chunk| columnarize(chunk.collect()))
-        .collect();
-    let bytes_in_ram: usize = batches
-        .iter()
-        .map(|batch| batch.cols.heap_size_of_children())
-        .sum();
+    let error = |err: &fmt::Display| format!("Failed to load {}: {}", filename, err);
+    if arrow_io::is_arrow_file(Path::new(filename)) {
+        let arrow_batches = arrow_io::read_file(Path::new(filename)).map_err(|err| error(&err))?;
+        for batch in arrow_batches {
+            batches.push(batch.map_err(|err| error(&err))?).map_err(|err| error(&err))?;
+        }
+    } else {
+        let data_iter = csv_loader::load_csv_file(filename).map_err(|err| error(&err))?;
+        for chunk in data_iter.chunks(LOAD_CHUNK_SIZE).into_iter() {
+            let records = chunk.collect::<Result<Vec<_>, _>>().map_err(|err| error(&err))?;
+            batches.push(columnarize(records)).map_err(|err| error(&err))?;
+        }
+    }
     println!(
-        "Loaded data into {:.2} MB in RAM in {} chunk(s) in {:.1} seconds.",
-        bytes_in_ram as f64 / 1024f64 / 1024f64,
+        "Loaded {} into {:.2} MB in RAM in {} chunk(s) ({} spilled to disk) in {:.1} seconds.",
+        filename,
+        batches.bytes_in_ram() as f64 / 1024f64 / 1024f64,
         batches.len(),
+        batches.len() - batches.batches_in_ram(),
         precise_time_s() - columnarization_start_time
     );
-    repl(&batches)
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut memory_limit = None;
+    let mut group_memory_limit = Some(DEFAULT_GROUP_MEMORY_LIMIT_MB * 1024 * 1024);
+    let mut timeout = None;
+    let mut cache_size = DEFAULT_CACHE_SIZE_MB * 1024 * 1024;
+    let mut cache_entries = DEFAULT_CACHE_ENTRIES;
+    let mut threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--memory-limit" => {
+                let mb: usize = args.next()
+                    .and_then(|mb| mb.parse().ok())
+                    .expect("--memory-limit expects a size in MB");
+                memory_limit = Some(mb * 1024 * 1024);
+            }
+            "--group-memory-limit" => {
+                let mb: usize = args.next()
+                    .and_then(|mb| mb.parse().ok())
+                    .expect("--group-memory-limit expects a size in MB");
+                group_memory_limit = Some(mb * 1024 * 1024);
+            }
+            "--cache-size" => {
+                let mb: usize = args.next()
+                    .and_then(|mb| mb.parse().ok())
+                    .expect("--cache-size expects a size in MB");
+                cache_size = mb * 1024 * 1024;
+            }
+            "--cache-entries" => {
+                cache_entries = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--cache-entries expects a number of entries");
+            }
+            "--timeout" => {
+                let seconds: f64 = args.next()
+                    .and_then(|seconds| seconds.parse().ok())
+                    .expect("--timeout expects a number of seconds");
+                timeout = Some(seconds);
+            }
+            "--threads" => {
+                threads = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--threads expects a number of threads");
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        panic!("Usage: rividb [--memory-limit <MB>] [--group-memory-limit <MB>] [--threads <n>] \
+             [--timeout <seconds>] [--cache-size <MB>] [--cache-entries <n>] [<table>=]<file>...");
+    }
+
+    // Tables are named after their file unless given as <table>=<file>, the first one is queried by default
+    let mut catalog = Catalog::new();
+    for file in files {
+        let (name, filename) = match file.find('=') {
+            Some(i) => (file[..i].to_string(), file[i + 1..].to_string()),
+            None => {
+                let stem = Path::new(&file).file_stem().map(|stem| stem.to_string_lossy().into_owned());
+                (stem.unwrap_or(file.clone()), file)
+            }
+        };
+        match load_table(&filename, memory_limit) {
+            Ok(table) => catalog.add(name, table),
+            Err(message) => {
+                println!("{}", message);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Ctrl-C cancels the running query instead of terminating the process and losing the loaded tables
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let handler_cancelled = cancelled.clone();
+    ctrlc::set_handler(move || handler_cancelled.store(true, Ordering::SeqCst)).expect("Failed to set Ctrl-C handler");
+    let cache = Arc::new(ResultCache::new(cache_size, cache_entries));
+    repl(&mut catalog, memory_limit, group_memory_limit, threads, cancelled, timeout, cache)
 }
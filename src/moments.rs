@@ -0,0 +1,253 @@
+use std::io::{Read, Result, Write};
+
+use spill::{read_f64, write_f64};
+
+/// Running count, mean and sum of squared deviations of a sample, updated with Welford's algorithm.
+/// Two `Moments` merge exactly using the pairwise formulas of Chan et al.
+#[derive(Debug, Clone, Copy)]
+pub struct Moments {
+    count: f64,
+    mean: f64,
+    m2: f64,
+}
+
+/// Moments of two variables plus the sum of products of their deviations, for covariance and correlation.
+#[derive(Debug, Clone, Copy)]
+pub struct CoMoments {
+    x: Moments,
+    y: Moments,
+    c2: f64,
+}
+
+impl Moments {
+    pub fn new() -> Moments {
+        Moments {
+            count: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        self.count += 1.0;
+        let delta = value - self.mean;
+        self.mean += delta / self.count;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn merge(&mut self, other: &Moments) {
+        if other.count == 0.0 {
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count / count;
+        self.m2 += other.m2 + delta * delta * self.count * other.count / count;
+        self.count = count;
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_f64(writer, self.count)?;
+        write_f64(writer, self.mean)?;
+        write_f64(writer, self.m2)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Moments> {
+        Ok(Moments {
+            count: read_f64(reader)?,
+            mean: read_f64(reader)?,
+            m2: read_f64(reader)?,
+        })
+    }
+
+    pub fn var_pop(&self) -> Option<f64> {
+        if self.count < 1.0 {
+            None
+        } else {
+            Some(self.m2 / self.count)
+        }
+    }
+
+    pub fn var_samp(&self) -> Option<f64> {
+        if self.count < 2.0 {
+            None
+        } else {
+            Some(self.m2 / (self.count - 1.0))
+        }
+    }
+}
+
+impl CoMoments {
+    pub fn new() -> CoMoments {
+        CoMoments {
+            x: Moments::new(),
+            y: Moments::new(),
+            c2: 0.0,
+        }
+    }
+
+    pub fn insert(&mut self, x: f64, y: f64) {
+        let delta_x = x - self.x.mean;
+        self.x.insert(x);
+        self.y.insert(y);
+        self.c2 += delta_x * (y - self.y.mean);
+    }
+
+    pub fn merge(&mut self, other: &CoMoments) {
+        if other.x.count == 0.0 {
+            return;
+        }
+        let count = self.x.count + other.x.count;
+        let delta_x = other.x.mean - self.x.mean;
+        let delta_y = other.y.mean - self.y.mean;
+        self.c2 += other.c2 + delta_x * delta_y * self.x.count * other.x.count / count;
+        self.x.merge(&other.x);
+        self.y.merge(&other.y);
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.x.write_to(writer)?;
+        self.y.write_to(writer)?;
+        write_f64(writer, self.c2)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<CoMoments> {
+        Ok(CoMoments {
+            x: Moments::read_from(reader)?,
+            y: Moments::read_from(reader)?,
+            c2: read_f64(reader)?,
+        })
+    }
+
+    pub fn covar_samp(&self) -> Option<f64> {
+        if self.x.count < 2.0 {
+            None
+        } else {
+            Some(self.c2 / (self.x.count - 1.0))
+        }
+    }
+
+    pub fn corr(&self) -> Option<f64> {
+        let denominator = (self.x.m2 * self.y.m2).sqrt();
+        if self.x.count < 2.0 || denominator == 0.0 {
+            None
+        } else {
+            Some(self.c2 / denominator)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(count: usize, offset: f64) -> Vec<f64> {
+        (0..count).map(|i| offset + ((i * 7919) % 1000) as f64 / 10.0).collect()
+    }
+
+    fn mean(values: &[f64]) -> f64 {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+
+    /// Sum of squared deviations from the mean, computed in two passes.
+    fn squared_deviations(values: &[f64]) -> f64 {
+        let mean = mean(values);
+        values.iter().map(|x| (x - mean) * (x - mean)).sum()
+    }
+
+    fn moments(values: &[f64]) -> Moments {
+        let mut moments = Moments::new();
+        for &value in values {
+            moments.insert(value);
+        }
+        moments
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= 1e-9 * expected.abs().max(1.0),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn variance_matches_two_pass_computation() {
+        // A large offset makes the naive sum of squares formula lose all precision
+        for &offset in &[0.0, -250.0, 1e9] {
+            let values = values(10_000, offset);
+            let moments = moments(&values);
+            assert_close(moments.var_pop().unwrap(), squared_deviations(&values) / 10_000.0);
+            assert_close(moments.var_samp().unwrap(), squared_deviations(&values) / 9_999.0);
+        }
+    }
+
+    #[test]
+    fn merged_moments_equal_moments_of_all_values() {
+        let values = values(10_000, 1e6);
+        let mut merged = Moments::new();
+        merged.merge(&Moments::new());
+        for chunk in values.chunks(999) {
+            merged.merge(&moments(chunk));
+        }
+        merged.merge(&Moments::new());
+        let all = moments(&values);
+        assert_close(merged.count, all.count);
+        assert_close(merged.mean, all.mean);
+        assert_close(merged.var_samp().unwrap(), squared_deviations(&values) / 9_999.0);
+    }
+
+    #[test]
+    fn too_few_values_have_no_variance() {
+        assert_eq!(Moments::new().var_pop(), None);
+        assert_eq!(moments(&[5.0]).var_pop(), Some(0.0));
+        assert_eq!(moments(&[5.0]).var_samp(), None);
+    }
+
+    #[test]
+    fn covariance_and_correlation_match_two_pass_computation() {
+        let xs = values(5_000, 1e6);
+        let ys: Vec<f64> = xs.iter().enumerate().map(|(i, x)| 3.0 * x + (i % 13) as f64).collect();
+        let (mean_x, mean_y) = (mean(&xs), mean(&ys));
+        let products: f64 = xs.iter().zip(&ys).map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+
+        let mut merged = CoMoments::new();
+        for (chunk_x, chunk_y) in xs.chunks(777).zip(ys.chunks(777)) {
+            let mut co_moments = CoMoments::new();
+            for (&x, &y) in chunk_x.iter().zip(chunk_y) {
+                co_moments.insert(x, y);
+            }
+            merged.merge(&co_moments);
+        }
+        assert_close(merged.covar_samp().unwrap(), products / 4_999.0);
+        assert_close(
+            merged.corr().unwrap(),
+            products / (squared_deviations(&xs) * squared_deviations(&ys)).sqrt(),
+        );
+    }
+
+    #[test]
+    fn correlation_of_constant_is_undefined() {
+        let mut co_moments = CoMoments::new();
+        for i in 0..10 {
+            co_moments.insert(i as f64, 1.0);
+        }
+        assert_eq!(co_moments.covar_samp(), Some(0.0));
+        assert_eq!(co_moments.corr(), None);
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let mut co_moments = CoMoments::new();
+        for (i, x) in values(100, 3.0).into_iter().enumerate() {
+            co_moments.insert(x, i as f64);
+        }
+        let mut bytes = Vec::new();
+        co_moments.write_to(&mut bytes).unwrap();
+        let read = CoMoments::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read.covar_samp(), co_moments.covar_samp());
+        assert_eq!(read.corr(), co_moments.corr());
+        assert_eq!(read.x.var_samp(), co_moments.x.var_samp());
+    }
+}
@@ -1,11 +1,16 @@
-use nom::{alphanumeric, digit, is_alphabetic, multispace};
-use std::rc::Rc;
+use nom::{alphanumeric, digit, is_alphabetic, is_digit, multispace};
+use std::iter;
+use std::sync::Arc;
 use std::str;
 use std::str::FromStr;
 
 use aggregator::Aggregator;
 use expression::*;
+use hyperloglog::DEFAULT_PRECISION;
+use join::{Join, JoinKind};
 use query_engine::*;
+use time_bucket::Interval;
+use window::{Frame, Window, WindowFunction};
 use value::*;
 
 pub fn test() {
@@ -15,44 +20,304 @@ pub fn test() {
     println!("{:?}", res.unwrap().1);
 }
 
-named!(pub parse_query<&[u8], Query>, alt_complete!(full_query | simple_query));
+named!(pub parse_query<&[u8], Query>,
+    do_parse!(
+        query: select_statement >>
+        unions: many0!(complete!(union_clause)) >>
+        order_by: opt!(complete!(order_by_clause)) >>
+        limit: opt!(complete!(limit_clause)) >>
+        opt!(complete!(multispace)) >>
+        opt!(complete!(char!(';'))) >>
+        (Query {
+            unions: unions,
+            order_by: order_by.unwrap_or(Vec::new()),
+            limit: limit,
+            ..query
+        })
+    )
+);
 
-named!(full_query<&[u8], Query>,
+named!(select_statement<&[u8], Query>,
     do_parse!(
         tag_no_case!("select") >>
         multispace >>
+        distinct: opt!(complete!(terminated!(tag_no_case!("distinct"), multispace))) >>
         select: select_clauses >>
+        from: opt!(complete!(from_clause)) >>
+        filter: opt!(complete!(where_clause)) >>
+        group_by: opt!(complete!(group_by_clause)) >>
+        having: opt!(complete!(having_clause)) >>
+        (construct_query(select, distinct.is_some(), from, filter, group_by, having))
+    )
+);
+
+named!(union_clause<&[u8], (bool, Query)>,
+    do_parse!(
         multispace >>
-        tag_no_case!("where") >>
+        tag_no_case!("union") >>
+        all: opt!(complete!(preceded!(multispace, tag_no_case!("all")))) >>
+        opt!(multispace) >>
+        query: alt!(subquery | select_statement) >>
+        ((all.is_some(), query))
+    )
+);
+
+// First table with the fraction of its rows that is sampled, followed by the joined tables
+type FromClause = (TableRef, Option<f64>, Vec<(Join, Option<Query>)>);
+
+named!(from_clause<&[u8], FromClause>,
+    do_parse!(
         multispace >>
-        filter: expr >>
+        tag_no_case!("from") >>
+        multispace >>
+        table: table_ref >>
+        sample: opt!(complete!(sample_clause)) >>
+        joins: many0!(complete!(join_clause)) >>
+        ((table, sample, joins))
+    )
+);
+
+named!(join_clause<&[u8], (Join, Option<Query>)>,
+    do_parse!(
+        multispace >>
+        kind: opt!(complete!(terminated!(join_kind, multispace))) >>
+        tag_no_case!("join") >>
+        multispace >>
+        table: table_ref >>
+        multispace >>
+        tag_no_case!("on") >>
+        on: expr >>
+        ((
+            Join {
+                kind: kind.unwrap_or(JoinKind::Inner),
+                table: table.0,
+                on: on,
+            },
+            table.1,
+        ))
+    )
+);
+
+// Table name, or derived table with an optional alias. Derived tables without an alias have an empty name until
+// `construct_query` gives them a unique default name.
+type TableRef = (String, Option<Query>);
+
+named!(table_ref<&[u8], TableRef>,
+    alt!(
+        do_parse!(
+            query: subquery >>
+            alias: opt!(complete!(table_alias)) >>
+            ((alias.unwrap_or("").to_string(), Some(query)))
+        ) |
+        map!(identifier, |name: &str| (name.to_string(), None))
+    )
+);
+
+named!(table_alias<&[u8], &str>,
+    do_parse!(
+        multispace >>
+        opt!(complete!(terminated!(tag_no_case!("as"), multispace))) >>
+        alias: verify!(identifier, |name: &str| !is_keyword(name)) >>
+        (alias)
+    )
+);
+
+named!(subquery<&[u8], Query>,
+    do_parse!(
+        char!('(') >>
+        opt!(multispace) >>
+        query: parse_query >>
         opt!(multispace) >>
-        char!(';') >>
-        (construct_query(select, filter))
+        char!(')') >>
+        (query)
     )
 );
 
-named!(simple_query<&[u8], Query>,
+named!(scalar_subquery<&[u8], Expr>, map!(subquery, |query| Expr::Subquery(Box::new(query))));
+
+named!(in_subquery<&[u8], Query>,
     do_parse!(
-        tag_no_case!("select") >>
         multispace >>
-        select: select_clauses >>
+        tag_no_case!("in") >>
         opt!(multispace) >>
-        opt!(char!(';')) >>
-        (construct_query(select, Expr::Const(ValueType::Bool(true))))
+        query: subquery >>
+        (query)
+    )
+);
+
+// Percentage of rows to sample, as a fraction
+named!(sample_clause<&[u8], f64>,
+    do_parse!(
+        multispace >>
+        alt!(tag_no_case!("tablesample") | tag_no_case!("sample")) >>
+        multispace >>
+        percent: fraction >>
+        opt!(multispace) >>
+        char!('%') >>
+        (percent / 100.0)
+    )
+);
+
+fn is_keyword(name: &str) -> bool {
+    ["where", "group", "having", "union", "order", "limit", "join", "inner", "left", "on", "sample", "tablesample"]
+        .iter()
+        .any(|keyword| name.eq_ignore_ascii_case(keyword))
+}
+
+named!(join_kind<&[u8], JoinKind>,
+    alt!(
+        map!(tag_no_case!("inner"), |_| JoinKind::Inner) |
+        map!(
+            pair!(tag_no_case!("left"), opt!(complete!(preceded!(multispace, tag_no_case!("outer"))))),
+            |_| JoinKind::Left
+        )
+    )
+);
+
+named!(where_clause<&[u8], Expr>,
+    do_parse!(
+        multispace >>
+        tag_no_case!("where") >>
+        multispace >>
+        filter: expr >>
+        (filter)
     )
 );
 
-fn construct_query(select_clauses: Vec<AggregateOrSelect>, filter: Expr) -> Query {
+named!(group_by_clause<&[u8], Vec<Expr>>,
+    do_parse!(
+        multispace >>
+        tag_no_case!("group") >>
+        multispace >>
+        tag_no_case!("by") >>
+        group_by: separated_nonempty_list!(tag!(","), expr) >>
+        (group_by)
+    )
+);
+
+named!(having_clause<&[u8], Expr>,
+    do_parse!(
+        multispace >>
+        tag_no_case!("having") >>
+        multispace >>
+        having: grouped_expr >>
+        (having)
+    )
+);
+
+named!(order_by_clause<&[u8], Vec<(Expr, bool)>>,
+    do_parse!(
+        multispace >>
+        tag_no_case!("order") >>
+        multispace >>
+        tag_no_case!("by") >>
+        order_by: separated_nonempty_list!(tag!(","), order_by_item) >>
+        (order_by)
+    )
+);
+
+named!(order_by_item<&[u8], (Expr, bool)>,
+    do_parse!(
+        e: grouped_expr >>
+        desc: opt!(complete!(ordering)) >>
+        ((e, desc.unwrap_or(false)))
+    )
+);
+
+named!(ordering<&[u8], bool>,
+    do_parse!(
+        multispace >>
+        desc: alt!(
+            map!(tag_no_case!("asc"), |_| false) |
+            map!(tag_no_case!("desc"), |_| true)
+        ) >>
+        (desc)
+    )
+);
+
+named!(limit_clause<&[u8], LimitClause>,
+    do_parse!(
+        multispace >>
+        tag_no_case!("limit") >>
+        multispace >>
+        limit: unsigned >>
+        offset: opt!(complete!(offset_clause)) >>
+        (LimitClause { limit: limit, offset: offset.unwrap_or(0) })
+    )
+);
+
+named!(offset_clause<&[u8], usize>,
+    do_parse!(
+        multispace >>
+        tag_no_case!("offset") >>
+        multispace >>
+        offset: unsigned >>
+        (offset)
+    )
+);
+
+/// Names a derived table without an alias subquery, subquery_2, subquery_3 and so on, skipping names in use.
+fn default_table_name(name: String, names: &mut Vec<String>) -> String {
+    if !name.is_empty() {
+        return name;
+    }
+    let name = (1..)
+        .map(|i| if i == 1 { "subquery".to_string() } else { format!("subquery_{}", i) })
+        .find(|name| !names.contains(name))
+        .unwrap();
+    names.push(name.clone());
+    name
+}
+
+fn construct_query(
+    select_clauses: Vec<AggregateOrSelect>,
+    distinct: bool,
+    from: Option<FromClause>,
+    filter: Option<Expr>,
+    group_by: Option<Vec<Expr>>,
+    having: Option<Expr>,
+) -> Query {
     let (select, aggregate) = partition(select_clauses);
+    let mut derived_tables = Vec::new();
+    let mut joins = Vec::new();
+    let mut sample = None;
+    let from = match from {
+        Some(((table, derived), table_sample, join_clauses)) => {
+            sample = table_sample;
+            let mut names: Vec<String> = iter::once(&table)
+                .chain(join_clauses.iter().map(|&(ref join, _)| &join.table))
+                .filter(|name| !name.is_empty())
+                .cloned()
+                .collect();
+            let table = default_table_name(table, &mut names);
+            derived_tables.extend(derived.map(|query| (table.clone(), query)));
+            for (mut join, derived) in join_clauses {
+                join.table = default_table_name(join.table, &mut names);
+                derived_tables.extend(derived.map(|query| (join.table.clone(), query)));
+                joins.push(join);
+            }
+            Some(table)
+        }
+        None => None,
+    };
     Query {
         select: select,
-        filter: filter,
+        distinct: distinct,
+        from: from,
+        joins: joins,
+        derived_tables: derived_tables,
+        sample: sample,
+        filter: filter.unwrap_or(Expr::Const(ValueType::Bool(true))),
         aggregate: aggregate,
+        group_by: group_by.unwrap_or(Vec::new()),
+        having: having,
+        unions: Vec::new(),
+        order_by: Vec::new(),
+        limit: None,
     }
 }
 
-fn partition(select_or_aggregates: Vec<AggregateOrSelect>) -> (Vec<Expr>, Vec<(Aggregator, Expr)>) {
+fn partition(select_or_aggregates: Vec<AggregateOrSelect>) -> (Vec<Expr>, Vec<(Aggregator, Vec<Expr>)>) {
     let (selects, aggregates): (Vec<AggregateOrSelect>, Vec<AggregateOrSelect>) =
         select_or_aggregates.into_iter().partition(|x| match x {
             &AggregateOrSelect::Select(_) => true,
@@ -80,25 +345,144 @@ fn partition(select_or_aggregates: Vec<AggregateOrSelect>) -> (Vec<Expr>, Vec<(A
 named!(select_clauses<&[u8], Vec<AggregateOrSelect>>,
     separated_list!(
         tag!(","),
-        alt_complete!(aggregate_clause | select_clause)
+        alt_complete!(window_clause | aggregate_clause | select_clause)
+    )
+);
+
+named!(window_clause<&[u8], AggregateOrSelect>, map!(window_expr, AggregateOrSelect::Select));
+
+named!(aggregate_clause<&[u8], AggregateOrSelect>, map!(aggregate, AggregateOrSelect::Aggregate));
+
+named!(aggregate_expr<&[u8], Expr>,
+    map!(aggregate, |(atype, args)| Expr::Aggregate(atype, args))
+);
+
+named!(aggregate<&[u8], (Aggregator, Vec<Expr>)>,
+    do_parse!(
+        opt!(multispace) >>
+        result: alt_complete!(approx_count_distinct | approx_top_k | percentile | bivariate_aggregate | simple_aggregate) >>
+        (result)
     )
 );
 
-named!(aggregate_clause<&[u8], AggregateOrSelect>,
+named!(approx_count_distinct<&[u8], (Aggregator, Vec<Expr>)>,
     do_parse!(
+        tag_no_case!("approx_count_distinct") >>
+        char!('(') >>
+        e: expr >>
         opt!(multispace) >>
+        precision: opt!(complete!(precision)) >>
+        char!(')') >>
+        ((Aggregator::ApproxCountDistinct(precision.unwrap_or(DEFAULT_PRECISION)), vec![e]))
+    )
+);
+
+named!(approx_top_k<&[u8], (Aggregator, Vec<Expr>)>,
+    do_parse!(
+        tag_no_case!("approx_top_k") >>
+        char!('(') >>
+        e: expr >>
+        opt!(multispace) >>
+        char!(',') >>
+        opt!(multispace) >>
+        k: unsigned >>
+        opt!(multispace) >>
+        char!(')') >>
+        ((Aggregator::ApproxTopK(k), vec![e]))
+    )
+);
+
+named!(precision<&[u8], usize>,
+    do_parse!(
+        char!(',') >>
+        opt!(multispace) >>
+        p: unsigned >>
+        opt!(multispace) >>
+        (p)
+    )
+);
+
+named!(percentile<&[u8], (Aggregator, Vec<Expr>)>,
+    do_parse!(
+        exact: alt!(
+            map!(tag_no_case!("percentile"), |_| false) |
+            map!(tag_no_case!("exact_percentile"), |_| true)
+        ) >>
+        char!('(') >>
+        e: expr >>
+        opt!(multispace) >>
+        char!(',') >>
+        opt!(multispace) >>
+        q: fraction >>
+        opt!(multispace) >>
+        char!(')') >>
+        ((if exact { Aggregator::ExactPercentile(q) } else { Aggregator::Percentile(q) }, vec![e]))
+    )
+);
+
+named!(fraction<&[u8], f64>,
+    map!(
+        alt!(complete!(float) | integer),
+        |value: ValueType| value.as_f64().unwrap()
+    )
+);
+
+named!(simple_aggregate<&[u8], (Aggregator, Vec<Expr>)>,
+    do_parse!(
         atype: aggregate_func >>
         char!('(') >>
         e: expr >>
         opt!(multispace) >>
         char!(')') >>
-        (AggregateOrSelect::Aggregate((atype, e)))
+        ((atype, vec![e]))
+    )
+);
+
+named!(bivariate_aggregate<&[u8], (Aggregator, Vec<Expr>)>,
+    do_parse!(
+        atype: alt!(covar | corr) >>
+        char!('(') >>
+        e1: expr >>
+        opt!(multispace) >>
+        char!(',') >>
+        e2: expr >>
+        opt!(multispace) >>
+        char!(')') >>
+        ((atype, vec![e1, e2]))
     )
 );
 
+named!(covar<&[u8], Aggregator>,
+    map!( tag_no_case!("covar"), |_| Aggregator::Covar )
+);
+
+named!(corr<&[u8], Aggregator>,
+    map!( tag_no_case!("corr"), |_| Aggregator::Corr )
+);
+
 named!(select_clause<&[u8], AggregateOrSelect>, map!(expr, AggregateOrSelect::Select));
 
-named!(aggregate_func<&[u8], Aggregator>, alt!(count | sum));
+named!(aggregate_func<&[u8], Aggregator>, alt!(count_distinct | count | sum | min | max | avg | median | var_pop | var_samp | stddev));
+
+named!(count_distinct<&[u8], Aggregator>,
+    map!( tag_no_case!("count_distinct"), |_| Aggregator::CountDistinct )
+);
+
+named!(var_pop<&[u8], Aggregator>,
+    map!( tag_no_case!("var_pop"), |_| Aggregator::VarPop )
+);
+
+named!(var_samp<&[u8], Aggregator>,
+    map!( tag_no_case!("var_samp"), |_| Aggregator::VarSamp )
+);
+
+named!(stddev<&[u8], Aggregator>,
+    map!( tag_no_case!("stddev"), |_| Aggregator::Stddev )
+);
+
+named!(median<&[u8], Aggregator>,
+    map!( tag_no_case!("median"), |_| Aggregator::Median )
+);
 
 named!(count<&[u8], Aggregator>,
     map!( tag_no_case!("count"), |_| Aggregator::Count )
@@ -108,11 +492,27 @@ named!(sum<&[u8], Aggregator>,
     map!( tag_no_case!("sum"), |_| Aggregator::Sum )
 );
 
+named!(min<&[u8], Aggregator>,
+    map!( tag_no_case!("min"), |_| Aggregator::Min )
+);
+
+named!(max<&[u8], Aggregator>,
+    map!( tag_no_case!("max"), |_| Aggregator::Max )
+);
+
+named!(avg<&[u8], Aggregator>,
+    map!( tag_no_case!("avg"), |_| Aggregator::Avg )
+);
+
 named!(expr<&[u8], Expr>,
     do_parse!(
         opt!(multispace) >>
-        result: alt!(function | colname | constant) >>
-        (result)
+        result: alt!(scalar_subquery | time_bucket | function | colname | constant) >>
+        subquery: opt!(complete!(in_subquery)) >>
+        (match subquery {
+            Some(query) => Expr::In(Box::new(result), Box::new(query)),
+            None => result,
+        })
     )
 );
 
@@ -130,9 +530,225 @@ named!(function<&[u8], Expr>,
     )
 );
 
+named!(time_bucket<&[u8], Expr>,
+    alt_complete!(
+        do_parse!(
+            tag_no_case!("time_bucket") >>
+            bucket: map_res!(time_bucket_args, to_time_bucket) >>
+            (bucket)
+        ) |
+        do_parse!(
+            tag_no_case!("date_trunc") >>
+            bucket: map_res!(date_trunc_args, to_time_bucket) >>
+            (bucket)
+        )
+    )
+);
+
+named!(time_bucket_args<&[u8], (ValueType, Expr, i64)>,
+    do_parse!(
+        char!('(') >>
+        opt!(multispace) >>
+        interval: string >>
+        opt!(multispace) >>
+        char!(',') >>
+        e: expr >>
+        opt!(multispace) >>
+        origin: opt!(complete!(origin)) >>
+        char!(')') >>
+        ((interval, e, origin.unwrap_or(0)))
+    )
+);
+
+named!(date_trunc_args<&[u8], (ValueType, Expr, i64)>,
+    do_parse!(
+        char!('(') >>
+        opt!(multispace) >>
+        unit: string >>
+        opt!(multispace) >>
+        char!(',') >>
+        e: expr >>
+        opt!(multispace) >>
+        char!(')') >>
+        ((unit, e, 0))
+    )
+);
+
+named!(origin<&[u8], i64>,
+    do_parse!(
+        char!(',') >>
+        opt!(multispace) >>
+        negative: opt!(char!('-')) >>
+        seconds: unsigned >>
+        opt!(multispace) >>
+        (if negative.is_some() { -(seconds as i64) } else { seconds as i64 })
+    )
+);
+
+fn to_time_bucket((interval, expr, origin): (ValueType, Expr, i64)) -> Result<Expr, String> {
+    match interval {
+        ValueType::Str(interval) => Ok(Expr::TimeBucket(Interval::parse(&interval, origin)?, Box::new(expr))),
+        _ => Err("Interval must be a string".to_string()),
+    }
+}
+
+named!(window_expr<&[u8], Expr>,
+    do_parse!(
+        opt!(multispace) >>
+        call: alt_complete!(
+            ranking_call |
+            offset_call |
+            window_aggregate_call |
+            map!(aggregate, |(atype, args)| (WindowFunction::Aggregate(atype), args))
+        ) >>
+        multispace >>
+        tag_no_case!("over") >>
+        opt!(multispace) >>
+        char!('(') >>
+        partition_by: opt!(complete!(partition_clause)) >>
+        order_by: opt!(complete!(window_order_clause)) >>
+        frame: opt!(complete!(frame_clause)) >>
+        opt!(multispace) >>
+        char!(')') >>
+        (Expr::Window(Box::new(Window {
+            function: call.0,
+            args: call.1,
+            partition_by: partition_by.unwrap_or(Vec::new()),
+            order_by: order_by.unwrap_or(Vec::new()),
+            frame: frame.unwrap_or(Frame::Default),
+        })))
+    )
+);
+
+named!(ranking_call<&[u8], (WindowFunction, Vec<Expr>)>,
+    do_parse!(
+        function: alt!(
+            map!(tag_no_case!("row_number"), |_| WindowFunction::RowNumber) |
+            map!(tag_no_case!("dense_rank"), |_| WindowFunction::DenseRank) |
+            map!(tag_no_case!("rank"), |_| WindowFunction::Rank)
+        ) >>
+        char!('(') >>
+        opt!(multispace) >>
+        char!(')') >>
+        ((function, Vec::new()))
+    )
+);
+
+named!(offset_call<&[u8], (WindowFunction, Vec<Expr>)>,
+    do_parse!(
+        lag: alt!(
+            map!(tag_no_case!("lag"), |_| true) |
+            map!(tag_no_case!("lead"), |_| false)
+        ) >>
+        char!('(') >>
+        e: grouped_expr >>
+        opt!(multispace) >>
+        offset: opt!(complete!(do_parse!(
+            char!(',') >>
+            opt!(multispace) >>
+            offset: unsigned >>
+            opt!(multispace) >>
+            (offset)
+        ))) >>
+        char!(')') >>
+        ((if lag { WindowFunction::Lag(offset.unwrap_or(1)) } else { WindowFunction::Lead(offset.unwrap_or(1)) }, vec![e]))
+    )
+);
+
+named!(window_aggregate_call<&[u8], (WindowFunction, Vec<Expr>)>,
+    do_parse!(
+        atype: aggregate_func >>
+        char!('(') >>
+        e: grouped_expr >>
+        opt!(multispace) >>
+        char!(')') >>
+        ((WindowFunction::Aggregate(atype), vec![e]))
+    )
+);
+
+named!(partition_clause<&[u8], Vec<Expr>>,
+    do_parse!(
+        opt!(multispace) >>
+        tag_no_case!("partition") >>
+        multispace >>
+        tag_no_case!("by") >>
+        partition_by: separated_nonempty_list!(tag!(","), expr) >>
+        (partition_by)
+    )
+);
+
+named!(window_order_clause<&[u8], Vec<(Expr, bool)>>,
+    do_parse!(
+        opt!(multispace) >>
+        tag_no_case!("order") >>
+        multispace >>
+        tag_no_case!("by") >>
+        order_by: separated_nonempty_list!(tag!(","), order_by_item) >>
+        (order_by)
+    )
+);
+
+named!(frame_clause<&[u8], Frame>,
+    do_parse!(
+        opt!(multispace) >>
+        tag_no_case!("rows") >>
+        multispace >>
+        preceding: alt_complete!(
+            do_parse!(
+                tag_no_case!("between") >>
+                multispace >>
+                preceding: frame_start >>
+                multispace >>
+                tag_no_case!("and") >>
+                multispace >>
+                tag_no_case!("current") >>
+                multispace >>
+                tag_no_case!("row") >>
+                (preceding)
+            ) |
+            frame_start
+        ) >>
+        (Frame::Rows(preceding))
+    )
+);
+
+named!(frame_start<&[u8], Option<usize>>,
+    do_parse!(
+        preceding: alt!(
+            map!(tag_no_case!("unbounded"), |_| None) |
+            map!(unsigned, Some)
+        ) >>
+        multispace >>
+        tag_no_case!("preceding") >>
+        (preceding)
+    )
+);
+
+named!(grouped_expr<&[u8], Expr>,
+    do_parse!(
+        opt!(multispace) >>
+        result: alt_complete!(scalar_subquery | window_expr | time_bucket | grouped_function | aggregate_expr | colname | constant) >>
+        (result)
+    )
+);
+
+named!(grouped_function<&[u8], Expr>,
+    do_parse!(
+        ft: function_name >>
+        char!('(') >>
+        e1: grouped_expr >>
+        opt!(multispace) >>
+        char!(',') >>
+        e2: grouped_expr >>
+        opt!(multispace) >>
+        char!(')') >>
+        (Expr::func(ft, e1, e2))
+    )
+);
+
 named!(constant<&[u8], Expr>,
     map!(
-        alt!(integer |  string),
+        alt!(complete!(float) | integer |  string),
         Expr::Const
     )
 );
@@ -150,24 +766,47 @@ named!(integer<&[u8], ValueType>,
     )
 );
 
+named!(float<&[u8], ValueType>,
+    map!(
+        map_res!(
+            map_res!(
+                recognize!(tuple!(digit, char!('.'), digit)),
+                str::from_utf8
+            ),
+            FromStr::from_str
+        ),
+        |x| ValueType::Float(OrderedFloat(x))
+    )
+);
+
+named!(unsigned<&[u8], usize>,
+    map_res!(
+        map_res!(
+            digit,
+            str::from_utf8
+        ),
+        FromStr::from_str
+    )
+);
+
 named!(string<&[u8], ValueType>,
     do_parse!(
         char!('"') >>
         s: is_not!("\"") >>
         char!('"') >>
-        (ValueType::Str(Rc::new(str::from_utf8(s).unwrap().to_string())))
+        (ValueType::Str(Arc::new(str::from_utf8(s).unwrap().to_string())))
     )
 );
 
 named!(colname<&[u8], Expr>,
     map!(
-        identifier,
-        |ident: &str| Expr::ColName(Rc::new(ident.to_string()))
+        qualified_identifier,
+        |ident: &str| Expr::ColName(Arc::new(ident.to_string()))
     )
 );
 
 named!(function_name<&[u8], FuncType>,
-    alt!( equals | and | greater | less )
+    alt!( equals | and | or | greater | less )
 );
 
 named!(equals<&[u8], FuncType>,
@@ -186,9 +825,21 @@ named!(and<&[u8], FuncType>,
     map!( tag_no_case!("and"), |_| FuncType::And)
 );
 
+named!(or<&[u8], FuncType>,
+    map!( tag_no_case!("or"), |_| FuncType::Or)
+);
+
 named!(identifier<&[u8], &str>,
     map_res!(
-        take_while1!(is_sql_identifier),
+        recognize!(pair!(take_while1!(is_sql_identifier), take_while!(is_sql_identifier_char))),
+        str::from_utf8
+    )
+);
+
+// Column names can be qualified by their table as in `events.url`
+named!(qualified_identifier<&[u8], &str>,
+    map_res!(
+        recognize!(pair!(identifier, opt!(complete!(pair!(char!('.'), identifier))))),
         str::from_utf8
     )
 );
@@ -197,7 +848,39 @@ fn is_sql_identifier(chr: u8) -> bool {
     is_alphabetic(chr) || chr == '_' as u8
 }
 
+fn is_sql_identifier_char(chr: u8) -> bool {
+    is_sql_identifier(chr) || is_digit(chr)
+}
+
 enum AggregateOrSelect {
-    Aggregate((Aggregator, Expr)),
+    Aggregate((Aggregator, Vec<Expr>)),
     Select(Expr),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Query {
+        parse_query(format!("{};", query).as_bytes()).unwrap().1
+    }
+
+    #[test]
+    fn parses_time_bucket_and_date_trunc() {
+        let query = parse("select time_bucket(\"15 minutes\", timestamp), date_trunc(\"month\", timestamp)");
+        assert_eq!(
+            query.select,
+            vec![
+                Expr::TimeBucket(Interval::parse("15 minutes", 0).unwrap(), Box::new(Expr::col("timestamp"))),
+                Expr::TimeBucket(Interval::parse("month", 0).unwrap(), Box::new(Expr::col("timestamp"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_columns_named_like_time_functions() {
+        let query = parse("select time_bucket_id, date_truncated from events group by time_bucket_id, date_truncated");
+        assert_eq!(query.select, vec![Expr::col("time_bucket_id"), Expr::col("date_truncated")]);
+        assert_eq!(query.group_by, vec![Expr::col("time_bucket_id"), Expr::col("date_truncated")]);
+    }
+}
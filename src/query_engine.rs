@@ -1,20 +1,52 @@
-use std::collections::{HashMap, HashSet};
+use heapsize::HeapSizeOf;
+use std::cmp;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::iter::Iterator;
 use std::ops::Add;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use time::precise_time_ns;
 
 use aggregator::*;
-use columns::{Batch, ColIter, Column};
+use batch_store::BatchStore;
+use cancellation::Cancellation;
+use catalog::Catalog;
+use columns::{rebuild_column, Batch, ColIter, ColumnType};
 use expression::*;
+use group_table::GroupTable;
+use hyperloglog::{MAX_PRECISION, MIN_PRECISION};
+use join::{Join, JoinPlan};
+use result_cache::{CacheKey, CachedRows, ResultCache};
 use util::fmt_table;
-use value::ValueType;
+use value::{OrderedFloat, ValueType};
+use window::{Window, WindowFunction};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Query {
     pub select: Vec<Expr>,
+    pub distinct: bool,
+    pub from: Option<String>,
+    pub joins: Vec<Join>,
+    /// Subqueries in the from clause, whose results are queried like tables of the given name.
+    pub derived_tables: Vec<(String, Query)>,
+    /// Fraction of the rows of the scanned table that are randomly sampled.
+    pub sample: Option<f64>,
     pub filter: Expr,
-    pub aggregate: Vec<(Aggregator, Expr)>,
+    pub aggregate: Vec<(Aggregator, Vec<Expr>)>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    /// Queries whose results are appended with `union all` (true) or `union`. Order by and limit apply to the
+    /// combined result.
+    pub unions: Vec<(bool, Query)>,
+    pub order_by: Vec<(Expr, bool)>,
+    pub limit: Option<LimitClause>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitClause {
+    pub limit: usize,
+    pub offset: usize,
 }
 
 pub struct QueryStats {
@@ -23,11 +55,89 @@ pub struct QueryStats {
 }
 
 pub struct QueryResult {
-    pub colnames: Vec<Rc<String>>,
+    pub colnames: Vec<Arc<String>>,
     pub rows: Vec<Vec<ValueType>>,
+    /// Confidence interval of each value of the rows that is estimated from a sample, empty if the query is not
+    /// sampled.
+    pub intervals: Vec<Vec<Option<ConfidenceInterval>>>,
     pub stats: QueryStats,
 }
 
+/// Bounds of the 95% confidence interval of an estimate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfidenceInterval {
+    pub low: f64,
+    pub high: f64,
+}
+
+/// Settings for running a query.
+pub struct ExecutionOptions {
+    /// Number of worker threads that scan batches.
+    pub threads: usize,
+    /// Memory that the batches of each table, including derived tables, may take before they are spilled to disk.
+    pub memory_limit: Option<usize>,
+    /// Memory that the groups of an aggregation may take before they are spilled to disk.
+    pub group_memory_limit: Option<usize>,
+    /// Seed of the random samples of sampled queries, which differ between runs without one.
+    pub sample_seed: Option<u64>,
+    pub cancel: Cancellation,
+    pub cache: Arc<ResultCache>,
+}
+
+/// Number of records between checks whether a query has been cancelled or timed out.
+const CANCELLATION_CHECK_INTERVAL: u64 = 4096;
+
+/// Quantile of the standard normal distribution for 95% confidence intervals.
+const CONFIDENCE_Z: f64 = 1.96;
+
+/// Produces the records a query is evaluated on, one at a time.
+pub trait RecordSource {
+    /// Overwrites `record` with the next record and returns false if there are none left.
+    fn next_record(&mut self, record: &mut Vec<ValueType>) -> bool;
+}
+
+impl<'a> RecordSource for Vec<ColIter<'a>> {
+    fn next_record(&mut self, record: &mut Vec<ValueType>) -> bool {
+        record.clear();
+        if self.is_empty() {
+            return false;
+        }
+        for col in self.iter_mut() {
+            match col.next() {
+                Some(item) => record.push(item),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Result of running a query on a single batch. Select queries produce rows, aggregation queries
+/// produce accumulators for each group that still have to be merged with those of other batches.
+struct PartialResult {
+    rows: Vec<Vec<ValueType>>,
+    groups: GroupTable,
+    stats: QueryStats,
+}
+
+impl QueryResult {
+    /// Converts the result rows into a batch with a column for each result column, in the same order.
+    pub fn into_batch(self) -> Batch {
+        let mut columns: Vec<Vec<ValueType>> = self.colnames.iter().map(|_| Vec::with_capacity(self.rows.len())).collect();
+        for row in self.rows {
+            for (column, value) in columns.iter_mut().zip(row) {
+                column.push(value);
+            }
+        }
+        let cols = self.colnames
+            .iter()
+            .zip(columns)
+            .map(|(name, values)| rebuild_column(name.to_string(), values))
+            .collect();
+        Batch { cols: cols }
+    }
+}
+
 impl Add for QueryStats {
     type Output = QueryStats;
 
@@ -40,172 +150,1423 @@ impl Add for QueryStats {
 }
 
 impl Query {
-    pub fn run(&self, source: &Batch) -> QueryResult {
-        let referenced_cols = self.find_referenced_cols();
-        let efficient_source: Vec<&Box<Column>> = source
-            .cols
+    /// Checks that every column used outside of aggregates in an aggregation query is grouped by,
+    /// and that distinct queries are only ordered by result columns.
+    pub fn validate(&self) -> Result<(), String> {
+        for query in self.subqueries() {
+            query.validate()?;
+        }
+        if let Some(fraction) = self.sample {
+            if fraction <= 0.0 || fraction > 1.0 {
+                return Err("Sample must be more than 0% and at most 100% of the rows".to_string());
+            }
+        }
+        for &(ref name, ref query) in &self.derived_tables {
+            let colnames = query.result_column_names();
+            if let Some(colname) = colnames.iter().find(|colname| colnames.iter().filter(|c| c == colname).count() > 1) {
+                return Err(format!("Derived table {} has more than one column named {}", name, colname));
+            }
+        }
+        if self.having.as_ref().map_or(false, |having| having.contains_window()) {
+            return Err("Window functions are not allowed in HAVING".to_string());
+        }
+        if !self.unions.is_empty() {
+            for &(ref expr, _) in &self.order_by {
+                match expr {
+                    &Expr::ColName(ref name) if self.result_column_names().contains(name) => {}
+                    _ => return Err("ORDER BY of a UNION can only refer to result columns by name".to_string()),
+                }
+            }
+        }
+        if self.distinct {
+            for &(ref expr, _) in &self.order_by {
+                if !self.is_result_column(expr) {
+                    return Err("For SELECT DISTINCT, ORDER BY expressions must appear in the select list".to_string());
+                }
+            }
+        }
+        for window in self.windows() {
+            if let WindowFunction::Aggregate(agg) = window.function {
+                if agg.is_top_k() {
+                    return Err("approx_top_k cannot be used as a window function".to_string());
+                }
+            }
+        }
+        if !self.is_aggregation() {
+            return Ok(());
+        }
+        let aggregates = self.all_aggregates();
+        for &(aggregator, _) in &aggregates {
+            match aggregator {
+                Aggregator::ApproxCountDistinct(precision) if precision < MIN_PRECISION || precision > MAX_PRECISION => {
+                    return Err(format!(
+                        "Precision of approx_count_distinct must be between {} and {}",
+                        MIN_PRECISION,
+                        MAX_PRECISION
+                    ));
+                }
+                Aggregator::ApproxTopK(0) => return Err("approx_top_k requires k > 0".to_string()),
+                Aggregator::Percentile(q) |
+                Aggregator::ExactPercentile(q) if q < 0.0 || q > 1.0 => {
+                    return Err(format!("Percentile {} must be between 0 and 1", q));
+                }
+                _ => {}
+            }
+        }
+        if aggregates.iter().filter(|&&(agg, _)| agg.is_top_k()).count() > 1 {
+            return Err("approx_top_k can only be used once per query".to_string());
+        }
+        let grouped_exprs = self.select
             .iter()
-            .filter(|col| referenced_cols.contains(&col.get_name().to_string()))
-            .collect();
-        let mut coliter = efficient_source.iter().map(|col| col.iter()).collect();
+            .chain(self.having.iter())
+            .chain(self.order_by.iter().map(|&(ref expr, _)| expr));
+        for expr in grouped_exprs {
+            if let Some(name) = self.find_ungrouped_colname(expr, &aggregates) {
+                return Err(format!(
+                    "Column {} must appear in the GROUP BY clause or be used in an aggregate function",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the query on the tables in `catalog`, failing if it is cancelled. Results are taken from the cache while
+    /// the batches of the tables they were computed from are unchanged.
+    pub fn execute(&self, catalog: &Catalog, options: &ExecutionOptions) -> Result<QueryResult, String> {
+        if !options.cache.is_enabled() || self.is_sampled() {
+            return self.execute_uncached(catalog, options, None);
+        }
+        let start_time_ns = precise_time_ns();
+        let normalized = Arc::new(format!("{:?}", self.normalized()));
+        let key = CacheKey::Result(normalized.clone(), self.batch_ids(catalog, None));
+        if let Some(cached) = options.cache.get(&key) {
+            return Ok(QueryResult {
+                colnames: self.result_column_names(),
+                rows: cached.rows,
+                intervals: Vec::new(),
+                stats: QueryStats {
+                    runtime_ns: precise_time_ns() - start_time_ns,
+                    rows_scanned: 0,
+                },
+            });
+        }
+        let result = self.execute_uncached(catalog, options, Some(normalized))?;
+        if options.cache.admits(result.rows.heap_size_of_children()) {
+            let rows = CachedRows {
+                rows: result.rows.clone(),
+                groups: Vec::new(),
+            };
+            options.cache.put(key, rows);
+        }
+        Ok(result)
+    }
+
+    /// Runs the query, caching the partial result of each batch under the `normalized` query unless it is None.
+    fn execute_uncached(
+        &self,
+        catalog: &Catalog,
+        options: &ExecutionOptions,
+        normalized: Option<Arc<String>>,
+    ) -> Result<QueryResult, String> {
+        if !self.unions.is_empty() {
+            return self.execute_union(catalog, options);
+        }
+        let start_time_ns = precise_time_ns();
+        let mut catalog = catalog.clone();
+        let mut rows_scanned = 0;
+        for &(ref name, ref query) in &self.derived_tables {
+            let result = query.execute(&catalog, options)?;
+            rows_scanned += result.stats.rows_scanned;
+            let mut table = BatchStore::new(options.memory_limit);
+            table.push(result.into_batch())?;
+            catalog.add(name.clone(), table);
+        }
+        let (query, subquery_rows) = self.materialize_subqueries(&catalog, options)?;
+        let (batches, plan) = JoinPlan::new(&query, &catalog, true, &options.cancel)?;
+        // Partial results of derived tables are not cached, since they are computed again for every query
+        let partial_key = normalized
+            .filter(|_| self.derived_tables.is_empty())
+            .map(|normalized| (normalized, self.batch_ids(&catalog, Some(plan.probe_table()))));
+        let mut result = query.run_batches(batches, &plan, options, partial_key)?;
+        result.stats.runtime_ns = precise_time_ns() - start_time_ns;
+        result.stats.rows_scanned += rows_scanned + subquery_rows;
+        Ok(result)
+    }
+
+    /// Describes how the query runs: the columns read, the batches that are skipped, the optimized filters and
+    /// joins, the strategy used to compute the result and estimated row counts. Derived tables and subqueries are
+    /// run, since the outer query is planned on their results.
+    pub fn explain(&self, catalog: &Catalog, options: &ExecutionOptions) -> Result<Vec<String>, String> {
+        let indent = |lines: Vec<String>| lines.into_iter().map(|line| format!("    {}", line));
+        let mut lines = Vec::new();
+        if !self.unions.is_empty() {
+            let first = Query {
+                unions: Vec::new(),
+                order_by: Vec::new(),
+                limit: None,
+                ..self.clone()
+            };
+            lines.push("Query 1:".to_string());
+            lines.extend(indent(first.explain(catalog, options)?));
+            for (i, &(all, ref query)) in self.unions.iter().enumerate() {
+                lines.push(format!("{} query {}:", if all { "Union all with" } else { "Union with" }, i + 2));
+                lines.extend(indent(query.explain(catalog, options)?));
+            }
+            lines.extend(self.describe_order_and_limit());
+            return Ok(lines);
+        }
+
+        let mut catalog = catalog.clone();
+        for &(ref name, ref query) in &self.derived_tables {
+            lines.push(format!("Derived table {}:", name));
+            lines.extend(indent(query.explain(&catalog, options)?));
+            let mut table = BatchStore::new(options.memory_limit);
+            table.push(query.execute(&catalog, options)?.into_batch())?;
+            catalog.add(name.clone(), table);
+        }
+        let mut scalars = Vec::new();
+        let mut sets = Vec::new();
+        for expr in self.exprs() {
+            expr.add_subqueries(&mut scalars, &mut sets);
+        }
+        for (query, kind) in scalars.iter().map(|q| (q, "constant")).chain(sets.iter().map(|q| (q, "hash set"))) {
+            lines.push(format!("Subquery, evaluated once into a {}:", kind));
+            lines.extend(indent(query.explain(&catalog, options)?));
+        }
+
+        let (query, _) = self.materialize_subqueries(&catalog, options)?;
+        let (batches, plan) = JoinPlan::new(&query, &catalog, false, &options.cancel)?;
+        lines.extend(plan.describe());
+        let mut skipped = Vec::new();
+        let mut rows = 0;
+        let mut estimated_rows = 0.0;
+        for i in 0..batches.len() {
+            let zone_map = batches.zone_map(i);
+            if plan.may_match(zone_map) {
+                rows += zone_map.rows();
+                estimated_rows += zone_map.rows() as f64 * plan.selectivity(zone_map);
+            } else {
+                skipped.push(i.to_string());
+            }
+        }
+        lines.push(format!(
+            "Scan {} of {} batch(es) with {} of {} rows, skipping {}",
+            batches.len() - skipped.len(),
+            batches.len(),
+            rows,
+            batches.rows(),
+            if skipped.is_empty() { "none".to_string() } else { format!("batch(es) {}", skipped.join(", ")) }
+        ));
+        lines.push(format!(
+            "Estimated rows passing the filter{}: {}",
+            if plan.has_joins() { format!(" on {}", plan.probe_table()) } else { String::new() },
+            estimated_rows.round()
+        ));
+        lines.extend(query.describe_strategy(options.threads.max(1).min(batches.len())));
+        Ok(lines)
+    }
 
-        let column_indices = create_colname_map(&efficient_source);
-        let compiled_selects = self.select
+    fn describe_strategy(&self, threads: usize) -> Vec<String> {
+        let exprs = |exprs: &Vec<Expr>| exprs.iter().map(|expr| expr.to_string()).collect::<Vec<_>>().join(", ");
+        let mut lines = Vec::new();
+        if self.is_aggregation() {
+            let aggregates = self.all_aggregates()
+                .into_iter()
+                .map(|(agg, args)| Expr::Aggregate(agg, args))
+                .collect();
+            if self.group_by.is_empty() {
+                lines.push(format!("Aggregate {} over all rows", exprs(&aggregates)));
+            } else {
+                lines.push(format!(
+                    "Hash aggregate {} grouped by {}",
+                    exprs(&aggregates),
+                    exprs(&self.group_by)
+                ));
+            }
+            lines.push(format!(
+                "Each batch is aggregated separately on {} thread(s) and the partial aggregates are merged",
+                threads
+            ));
+            if let Some(ref having) = self.having {
+                lines.push(format!("Having: {}", having));
+            }
+        } else if !self.windows().is_empty() {
+            lines.push(format!(
+                "Collect all filtered rows of {} on {} thread(s)",
+                self.window_input_columns().iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", "),
+                threads
+            ));
+        } else {
+            lines.push(format!("Select {} on {} thread(s)", exprs(&self.select), threads));
+        }
+        let windows = self.windows();
+        if !windows.is_empty() {
+            lines.push(format!("Compute {} window function(s) after merging", windows.len()));
+        }
+        if self.distinct {
+            lines.push("Remove duplicate rows".to_string());
+        }
+        if !self.is_aggregation() && windows.is_empty() {
+            match self.limit {
+                Some(limit) if !self.order_by.is_empty() => {
+                    lines.push(format!("Keep the first {} rows of each batch in a heap", limit.limit + limit.offset))
+                }
+                Some(limit) => lines.push(format!("Stop each batch after {} rows", limit.limit + limit.offset)),
+                None => {}
+            }
+        }
+        lines.extend(self.describe_order_and_limit());
+        lines
+    }
+
+    fn describe_order_and_limit(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if !self.order_by.is_empty() {
+            let order: Vec<String> = self.order_by
+                .iter()
+                .map(|&(ref expr, desc)| format!("{}{}", expr, if desc { " desc" } else { "" }))
+                .collect();
+            lines.push(format!("Sort by {}", order.join(", ")));
+        }
+        if let Some(LimitClause { limit, offset }) = self.limit {
+            lines.push(format!("Limit {} offset {}", limit, offset));
+        }
+        lines
+    }
+
+    /// Appends the results of all queries of a union, which must have the same number of columns with compatible
+    /// types. The result has the column names of the first query.
+    fn execute_union(&self, catalog: &Catalog, options: &ExecutionOptions) -> Result<QueryResult, String> {
+        let start_time_ns = precise_time_ns();
+        let first = Query {
+            unions: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            ..self.clone()
+        };
+        let first_types = first.result_column_types(catalog);
+        let mut result = first.execute(catalog, options)?;
+        let width = result.colnames.len();
+        for (i, &(all, ref query)) in self.unions.iter().enumerate() {
+            let other = query.execute(catalog, options)?;
+            if other.colnames.len() != width {
+                return Err(format!(
+                    "Query {} of UNION returns {} column(s), but the first query returns {}",
+                    i + 2,
+                    other.colnames.len(),
+                    width
+                ));
+            }
+            let types = column_types(&result.rows, width, &first_types);
+            let other_types = column_types(&other.rows, width, &query.result_column_types(catalog));
+            for (col, other_type) in other_types.into_iter().enumerate() {
+                match (types[col], other_type) {
+                    (Some(t1), Some(t2)) if t1 != t2 => {
+                        return Err(format!(
+                            "Column {} of UNION is of type {} in the queries before query {}, but of type {} in query {}",
+                            result.colnames[col],
+                            t1,
+                            i + 2,
+                            t2,
+                            i + 2
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            result.rows.extend(other.rows);
+            if !all {
+                result.rows = distinct_rows(result.rows, width);
+            }
+            result.stats = result.stats + other.stats;
+        }
+
+        let column_indices: HashMap<String, usize> = result.colnames
             .iter()
-            .map(|expr| expr.compile(&column_indices))
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), i))
             .collect();
-        let compiled_filter = self.filter.compile(&column_indices);
-        let compiled_aggregate = self.aggregate
+        let compiled_order: Vec<Expr> = self.order_by
             .iter()
-            .map(|&(agg, ref expr)| (agg, expr.compile(&column_indices)))
+            .map(|&(ref expr, _)| expr.compile(&column_indices))
             .collect();
+        let rows = result.rows
+            .into_iter()
+            .map(|mut row| {
+                let keys: Vec<ValueType> = compiled_order.iter().map(|expr| expr.eval(&row)).collect();
+                row.extend(keys);
+                row
+            })
+            .collect();
+        result.rows = sort_and_limit(rows, width, &self.descending(), self.limit);
+        // Rows are reordered, and estimates of sampled queries are not kept apart from the other rows
+        result.intervals = Vec::new();
+        result.stats.runtime_ns = precise_time_ns() - start_time_ns;
+        Ok(result)
+    }
 
+    /// Runs each subquery in an expression once and folds its result into the expression, as a constant for scalar
+    /// subqueries and as a hash set for `in`. Subqueries cannot refer to columns of the outer query.
+    fn materialize_subqueries(
+        &self,
+        catalog: &Catalog,
+        options: &ExecutionOptions,
+    ) -> Result<(Query, u64), String> {
+        let mut scalar_queries = Vec::new();
+        let mut set_queries = Vec::new();
+        for expr in self.exprs() {
+            expr.add_subqueries(&mut scalar_queries, &mut set_queries);
+        }
+        let mut rows_scanned = 0;
+        let mut scalars = Vec::new();
+        for query in scalar_queries {
+            let result = query.execute(catalog, options)?;
+            rows_scanned += result.stats.rows_scanned;
+            if result.colnames.len() != 1 || result.rows.len() > 1 {
+                return Err(format!(
+                    "Scalar subquery must return a single column and at most one row, got {} column(s) and {} row(s)",
+                    result.colnames.len(),
+                    result.rows.len()
+                ));
+            }
+            let value = result.rows.into_iter().next().map_or(ValueType::Null, |mut row| row.remove(0));
+            scalars.push((query, value));
+        }
+        let mut sets = Vec::new();
+        for query in set_queries {
+            let result = query.execute(catalog, options)?;
+            rows_scanned += result.stats.rows_scanned;
+            if result.colnames.len() != 1 {
+                return Err(format!("Subquery in IN must return a single column, got {}", result.colnames.len()));
+            }
+            let values = result.rows.into_iter().map(|mut row| row.remove(0)).collect();
+            sets.push((query, Arc::new(values)));
+        }
+        Ok((self.map_exprs(|expr| expr.replace_subqueries(&scalars, &sets)), rows_scanned))
+    }
+
+    /// Canonical form of the query, which is equal for queries that only differ in the order of `and` and `or`
+    /// operands or in constant expressions.
+    fn normalized(&self) -> Query {
+        Query {
+            derived_tables: self.derived_tables
+                .iter()
+                .map(|&(ref name, ref query)| (name.clone(), query.normalized()))
+                .collect(),
+            unions: self.unions.iter().map(|&(all, ref query)| (all, query.normalized())).collect(),
+            ..self.map_exprs(|expr| expr.normalize())
+        }
+    }
+
+    /// Ids of the batches of all tables that the query and its subqueries read, except for the `excluded` table of
+    /// the query itself.
+    fn batch_ids(&self, catalog: &Catalog, excluded: Option<&str>) -> Vec<usize> {
+        let from = self.from.as_ref().map(|name| name.as_str()).or(catalog.default_table());
+        let mut ids = Vec::new();
+        for name in from.into_iter().chain(self.joins.iter().map(|join| join.table.as_str())) {
+            if Some(name) == excluded || self.derived_tables.iter().any(|&(ref table, _)| table == name) {
+                continue;
+            }
+            if let Some(table) = catalog.get(name) {
+                ids.extend(table.batch_ids().iter().cloned());
+            }
+        }
+        for query in self.subqueries() {
+            ids.extend(query.batch_ids(catalog, None));
+        }
+        ids
+    }
+
+    /// Whether the query or any of its subqueries samples rows, so that its result differs between runs.
+    fn is_sampled(&self) -> bool {
+        self.sample.is_some() || self.subqueries().iter().any(|query| query.is_sampled())
+    }
+
+    fn subqueries(&self) -> Vec<Query> {
+        let mut queries: Vec<Query> = self.derived_tables.iter().map(|&(_, ref query)| query.clone()).collect();
+        queries.extend(self.unions.iter().map(|&(_, ref query)| query.clone()));
+        let mut sets = Vec::new();
+        for expr in self.exprs() {
+            expr.add_subqueries(&mut queries, &mut sets);
+        }
+        queries.extend(sets);
+        queries
+    }
+
+    fn exprs(&self) -> Vec<&Expr> {
+        let mut exprs: Vec<&Expr> = self.select.iter().collect();
+        exprs.extend(self.joins.iter().map(|join| &join.on));
+        exprs.push(&self.filter);
+        exprs.extend(self.aggregate.iter().flat_map(|&(_, ref args)| args.iter()));
+        exprs.extend(self.group_by.iter());
+        exprs.extend(self.having.iter());
+        exprs.extend(self.order_by.iter().map(|&(ref expr, _)| expr));
+        exprs
+    }
+
+    fn map_exprs<F: FnMut(&Expr) -> Expr>(&self, mut f: F) -> Query {
+        Query {
+            select: self.select.iter().map(|expr| f(expr)).collect(),
+            distinct: self.distinct,
+            from: self.from.clone(),
+            joins: self.joins
+                .iter()
+                .map(|join| {
+                    Join {
+                        kind: join.kind,
+                        table: join.table.clone(),
+                        on: f(&join.on),
+                    }
+                })
+                .collect(),
+            derived_tables: self.derived_tables.clone(),
+            sample: self.sample,
+            filter: f(&self.filter),
+            aggregate: self.aggregate
+                .iter()
+                .map(|&(agg, ref args)| (agg, args.iter().map(|arg| f(arg)).collect()))
+                .collect(),
+            group_by: self.group_by.iter().map(|expr| f(expr)).collect(),
+            having: self.having.as_ref().map(|expr| f(expr)),
+            unions: self.unions.clone(),
+            order_by: self.order_by.iter().map(|&(ref expr, desc)| (f(expr), desc)).collect(),
+            limit: self.limit,
+        }
+    }
+
+    /// Runs the query on a single batch without applying ordering, limits or hidden column removal
+    /// across the result, so that results from several batches can still be merged.
+    /// Groups of aggregations are spilled to disk once they take more than `group_memory_limit` bytes.
+    fn run_partial(
+        &self,
+        source: &Batch,
+        plan: &JoinPlan,
+        sample_seed: Option<u64>,
+        cancel: &Cancellation,
+        group_memory_limit: Option<usize>,
+    ) -> Result<PartialResult, String> {
         let start_time_ns = precise_time_ns();
-        let (result_rows, rows_touched) = if self.aggregate.len() == 0 {
-            run_select_query(&compiled_selects, &compiled_filter, &mut coliter)
+        cancel.check()?;
+        let (rows, groups, rows_touched) = if plan.has_joins() {
+            let mut scan = plan.scan(source, sample_seed, cancel);
+            let (rows, groups, _) = self.run_source(&mut scan, plan, cancel, group_memory_limit)?;
+            (rows, groups, scan.rows_scanned())
         } else {
-            run_aggregation_query(
+            let mut scan = plan.probe_scan(source, sample_seed, cancel);
+            let (rows, groups, _) = self.run_source(&mut scan, plan, cancel, group_memory_limit)?;
+            (rows, groups, scan.rows_scanned())
+        };
+        // Scans end early once the query is cancelled, leaving the partial result incomplete
+        cancel.check()?;
+
+        Ok(PartialResult {
+            rows: rows,
+            groups: groups,
+            stats: QueryStats {
+                runtime_ns: precise_time_ns() - start_time_ns,
+                rows_scanned: rows_touched,
+            },
+        })
+    }
+
+    fn run_source(
+        &self,
+        source: &mut RecordSource,
+        plan: &JoinPlan,
+        cancel: &Cancellation,
+        group_memory_limit: Option<usize>,
+    ) -> Result<(Vec<Vec<ValueType>>, GroupTable, u64), String> {
+        let aggregates = self.all_aggregates();
+        let column_indices = plan.column_indices();
+        let compiled_filter = plan.filter();
+        let compile = |expr: &Expr| expr.compile(column_indices).optimize(&|_| None);
+        let compiled_aggregate = aggregates
+            .iter()
+            .map(|&(agg, ref args)| (agg, args.iter().map(|arg| compile(arg)).collect()))
+            .collect();
+
+        let mut groups = GroupTable::new(aggregates.iter().map(|&(agg, _)| agg).collect(), group_memory_limit);
+        let (rows, rows_touched) = if !self.is_aggregation() && !self.windows().is_empty() {
+            // Windows need all filtered rows, so emit the referenced columns and compute them after merging
+            let compiled_columns = self.window_input_columns()
+                .into_iter()
+                .map(|name| Expr::ColName(name).compile(column_indices))
+                .collect();
+            run_select_query(
+                &compiled_columns,
+                compiled_filter,
+                &Vec::new(),
+                &Vec::new(),
+                false,
+                None,
+                source,
+                cancel,
+            )?
+        } else if !self.is_aggregation() {
+            let compiled_selects = self.select
+                .iter()
+                .map(|expr| compile(expr))
+                .collect();
+            let compiled_order = self.order_by
+                .iter()
+                .map(|&(ref expr, _)| compile(expr))
+                .collect();
+            run_select_query(
                 &compiled_selects,
-                &compiled_filter,
+                compiled_filter,
+                &compiled_order,
+                &self.descending(),
+                self.distinct,
+                self.limit.map(|l| l.limit + l.offset),
+                source,
+                cancel,
+            )?
+        } else {
+            let compiled_group_by = self.group_by
+                .iter()
+                .map(|expr| compile(expr))
+                .collect();
+            let rows_touched = run_aggregation_query(
+                &compiled_group_by,
+                compiled_filter,
                 &compiled_aggregate,
-                &mut coliter,
-            )
+                source,
+                &mut groups,
+                cancel,
+            )?;
+            (Vec::new(), rows_touched)
         };
+        Ok((rows, groups, rows_touched))
+    }
+
+    /// Scans batches on `threads` worker threads and merges their partial results. With a `partial_key` of the
+    /// normalized query and the batches of other tables it reads, partial results are taken from and added to the
+    /// cache.
+    fn run_batches(
+        &self,
+        batches: &BatchStore,
+        plan: &JoinPlan,
+        options: &ExecutionOptions,
+        partial_key: Option<(Arc<String>, Vec<usize>)>,
+    ) -> Result<QueryResult, String> {
+        let start_time_ns = precise_time_ns();
+        let workers = options.threads.max(1).min(batches.len());
+        // Each worker and the merged groups get an equal share of the memory limit
+        let group_memory_limit = options.group_memory_limit.map(|limit| limit / (workers + 1));
+        let aggregates = self.all_aggregates();
+        // Rows of each batch, which are concatenated in batch order however the workers finish
+        let mut batch_rows = vec![Vec::new(); batches.len()];
+        let mut groups = GroupTable::new(aggregates.iter().map(|&(agg, _)| agg).collect(), group_memory_limit);
+        let mut rows_scanned = 0;
+        let mut error = None;
+
+        let cache_key = |i: usize| {
+            partial_key.as_ref().map(|&(ref query, ref batch_ids)| {
+                CacheKey::Partial(query.clone(), batch_ids.clone(), batches.batch_ids()[i])
+            })
+        };
+        let mut cached = vec![false; batches.len()];
+        for i in 0..batches.len() {
+            if !plan.may_match(batches.zone_map(i)) {
+                continue;
+            }
+            if let Some(partial) = cache_key(i).and_then(|key| options.cache.get(&key)) {
+                batch_rows[i] = partial.rows;
+                groups.extend(partial.groups)?;
+                cached[i] = true;
+            }
+        }
 
-        QueryResult {
+        let next_batch = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let sender = sender.clone();
+                let next_batch = &next_batch;
+                let cached = &cached;
+                scope.spawn(move || loop {
+                    let i = next_batch.fetch_add(1, Ordering::SeqCst);
+                    if i >= batches.len() {
+                        break;
+                    }
+                    if cached[i] || !plan.may_match(batches.zone_map(i)) {
+                        continue;
+                    }
+                    let partial = batches
+                        .get(i)
+                        .and_then(|batch| {
+                            // Every batch is sampled with a seed of its own
+                            let seed = options
+                                .sample_seed
+                                .map(|seed| seed ^ (i as u64).wrapping_mul(0x9e3779b97f4a7c15));
+                            self.run_partial(&batch, plan, seed, &options.cancel, group_memory_limit)
+                        })
+                        .map(|partial| (i, partial));
+                    let failed = partial.is_err();
+                    sender.send(partial).unwrap();
+                    if failed {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+
+            for partial in receiver {
+                let (i, partial) = match partial {
+                    Ok(partial) => partial,
+                    Err(message) => {
+                        error = Some(message);
+                        continue;
+                    }
+                };
+                if let Some(key) = cache_key(i) {
+                    let bytes = partial.rows.heap_size_of_children() + partial.groups.bytes();
+                    // Groups are only copied for entries that fit into the cache
+                    if options.cache.admits(bytes) {
+                        if let Some(groups) = partial.groups.to_groups() {
+                            let rows = CachedRows {
+                                rows: partial.rows.clone(),
+                                groups: groups,
+                            };
+                            options.cache.put(key, rows);
+                        }
+                    }
+                }
+                if !self.is_aggregation() {
+                    batch_rows[i] = partial.rows;
+                } else {
+                    if let Err(message) = groups.merge(partial.groups) {
+                        error = Some(message);
+                    }
+                }
+                rows_scanned += partial.stats.rows_scanned;
+            }
+        });
+        if let Some(message) = error {
+            return Err(message);
+        }
+        let combined_rows = if self.is_aggregation() {
+            groups.into_rows()?
+        } else {
+            batch_rows.into_iter().flatten().collect()
+        };
+        let (rows, intervals) = self.finalize(combined_rows);
+        Ok(QueryResult {
             colnames: self.result_column_names(),
-            rows: result_rows,
+            rows: rows,
+            intervals: intervals,
             stats: QueryStats {
                 runtime_ns: precise_time_ns() - start_time_ns,
-                rows_scanned: rows_touched,
+                rows_scanned: rows_scanned,
             },
-        }
+        })
     }
 
-    pub fn run_batches(&self, batches: &Vec<Batch>) -> QueryResult {
-        let mut combined_rows = Vec::new();
-        let mut combined_stats = QueryStats {
-            runtime_ns: 0,
-            rows_scanned: 0,
+    /// Computes the output columns of merged groups, filters them on the having clause, computes window
+    /// functions, sorts, limits and strips the order keys from the result. Sampled aggregations also return the
+    /// confidence interval of each result value.
+    fn finalize(&self, rows: Vec<Vec<ValueType>>) -> (Vec<Vec<ValueType>>, Vec<Vec<Option<ConfidenceInterval>>>) {
+        let width = self.result_column_names().len();
+        let windows = self.windows();
+        // Output rows of sampled aggregations hold the bounds of each aggregate column before the order keys
+        let interval_columns = if self.is_aggregation() && self.sample.is_some() {
+            2 * (width - self.select.len())
+        } else {
+            0
+        };
+        let rows = if self.is_aggregation() {
+            let aggregates = self.all_aggregates();
+            let first_aggregate = self.group_by.len();
+            let rows = match self.sample {
+                Some(fraction) => rows.into_iter().map(|row| self.estimate(row, &aggregates, fraction)).collect(),
+                None => rows,
+            };
+            let compiled_having = self.having
+                .as_ref()
+                .map(|expr| self.compile_grouped(expr, &aggregates));
+            let rows = rows.into_iter()
+                .filter(|row| match compiled_having {
+                    Some(ref having) => having.eval(row) == ValueType::Bool(true),
+                    None => true,
+                })
+                .collect();
+            // Grouped rows hold the count and error of approx_top_k values after the aggregates, followed by the
+            // bounds of the aggregates in the select list
+            let first_top_k = first_aggregate + aggregates.len();
+            let first_bound = if aggregates.iter().any(|&(agg, _)| agg.is_top_k()) {
+                first_top_k + 2
+            } else {
+                first_top_k
+            };
+            let bound_columns = if self.sample.is_some() { 2 * self.aggregate.len() } else { 0 };
+            let (rows, compiled_selects, compiled_order) = self.compute_windows(
+                rows,
+                &windows,
+                first_bound + bound_columns,
+                |expr| self.compile_grouped(expr, &aggregates),
+            );
+            rows.into_iter()
+                .map(|row| {
+                    let mut output: Vec<ValueType> = compiled_selects.iter().map(|expr| expr.eval(&row)).collect();
+                    let mut bounds = Vec::with_capacity(interval_columns);
+                    for (i, &(agg, _)) in self.aggregate.iter().enumerate() {
+                        output.push(row[first_aggregate + i].clone());
+                        if interval_columns > 0 {
+                            bounds.extend(row[first_bound + 2 * i..first_bound + 2 * i + 2].iter().cloned());
+                        }
+                        if agg.is_top_k() {
+                            output.extend(row[first_top_k..first_top_k + 2].iter().cloned());
+                            if interval_columns > 0 {
+                                bounds.extend(vec![ValueType::Null; 4]);
+                            }
+                        }
+                    }
+                    output.extend(bounds);
+                    output.extend(compiled_order.iter().map(|expr| expr.eval(&row)));
+                    output
+                })
+                .collect()
+        } else if !windows.is_empty() {
+            let column_indices: HashMap<String, usize> = self.window_input_columns()
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.to_string(), i))
+                .collect();
+            let (rows, compiled_selects, compiled_order) = self.compute_windows(
+                rows,
+                &windows,
+                column_indices.len(),
+                |expr| expr.compile(&column_indices),
+            );
+            rows.into_iter()
+                .map(|row| compiled_selects.iter().chain(compiled_order.iter()).map(|expr| expr.eval(&row)).collect())
+                .collect()
+        } else {
+            rows
         };
 
-        for batch in batches {
-            let QueryResult { rows, stats, .. } = self.run(batch);
-            combined_rows.extend(rows); // TODO: This is not the right way to combine results!
-            combined_stats = combined_stats + stats;
+        let rows = if self.distinct { distinct_rows(rows, width) } else { rows };
+        let mut rows = sort_and_limit(rows, width + interval_columns, &self.descending(), self.limit);
+        if interval_columns == 0 {
+            return (rows, Vec::new());
         }
-        QueryResult {
-            colnames: self.result_column_names(),
-            rows: combined_rows,
-            stats: combined_stats,
+        let intervals = rows.iter_mut()
+            .map(|row| {
+                let bounds = row.split_off(width);
+                let estimates = bounds.chunks(2).map(|bounds| match (bounds[0].as_f64(), bounds[1].as_f64()) {
+                    (Some(low), Some(high)) => Some(ConfidenceInterval { low: low, high: high }),
+                    _ => None,
+                });
+                (0..self.select.len()).map(|_| None).chain(estimates).collect()
+            })
+            .collect();
+        (rows, intervals)
+    }
+
+    /// Scales the sums and counts of a group over sampled rows up to all rows and appends the bounds of the
+    /// confidence interval of each aggregate in the select list, which are null for aggregates that are not scaled.
+    /// Each row is sampled independently with probability `fraction`, so the variance of a scaled sum is
+    /// (1 - fraction) / fraction² times the sum of the squared values, which is the count for counts.
+    fn estimate(
+        &self,
+        mut row: Vec<ValueType>,
+        aggregates: &Vec<(Aggregator, Vec<Expr>)>,
+        fraction: f64,
+    ) -> Vec<ValueType> {
+        let first_aggregate = self.group_by.len();
+        let values = row[first_aggregate..].to_vec();
+        let mut bounds = Vec::with_capacity(2 * self.aggregate.len());
+        for (i, &(aggregator, ref args)) in aggregates.iter().enumerate() {
+            let squares = match aggregator {
+                Aggregator::Count => values[i].as_f64(),
+                Aggregator::Sum => aggregates
+                    .iter()
+                    .position(|&(agg, ref a)| agg == Aggregator::SumOfSquares && a == args)
+                    .and_then(|j| values[j].as_f64()),
+                _ => None,
+            };
+            let (low, high) = match (squares, scale(&values[i], fraction)) {
+                (Some(squares), Some(scaled)) => {
+                    row[first_aggregate + i] = scaled;
+                    let estimate = values[i].as_f64().unwrap() / fraction;
+                    let margin = CONFIDENCE_Z * ((1.0 - fraction) * squares).sqrt() / fraction;
+                    let low = if aggregator == Aggregator::Count { (estimate - margin).max(0.0) } else { estimate - margin };
+                    (ValueType::Float(OrderedFloat(low)), ValueType::Float(OrderedFloat(estimate + margin)))
+                }
+                _ => (ValueType::Null, ValueType::Null),
+            };
+            if i < self.aggregate.len() {
+                bounds.push(low);
+                bounds.push(high);
+            }
+        }
+        row.extend(bounds);
+        row
+    }
+
+    fn is_result_column(&self, expr: &Expr) -> bool {
+        match expr {
+            &Expr::Aggregate(agg, ref args) => self.aggregate.iter().any(|&(a, ref e)| a == agg && e == args),
+            &Expr::ColName(ref name) => {
+                self.select.contains(expr) || self.result_column_names().contains(name)
+            }
+            expr => self.select.contains(expr),
+        }
+    }
+
+    /// Appends the result of each window to `rows`, starting at column `first_column`, and compiles the select
+    /// and order by expressions so that they read window results from there.
+    fn compute_windows<F: Fn(&Expr) -> Expr>(
+        &self,
+        mut rows: Vec<Vec<ValueType>>,
+        windows: &Vec<Window>,
+        first_column: usize,
+        compile: F,
+    ) -> (Vec<Vec<ValueType>>, Vec<Expr>, Vec<Expr>) {
+        for window in windows {
+            let values = window.map_exprs(|expr| compile(expr)).compute(&rows);
+            for (row, value) in rows.iter_mut().zip(values) {
+                row.push(value);
+            }
+        }
+        let compile_with_windows =
+            |expr: &Expr| compile(&self.resolve_window_column(expr)).replace_windows(windows, first_column);
+        let compiled_selects = self.select.iter().map(&compile_with_windows).collect();
+        let compiled_order = self.order_by
+            .iter()
+            .map(|&(ref expr, _)| compile_with_windows(expr))
+            .collect();
+        (rows, compiled_selects, compiled_order)
+    }
+
+    /// Replaces a reference to a select column by name with the select expression if it contains windows.
+    fn resolve_window_column(&self, expr: &Expr) -> Expr {
+        if let &Expr::ColName(ref name) = expr {
+            let index = self.result_column_names().iter().position(|colname| colname == name);
+            if let Some(index) = index {
+                if index < self.select.len() && self.select[index].contains_window() {
+                    return self.select[index].clone();
+                }
+            }
+        }
+        expr.clone()
+    }
+
+    fn windows(&self) -> Vec<Window> {
+        let mut windows = Vec::new();
+        for expr in self.select.iter().chain(self.order_by.iter().map(|&(ref expr, _)| expr)) {
+            expr.add_windows(&mut windows);
+        }
+        windows
+    }
+
+    /// Columns scanned for select queries with window functions, in a fixed order for all batches.
+    fn window_input_columns(&self) -> Vec<Arc<String>> {
+        let mut columns: Vec<Arc<String>> = self.find_referenced_cols().into_iter().collect();
+        columns.sort();
+        columns
+    }
+
+    fn is_aggregation(&self) -> bool {
+        !self.aggregate.is_empty() || !self.group_by.is_empty() || self.having.is_some() ||
+            self.select
+                .iter()
+                .chain(self.order_by.iter().map(|&(ref expr, _)| expr))
+                .any(|expr| expr.contains_aggregate())
+    }
+
+    /// All aggregates computed for each group: those in the select list followed by any that are only referenced
+    /// in the having or order by clauses.
+    fn all_aggregates(&self) -> Vec<(Aggregator, Vec<Expr>)> {
+        let mut aggregates = self.aggregate.clone();
+        for expr in self.select.iter().chain(self.having.iter()) {
+            expr.add_aggregates(&mut aggregates);
+        }
+        for &(ref expr, _) in self.order_by.iter() {
+            expr.add_aggregates(&mut aggregates);
+        }
+        // Confidence intervals of sums over sampled rows are computed from the sums of squares
+        if self.sample.is_some() {
+            let sums: Vec<Vec<Expr>> = aggregates
+                .iter()
+                .filter(|&&(agg, _)| agg == Aggregator::Sum)
+                .map(|&(_, ref args)| args.clone())
+                .collect();
+            for args in sums {
+                let squares = (Aggregator::SumOfSquares, args);
+                if !aggregates.contains(&squares) {
+                    aggregates.push(squares);
+                }
+            }
+        }
+        aggregates
+    }
+
+    /// Compiles an expression that is evaluated on grouped rows, which hold the group by values followed by
+    /// the values of all aggregates. Columns can be referred to by group by expression or by result column name.
+    fn compile_grouped(&self, expr: &Expr, aggregates: &Vec<(Aggregator, Vec<Expr>)>) -> Expr {
+        if let Some(index) = self.group_by.iter().position(|group| group == expr) {
+            return Expr::ColIndex(index);
+        }
+        match expr {
+            &Expr::Aggregate(agg, ref args) => {
+                let index = aggregates
+                    .iter()
+                    .position(|&(a, ref e)| a == agg && e == args)
+                    .unwrap();
+                Expr::ColIndex(self.group_by.len() + index)
+            }
+            &Expr::Func(ftype, ref expr1, ref expr2) => {
+                Expr::func(
+                    ftype,
+                    self.compile_grouped(expr1, aggregates),
+                    self.compile_grouped(expr2, aggregates),
+                )
+            }
+            &Expr::TimeBucket(interval, ref inner) => {
+                Expr::TimeBucket(interval, Box::new(self.compile_grouped(inner, aggregates)))
+            }
+            &Expr::InSet(ref inner, ref values) => {
+                Expr::InSet(Box::new(self.compile_grouped(inner, aggregates)), values.clone())
+            }
+            &Expr::ColName(ref name) => {
+                if let Some(index) = self.top_k_column(name, aggregates) {
+                    return Expr::ColIndex(index);
+                }
+                let colnames = self.column_names(aggregates);
+                match colnames.iter().position(|colname| colname == name) {
+                    Some(index) if index >= self.select.len() => {
+                        Expr::ColIndex(self.group_by.len() + index - self.select.len())
+                    }
+                    Some(index) if self.select[index] != *expr => self.compile_grouped(&self.select[index], aggregates),
+                    _ => Expr::Const(ValueType::Null),
+                }
+            }
+            other => other.clone(),
         }
     }
 
-    fn find_referenced_cols(&self) -> HashSet<Rc<String>> {
+    fn find_ungrouped_colname(&self, expr: &Expr, aggregates: &Vec<(Aggregator, Vec<Expr>)>) -> Option<Arc<String>> {
+        if self.group_by.contains(expr) {
+            return None;
+        }
+        match expr {
+            &Expr::Window(ref window) => {
+                window
+                    .exprs()
+                    .into_iter()
+                    .filter_map(|expr| self.find_ungrouped_colname(expr, aggregates))
+                    .next()
+            }
+            &Expr::Func(_, ref expr1, ref expr2) => {
+                self.find_ungrouped_colname(expr1, aggregates)
+                    .or_else(|| self.find_ungrouped_colname(expr2, aggregates))
+            }
+            &Expr::TimeBucket(_, ref inner) |
+            &Expr::In(ref inner, _) |
+            &Expr::InSet(ref inner, _) => self.find_ungrouped_colname(inner, aggregates),
+            &Expr::ColName(ref name) => {
+                if self.top_k_column(name, aggregates).is_some() {
+                    return None;
+                }
+                let colnames = self.column_names(aggregates);
+                match colnames.iter().position(|colname| colname == name) {
+                    Some(index) if index >= self.select.len() => None,
+                    Some(index) if self.select[index] != *expr => {
+                        self.find_ungrouped_colname(&self.select[index], aggregates)
+                    }
+                    _ => Some(name.clone()),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn descending(&self) -> Vec<bool> {
+        self.order_by.iter().map(|&(_, desc)| desc).collect()
+    }
+
+    pub fn find_referenced_cols(&self) -> HashSet<Arc<String>> {
         let mut colnames = HashSet::new();
-        for expr in self.select.iter() {
+        for expr in self.select.iter().chain(self.group_by.iter()) {
             expr.add_colnames(&mut colnames);
         }
         self.filter.add_colnames(&mut colnames);
-        for &(_, ref expr) in self.aggregate.iter() {
-            expr.add_colnames(&mut colnames);
+        for &(_, ref args) in self.all_aggregates().iter() {
+            for arg in args {
+                arg.add_colnames(&mut colnames);
+            }
+        }
+        if !self.is_aggregation() {
+            for &(ref expr, _) in self.order_by.iter() {
+                expr.add_colnames(&mut colnames);
+            }
+        }
+        colnames
+    }
+
+    /// Names of the result columns, where an approx_top_k aggregate is followed by the count and error columns of
+    /// its values.
+    fn result_column_names(&self) -> Vec<Arc<String>> {
+        let mut colnames = Vec::new();
+        for (i, name) in self.column_names(&self.aggregate).into_iter().enumerate() {
+            let is_top_k = i >= self.select.len() && self.aggregate[i - self.select.len()].0.is_top_k();
+            colnames.push(name.clone());
+            if is_top_k {
+                colnames.push(Arc::new(format!("{}_count", name)));
+                colnames.push(Arc::new(format!("{}_error", name)));
+            }
         }
         colnames
     }
 
-    fn result_column_names(&self) -> Vec<Rc<String>> {
+    /// Types of the result columns as far as they follow from the column types of the tables read, so that the
+    /// queries of a union can be checked before they return any rows.
+    fn result_column_types(&self, catalog: &Catalog) -> Vec<Option<&'static str>> {
+        let from = self.from.clone().or_else(|| catalog.default_table().map(|table| table.to_string()));
+        let mut columns = HashMap::new();
+        for table in from.into_iter().chain(self.joins.iter().map(|join| join.table.clone())) {
+            let table_columns: Vec<(String, Option<&'static str>)> =
+                match self.derived_tables.iter().find(|&&(ref name, _)| *name == table) {
+                    Some(&(_, ref query)) => {
+                        let names = query.result_column_names().iter().map(|name| name.to_string()).collect::<Vec<_>>();
+                        names.into_iter().zip(query.result_column_types(catalog)).collect()
+                    }
+                    None => match catalog.get(&table) {
+                        Some(store) => {
+                            let types = store.column_types().iter().map(|&t| column_type_name(t));
+                            store.column_names().iter().cloned().zip(types).collect()
+                        }
+                        None => Vec::new(),
+                    },
+                };
+            // Unqualified names refer to the first table with the column, as when joining
+            for (name, column_type) in table_columns {
+                columns.insert(format!("{}.{}", table, name), column_type);
+                columns.entry(name).or_insert(column_type);
+            }
+        }
+        let mut types: Vec<Option<&'static str>> = self.select.iter().map(|expr| expr_type(expr, &columns)).collect();
+        for &(agg, ref args) in &self.aggregate {
+            types.push(aggregate_type(agg, args, &columns));
+            if agg.is_top_k() {
+                types.push(Some("number"));
+                types.push(Some("number"));
+            }
+        }
+        types
+    }
+
+    /// Index in grouped rows of the count or error column named `name` of the approx_top_k aggregate, which
+    /// follow the values of all aggregates.
+    fn top_k_column(&self, name: &str, aggregates: &Vec<(Aggregator, Vec<Expr>)>) -> Option<usize> {
+        let position = aggregates.iter().position(|&(agg, _)| agg.is_top_k())?;
+        let top_k_name = &self.column_names(aggregates)[self.select.len() + position];
+        let first_column = self.group_by.len() + aggregates.len();
+        if name == format!("{}_count", top_k_name) {
+            Some(first_column)
+        } else if name == format!("{}_error", top_k_name) {
+            Some(first_column + 1)
+        } else {
+            None
+        }
+    }
+
+    fn column_names(&self, aggregates: &Vec<(Aggregator, Vec<Expr>)>) -> Vec<Arc<String>> {
         let mut anon_columns = -1;
         let select_cols = self.select.iter().map(|expr| match expr {
             &Expr::ColName(ref name) => name.clone(),
             _ => {
                 anon_columns += 1;
-                Rc::new(format!("col_{}", anon_columns))
+                Arc::new(format!("col_{}", anon_columns))
             }
         });
         let mut anon_aggregates = -1;
-        let aggregate_cols = self.aggregate.iter().map(|&(agg, _)| {
+        let aggregate_cols = aggregates.iter().map(|&(agg, _)| {
             anon_aggregates += 1;
-            match agg {
-                Aggregator::Count => Rc::new(format!("count_{}", anon_aggregates)),
-                Aggregator::Sum => Rc::new(format!("sum_{}", anon_aggregates)),
-            }
+            Arc::new(format!("{}_{}", agg.name(), anon_aggregates))
         });
 
         select_cols.chain(aggregate_cols).collect()
     }
 }
 
-fn create_colname_map(source: &Vec<&Box<Column>>) -> HashMap<String, usize> {
-    let mut columns = HashMap::new();
-    for (i, col) in source.iter().enumerate() {
-        columns.insert(col.get_name().to_string(), i as usize);
-    }
-    columns
-}
-
-fn run_select_query(select: &Vec<Expr>, filter: &Expr, source: &mut Vec<ColIter>) -> (Vec<Vec<ValueType>>, u64) {
+fn run_select_query(
+    select: &Vec<Expr>,
+    filter: &Expr,
+    order: &Vec<Expr>,
+    descending: &Vec<bool>,
+    distinct: bool,
+    limit: Option<usize>,
+    source: &mut RecordSource,
+    cancel: &Cancellation,
+) -> Result<(Vec<Vec<ValueType>>, u64), String> {
     let mut result = Vec::new();
-    let mut record = Vec::with_capacity(source.len());
+    let mut seen = HashSet::new();
+    let mut top_k = if order.len() > 0 {
+        limit.map(|k| TopK::new(k, select.len(), descending))
+    } else {
+        None
+    };
+    let mut record = Vec::new();
     let mut rows_touched = 0;
-    if source.len() == 0 {
-        return (result, rows_touched);
+    if limit == Some(0) {
+        return Ok((result, rows_touched));
     }
-    loop {
-        record.clear();
-        for i in 0..source.len() {
-            match source[i].next() {
-                Some(item) => record.push(item),
-                None => return (result, rows_touched),
-            }
+    while source.next_record(&mut record) {
+        rows_touched += 1;
+        if rows_touched % CANCELLATION_CHECK_INTERVAL == 0 {
+            cancel.check()?;
         }
         if filter.eval(&record) == ValueType::Bool(true) {
-            result.push(select.iter().map(|expr| expr.eval(&record)).collect());
+            let row: Vec<ValueType> = select.iter().chain(order).map(|expr| expr.eval(&record)).collect();
+            if distinct && !seen.insert(row[..select.len()].to_vec()) {
+                continue;
+            }
+            match top_k {
+                Some(ref mut top_k) => top_k.push(row),
+                None => result.push(row),
+            }
+            if order.len() == 0 && Some(result.len()) == limit {
+                break;
+            }
         }
-        rows_touched += 1
+    }
+
+    if let Some(top_k) = top_k {
+        result = top_k.into_sorted_vec();
+    }
+    Ok((result, rows_touched))
+}
+
+/// Sorts rows on the order keys stored after the first `width` values, applies the limit and strips the keys.
+fn sort_and_limit(
+    rows: Vec<Vec<ValueType>>,
+    width: usize,
+    descending: &Vec<bool>,
+    limit: Option<LimitClause>,
+) -> Vec<Vec<ValueType>> {
+    let mut rows = match limit {
+        Some(LimitClause { limit, offset }) if descending.len() > 0 => {
+            let mut top_k = TopK::new(limit + offset, width, descending);
+            for row in rows {
+                top_k.push(row);
+            }
+            top_k.into_sorted_vec().into_iter().skip(offset).collect()
+        }
+        Some(LimitClause { limit, offset }) => rows.into_iter().skip(offset).take(limit).collect(),
+        None => {
+            let mut rows = rows;
+            rows.sort_by(|r1, r2| compare_keys(&r1[width..], &r2[width..], descending));
+            rows
+        }
+    };
+    for row in rows.iter_mut() {
+        row.truncate(width);
+    }
+    rows
+}
+
+/// Types of the result columns of a union query, taken from `known` where they follow from the tables read and
+/// otherwise from the values in `rows`.
+fn column_types(
+    rows: &Vec<Vec<ValueType>>,
+    width: usize,
+    known: &Vec<Option<&'static str>>,
+) -> Vec<Option<&'static str>> {
+    (0..width)
+        .map(|col| {
+            if let Some(known) = known[col] {
+                return Some(known);
+            }
+            let mut types = rows.iter().filter_map(|row| type_name(&row[col]));
+            let first = types.next();
+            if types.all(|t| Some(t) == first) { first } else { None }
+        })
+        .collect()
+}
+
+fn type_name(value: &ValueType) -> Option<&'static str> {
+    match value {
+        &ValueType::Null => None,
+        &ValueType::Bool(_) => Some("boolean"),
+        &ValueType::Timestamp(_) | &ValueType::Integer(_) | &ValueType::Float(_) => Some("number"),
+        &ValueType::Str(_) => Some("string"),
+        &ValueType::Set(_) => Some("set"),
+    }
+}
+
+fn column_type_name(column_type: ColumnType) -> Option<&'static str> {
+    match column_type {
+        ColumnType::Null | ColumnType::Mixed => None,
+        ColumnType::Bool => Some("boolean"),
+        ColumnType::Timestamp | ColumnType::Integer | ColumnType::Float => Some("number"),
+        ColumnType::Str => Some("string"),
+        ColumnType::Set => Some("set"),
+    }
+}
+
+/// Type of the values of `expr` given the types of the columns it reads, if it follows from them.
+fn expr_type(expr: &Expr, columns: &HashMap<String, Option<&'static str>>) -> Option<&'static str> {
+    match expr {
+        &Expr::ColName(ref name) => columns.get(name.as_ref()).cloned().unwrap_or(None),
+        &Expr::Const(ref value) => type_name(value),
+        &Expr::Func(ftype, _, _) => match ftype {
+            FuncType::Equals | FuncType::LT | FuncType::GT | FuncType::And | FuncType::Or => Some("boolean"),
+        },
+        &Expr::In(..) | &Expr::InSet(..) => Some("boolean"),
+        &Expr::TimeBucket(..) => Some("number"),
+        &Expr::Aggregate(agg, ref args) => aggregate_type(agg, args, columns),
+        &Expr::Window(ref window) => match window.function {
+            WindowFunction::Lag(_) |
+            WindowFunction::Lead(_) |
+            WindowFunction::Aggregate(Aggregator::Min) |
+            WindowFunction::Aggregate(Aggregator::Max) => window.args.first().and_then(|arg| expr_type(arg, columns)),
+            _ => Some("number"),
+        },
+        &Expr::ColIndex(_) | &Expr::Subquery(_) => None,
+    }
+}
+
+fn aggregate_type(agg: Aggregator, args: &Vec<Expr>, columns: &HashMap<String, Option<&'static str>>) -> Option<&'static str> {
+    match agg {
+        Aggregator::Min | Aggregator::Max | Aggregator::ApproxTopK(_) => expr_type(&args[0], columns),
+        _ => Some("number"),
+    }
+}
+
+/// Scales an integer or float sum or count over a sample of `fraction` of the rows up to all rows. Sums of timestamps
+/// are left as they are and get no confidence interval.
+fn scale(value: &ValueType, fraction: f64) -> Option<ValueType> {
+    match value {
+        &ValueType::Integer(i) => Some(ValueType::Integer((i as f64 / fraction).round() as i64)),
+        &ValueType::Float(OrderedFloat(x)) => Some(ValueType::Float(OrderedFloat(x / fraction))),
+        _ => None,
     }
 }
 
+/// Removes rows whose first `width` values equal those of an earlier row.
+fn distinct_rows(rows: Vec<Vec<ValueType>>, width: usize) -> Vec<Vec<ValueType>> {
+    let mut seen = HashSet::new();
+    rows.into_iter()
+        .filter(|row| seen.insert(row[..width].to_vec()))
+        .collect()
+}
+
 fn run_aggregation_query(
-    select: &Vec<Expr>,
+    group_by: &Vec<Expr>,
     filter: &Expr,
-    aggregation: &Vec<(Aggregator, Expr)>,
-    source: &mut Vec<ColIter>,
-) -> (Vec<Vec<ValueType>>, u64) {
-    let mut groups: HashMap<Vec<ValueType>, Vec<ValueType>> = HashMap::new();
-    let mut record = Vec::with_capacity(source.len());
+    aggregation: &Vec<(Aggregator, Vec<Expr>)>,
+    source: &mut RecordSource,
+    groups: &mut GroupTable,
+    cancel: &Cancellation,
+) -> Result<u64, String> {
+    let mut record = Vec::new();
     let mut rows_touched = 0;
-    'outer: loop {
-        record.clear();
-        for i in 0..source.len() {
-            match source[i].next() {
-                Some(item) => record.push(item),
-                None => break 'outer,
-            }
-        }
+    while source.next_record(&mut record) {
         if filter.eval(&record) == ValueType::Bool(true) {
-            let group: Vec<ValueType> = select.iter().map(|expr| expr.eval(&record)).collect();
-            let accumulator = groups.entry(group).or_insert(
-                aggregation
-                    .iter()
-                    .map(|x| x.0.zero())
-                    .collect(),
-            );
-            for (i, &(ref agg_func, ref expr)) in aggregation.iter().enumerate() {
-                accumulator[i] = agg_func.reduce(&accumulator[i], &expr.eval(&record));
+            let group: Vec<ValueType> = group_by.iter().map(|expr| expr.eval(&record)).collect();
+            groups.update(group, |accumulators| for (i, &(ref agg_func, ref args)) in aggregation.iter().enumerate() {
+                let value = args[0].eval(&record);
+                match args.get(1) {
+                    Some(arg) => agg_func.reduce_pair(&mut accumulators[i], &value, &arg.eval(&record)),
+                    None => agg_func.reduce(&mut accumulators[i], &value),
+                }
+            })?;
+        }
+        rows_touched += 1;
+        if rows_touched % CANCELLATION_CHECK_INTERVAL == 0 {
+            cancel.check()?;
+        }
+    }
+
+    Ok(rows_touched)
+}
+
+/// Keeps the `k` smallest rows according to the order keys stored from `key_start` onwards.
+struct TopK<'a> {
+    heap: BinaryHeap<KeyedRow<'a>>,
+    k: usize,
+    key_start: usize,
+    descending: &'a [bool],
+}
+
+impl<'a> TopK<'a> {
+    fn new(k: usize, key_start: usize, descending: &'a [bool]) -> TopK<'a> {
+        TopK {
+            heap: BinaryHeap::with_capacity(k + 1),
+            k: k,
+            key_start: key_start,
+            descending: descending,
+        }
+    }
+
+    fn push(&mut self, row: Vec<ValueType>) {
+        let row = KeyedRow {
+            row: row,
+            key_start: self.key_start,
+            descending: self.descending,
+        };
+        if self.heap.len() == self.k {
+            match self.heap.peek() {
+                Some(largest) if row < *largest => {}
+                _ => return,
             }
         }
-        if source.len() == 0 {
-            break;
+        self.heap.push(row);
+        if self.heap.len() > self.k {
+            self.heap.pop();
         }
-        rows_touched += 1
     }
 
-    let mut result: Vec<Vec<ValueType>> = Vec::new();
-    for (mut group, aggregate) in groups {
-        group.extend(aggregate);
-        result.push(group);
+    fn into_sorted_vec(self) -> Vec<Vec<ValueType>> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|keyed| keyed.row)
+            .collect()
+    }
+}
+
+struct KeyedRow<'a> {
+    row: Vec<ValueType>,
+    key_start: usize,
+    descending: &'a [bool],
+}
+
+impl<'a> Ord for KeyedRow<'a> {
+    fn cmp(&self, other: &KeyedRow<'a>) -> cmp::Ordering {
+        compare_keys(
+            &self.row[self.key_start..],
+            &other.row[other.key_start..],
+            self.descending,
+        )
+    }
+}
+
+impl<'a> PartialOrd for KeyedRow<'a> {
+    fn partial_cmp(&self, other: &KeyedRow<'a>) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> PartialEq for KeyedRow<'a> {
+    fn eq(&self, other: &KeyedRow<'a>) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl<'a> Eq for KeyedRow<'a> {}
+
+pub fn compare_keys(keys1: &[ValueType], keys2: &[ValueType], descending: &[bool]) -> cmp::Ordering {
+    for ((key1, key2), &desc) in keys1.iter().zip(keys2).zip(descending) {
+        let ordering = if desc { key2.cmp(key1) } else { key1.cmp(key2) };
+        if ordering != cmp::Ordering::Equal {
+            return ordering;
+        }
     }
-    (result, rows_touched)
+    cmp::Ordering::Equal
 }
 
 pub fn print_query_result(results: &QueryResult) {
@@ -225,13 +1586,27 @@ pub fn print_query_result(results: &QueryResult) {
         results.stats.rows_scanned,
         fmt_time
     );
-    println!("{}", format_results(&results.colnames, &results.rows));
+    println!("{}", format_results(&results.colnames, &results.rows, &results.intervals));
 }
 
-fn format_results(colnames: &Vec<Rc<String>>, rows: &Vec<Vec<ValueType>>) -> String {
-    let strcolnames: Vec<&str> = colnames.iter().map(|ref s| s.clone() as &str).collect();
+/// Formats the rows as a table. Estimated values are followed by their confidence interval in brackets.
+fn format_results(
+    colnames: &Vec<Arc<String>>,
+    rows: &Vec<Vec<ValueType>>,
+    intervals: &Vec<Vec<Option<ConfidenceInterval>>>,
+) -> String {
+    let strcolnames: Vec<&str> = colnames.iter().map(|s| s.as_str()).collect();
     let formattedrows: Vec<Vec<String>> = rows.iter()
-        .map(|row| row.iter().map(|val| format!("{}", val)).collect())
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(j, val)| match intervals.get(i).and_then(|row| row[j]) {
+                    Some(interval) => format!("{} [{:.1}, {:.1}]", val, interval.low, interval.high),
+                    None => format!("{}", val),
+                })
+                .collect()
+        })
         .collect();
     let strrows = formattedrows
         .iter()
@@ -241,54 +1616,395 @@ fn format_results(colnames: &Vec<Rc<String>>, rows: &Vec<Vec<ValueType>>) -> Str
     fmt_table(&strcolnames, &strrows)
 }
 
-pub fn test(source: &Batch) {
-    use self::Expr::*;
-    use self::FuncType::*;
-    use ValueType::*;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use columns::columnarize;
+    use parser::parse_query;
+    use std::sync::atomic::AtomicBool;
 
-    let query1 = Query {
-        select: vec![Expr::col("url")],
-        filter: Expr::func(
-            And,
-            Expr::func(LT, Expr::col("loadtime"), Const(Integer(1000))),
-            Expr::func(GT, Expr::col("timestamp"), Const(Timestamp(1000))),
-        ),
-        aggregate: vec![],
-    };
-    let query2 = Query {
-        select: vec![Expr::col("timestamp"), Expr::col("loadtime")],
-        filter: Expr::func(
-            Equals,
-            Expr::col("url"),
-            Const(Str(Rc::new("/".to_string()))),
-        ),
-        aggregate: vec![],
-    };
-    let count_query = Query {
-        select: vec![Expr::col("url")],
-        filter: Const(Bool(true)),
-        aggregate: vec![(Aggregator::Count, Const(Integer(0)))],
-    };
-    let sum_query = Query {
-        select: vec![Expr::col("url")],
-        filter: Const(Bool(true)),
-        aggregate: vec![(Aggregator::Sum, Expr::col("loadtime"))],
-    };
-    let missing_col_query = Query {
-        select: vec![],
-        filter: Const(Bool(true)),
-        aggregate: vec![(Aggregator::Sum, Expr::col("doesnotexist"))],
-    };
+    /// Table of 10000 rows with an integer column `x` in batches of 1000 rows, and the sum of `x`.
+    fn catalog() -> (Catalog, i64) {
+        let mut table = BatchStore::new(None);
+        let mut state = 3u64;
+        let mut total = 0;
+        for _ in 0..10 {
+            let records = (0..1000)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    let x = (state >> 33) as i64 % 100;
+                    total += x;
+                    vec![("x".to_string(), ValueType::Integer(x))]
+                })
+                .collect();
+            table.push(columnarize(records)).unwrap();
+        }
+        let mut catalog = Catalog::new();
+        catalog.add("t".to_string(), table);
+        (catalog, total)
+    }
+
+    /// Table `m` with an integer `id`, a column `x` of integers and floats and a timestamp column `ts`, in one
+    /// batch per row so that zone maps hold a single value.
+    fn mixed_catalog() -> Catalog {
+        let mut table = BatchStore::new(None);
+        let rows = vec![
+            (ValueType::Integer(5), 1000),
+            (ValueType::Float(OrderedFloat(5.0)), 999),
+            (ValueType::Integer(6), 1001),
+            (ValueType::Float(OrderedFloat(4.5)), 1000),
+        ];
+        for (id, (x, ts)) in rows.into_iter().enumerate() {
+            table
+                .push(columnarize(vec![vec![
+                    ("id".to_string(), ValueType::Integer(id as i64)),
+                    ("x".to_string(), x),
+                    ("ts".to_string(), ValueType::Timestamp(ts)),
+                ]]))
+                .unwrap();
+        }
+        let mut catalog = Catalog::new();
+        catalog.add("m".to_string(), table);
+        catalog
+    }
+
+    fn ids(catalog: &Catalog, filter: &str) -> Vec<i64> {
+        let mut ids: Vec<i64> = run(catalog, &format!("select id from m where {}", filter))
+            .rows
+            .iter()
+            .map(|row| match row[0] {
+                ValueType::Integer(id) => id,
+                ref other => panic!("Unexpected id {:?}", other),
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn run(catalog: &Catalog, query: &str) -> QueryResult {
+        execute(catalog, query, 2).unwrap()
+    }
+
+    fn execute(catalog: &Catalog, query: &str, threads: usize) -> Result<QueryResult, String> {
+        let query = parse_query(format!("{};", query).as_bytes()).unwrap().1;
+        query.validate().unwrap();
+        query.execute(catalog, &options(threads))
+    }
+
+    fn options(threads: usize) -> ExecutionOptions {
+        ExecutionOptions {
+            threads: threads,
+            memory_limit: None,
+            group_memory_limit: None,
+            sample_seed: None,
+            cancel: Cancellation::new(Arc::new(AtomicBool::new(false)), None),
+            cache: Arc::new(ResultCache::new(0, 0)),
+        }
+    }
 
-    let result1 = query1.run(source);
-    let result2 = query2.run(source);
-    let count_result = count_query.run(source);
-    let sum_result = sum_query.run(source);
-    let missing_col_query = missing_col_query.run(source);
-
-    print_query_result(&result1);
-    print_query_result(&result2);
-    print_query_result(&count_result);
-    print_query_result(&sum_result);
-    print_query_result(&missing_col_query);
+    #[test]
+    fn merges_groups_across_batches() {
+        let (catalog, total) = catalog();
+        let result = run(&catalog, "select x, count(0), sum(x) from t group by x");
+        // Every value of x occurs in all 10 batches but yields a single row
+        assert_eq!(result.rows.len(), 100);
+        let mut keys: Vec<ValueType> = result.rows.iter().map(|row| row[0].clone()).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 100);
+        let mut count = 0;
+        let mut sum = 0;
+        for row in &result.rows {
+            match (&row[0], &row[1], &row[2]) {
+                (&ValueType::Integer(x), &ValueType::Integer(n), &ValueType::Integer(s)) => {
+                    assert_eq!(s, x * n);
+                    count += n;
+                    sum += s;
+                }
+                _ => panic!("Unexpected row {:?}", row),
+            }
+        }
+        assert_eq!(count, 10000);
+        assert_eq!(sum, total);
+    }
+
+    #[test]
+    fn selects_rows_in_batch_order_on_any_number_of_threads() {
+        let (catalog, _) = catalog();
+        let table = catalog.get("t").unwrap();
+        let stored: Vec<Vec<ValueType>> = (0..table.len())
+            .flat_map(|i| table.get(i).unwrap().cols[0].iter().map(|x| vec![x]).collect::<Vec<_>>())
+            .collect();
+        let sequential = execute(&catalog, "select x from t", 1).unwrap().rows;
+        assert_eq!(sequential, stored);
+        for &threads in &[2, 4, 16] {
+            assert_eq!(execute(&catalog, "select x from t", threads).unwrap().rows, sequential);
+        }
+        let sums: Vec<_> = (1..5)
+            .map(|threads| execute(&catalog, "select sum(x), count(0) from t", threads).unwrap().rows)
+            .collect();
+        assert!(sums.iter().all(|rows| *rows == sums[0]));
+    }
+
+    #[test]
+    fn orders_and_limits_rows_and_groups() {
+        let (catalog, _) = catalog();
+        let mut all = run(&catalog, "select x from t").rows;
+        all.sort_by(|a, b| b.cmp(a));
+        assert_eq!(run(&catalog, "select x from t order by x desc limit 5 offset 1995").rows, &all[1995..2000]);
+        assert_eq!(run(&catalog, "select x from t order by x limit 10 offset 9995").rows, &all[..5]);
+
+        let mut groups = run(&catalog, "select x, count(0) from t group by x").rows;
+        groups.sort_by(|a, b| b[1].cmp(&a[1]).then(a[0].cmp(&b[0])));
+        let top = run(&catalog, "select x, count(0) from t group by x order by count(0) desc, x limit 3 offset 2").rows;
+        assert_eq!(top, &groups[2..5]);
+    }
+
+    #[test]
+    fn filters_groups_with_having() {
+        let (catalog, _) = catalog();
+        let groups = run(&catalog, "select x, count(0) from t group by x").rows;
+        let mut expected: Vec<_> = groups.into_iter().filter(|row| row[1] > ValueType::Integer(100)).collect();
+        expected.sort();
+        assert!(!expected.is_empty() && expected.len() < 100);
+        let mut having = run(&catalog, "select x, count(0) from t group by x having >(count(0), 100)").rows;
+        having.sort();
+        assert_eq!(having, expected);
+        // The having clause may use aggregates that are not selected
+        let mut x_only = run(&catalog, "select x from t group by x having >(count(0), 100)").rows;
+        x_only.sort();
+        assert_eq!(x_only, expected.iter().map(|row| vec![row[0].clone()]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn compares_equal_numbers_of_different_types_as_equal() {
+        let catalog = mixed_catalog();
+        assert_eq!(ids(&catalog, "<(x, 5.0)"), vec![3]);
+        assert_eq!(ids(&catalog, "<(x, 5)"), vec![3]);
+        assert_eq!(ids(&catalog, ">(x, 5)"), vec![2]);
+        assert_eq!(ids(&catalog, ">(x, 5.0)"), vec![2]);
+        assert_eq!(ids(&catalog, ">(5.0, x)"), vec![3]);
+        assert_eq!(ids(&catalog, ">(ts, 1000)"), vec![2]);
+        assert_eq!(ids(&catalog, "<(ts, 1000)"), vec![1]);
+        assert_eq!(ids(&catalog, ">(ts, 999.5)"), vec![0, 2, 3]);
+        assert_eq!(ids(&catalog, "<(1000, ts)"), vec![2]);
+    }
+
+    #[test]
+    fn sums_timestamps() {
+        let catalog = mixed_catalog();
+        let result = run(&catalog, "select sum(ts), sum(time_bucket(\"1 minute\", ts)), count(0) from m");
+        assert_eq!(
+            result.rows,
+            vec![vec![ValueType::Timestamp(4000), ValueType::Timestamp(3840), ValueType::Integer(4)]]
+        );
+        let mut grouped = run(&catalog, "select x, sum(ts) from m group by x").rows;
+        grouped.sort();
+        assert_eq!(
+            grouped,
+            vec![
+                vec![ValueType::Float(OrderedFloat(4.5)), ValueType::Timestamp(1000)],
+                vec![ValueType::Integer(5), ValueType::Timestamp(1000)],
+                vec![ValueType::Float(OrderedFloat(5.0)), ValueType::Timestamp(999)],
+                vec![ValueType::Integer(6), ValueType::Timestamp(1001)],
+            ]
+        );
+    }
+
+    #[test]
+    fn deduplicates_rows_across_batches() {
+        let (catalog, _) = catalog();
+        let distinct = run(&catalog, "select distinct x from t").rows;
+        assert_eq!(distinct.len(), 100);
+        let mut sorted = distinct.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 100);
+        // Rows keep the order of their first occurrence
+        let first = run(&catalog, "select x from t limit 1").rows;
+        assert_eq!(distinct[0], first[0]);
+        let limited = run(&catalog, "select distinct x from t order by x desc limit 3").rows;
+        let expected: Vec<_> = (97..100).rev().map(|x| vec![ValueType::Integer(x)]).collect();
+        assert_eq!(limited, expected);
+    }
+
+    #[test]
+    fn computes_window_functions_over_batches() {
+        let catalog = mixed_catalog();
+        let result = run(
+            &catalog,
+            "select id, row_number() over (order by id), rank() over (order by ts), lag(id) over (order by id), \
+             sum(id) over (order by id), sum(id) over (partition by ts), \
+             avg(id) over (order by id rows 1 preceding) from m order by id",
+        );
+        let int = |i: i64| ValueType::Integer(i);
+        let float = |x: f64| ValueType::Float(OrderedFloat(x));
+        assert_eq!(
+            result.rows,
+            vec![
+                vec![int(0), int(1), int(2), ValueType::Null, int(0), int(3), float(0.0)],
+                vec![int(1), int(2), int(1), int(0), int(1), int(1), float(0.5)],
+                vec![int(2), int(3), int(4), int(1), int(3), int(2), float(1.5)],
+                vec![int(3), int(4), int(2), int(2), int(6), int(3), float(2.5)],
+            ]
+        );
+        // Windows over groups run after the aggregation, and aggregates follow the other columns
+        let grouped = run(
+            &catalog,
+            "select ts, sum(count(0)) over (order by ts), count(0) from m group by ts order by ts",
+        );
+        let ts = |t: u64| ValueType::Timestamp(t);
+        assert_eq!(
+            grouped.rows,
+            vec![
+                vec![ts(999), int(1), int(1)],
+                vec![ts(1000), int(3), int(2)],
+                vec![ts(1001), int(4), int(1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_subqueries_in_where_and_from() {
+        let catalog = mixed_catalog();
+        let in_set = run(&catalog, "select id from m where id in (select id from m where >(x, 4.5)) order by id");
+        let int = |i: i64| vec![ValueType::Integer(i)];
+        assert_eq!(in_set.rows, vec![int(0), int(1), int(2)]);
+        let scalar = run(&catalog, "select id from m where >(ts, (select min(ts) from m)) order by id");
+        assert_eq!(scalar.rows, vec![int(0), int(2), int(3)]);
+        let empty = run(&catalog, "select id from m where =(ts, (select ts from m where >(id, 10)))");
+        assert!(empty.rows.is_empty());
+
+        // Derived table columns are named like the result columns of the subquery
+        let derived = run(
+            &catalog,
+            "select ts, count_0 from (select ts, count(0) from m group by ts) d where >(count_0, 1)",
+        );
+        assert_eq!(derived.rows, vec![vec![ValueType::Timestamp(1000), ValueType::Integer(2)]]);
+        let joined = run(
+            &catalog,
+            "select id, count_0 from m join (select ts, count(0) from m group by ts) d on =(m.ts, d.ts) order by id",
+        );
+        let counts: Vec<_> = joined.rows.iter().map(|row| row[1].clone()).collect();
+        assert_eq!(counts, vec![2, 1, 1, 2].into_iter().map(ValueType::Integer).collect::<Vec<_>>());
+        let reordered = run(&catalog, "select x, id from (select id, x from m) d where <(id, 2) order by id");
+        assert_eq!(
+            reordered.rows,
+            vec![
+                vec![ValueType::Integer(5), ValueType::Integer(0)],
+                vec![ValueType::Float(OrderedFloat(5.0)), ValueType::Integer(1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn unions_queries_of_the_same_types() {
+        let catalog = mixed_catalog();
+        let int = |i: i64| vec![ValueType::Integer(i)];
+        let all = run(&catalog, "select id from m where <(id, 2) union all select id from m where <(id, 3) order by id");
+        assert_eq!(all.rows, vec![int(0), int(0), int(1), int(1), int(2)]);
+        let distinct = run(&catalog, "select id from m where <(id, 2) union select id from m where <(id, 3) order by id");
+        assert_eq!(distinct.rows, vec![int(0), int(1), int(2)]);
+        // Integers, floats and timestamps are all numbers
+        assert_eq!(run(&catalog, "select id from m union all select x from m union all select ts from m").rows.len(), 12);
+        // Comparisons are booleans even if they select no rows
+        let err = match execute(&catalog, "select id from m union select <(id, 1) from m where >(id, 10)", 2) {
+            Err(err) => err,
+            Ok(_) => panic!("Union of a number and a boolean column succeeded"),
+        };
+        assert!(err.contains("of type number") && err.contains("of type boolean"), "{}", err);
+    }
+
+    #[test]
+    fn explains_pruning_and_strategy() {
+        let catalog = mixed_catalog();
+        let explain = |query: &str| {
+            let query = parse_query(format!("{};", query).as_bytes()).unwrap().1;
+            query.explain(&catalog, &options(2)).unwrap()
+        };
+        let lines = explain("select id from m where >(ts, 1000)");
+        assert!(lines.contains(&"Zone map conditions: ts > 1000".to_string()), "{:?}", lines);
+        assert!(
+            lines.contains(&"Scan 1 of 4 batch(es) with 1 of 4 rows, skipping batch(es) 0, 1, 3".to_string()),
+            "{:?}",
+            lines
+        );
+        let lines = explain("select ts, count(0) from (select ts from m) d group by ts");
+        assert_eq!(lines[0], "Derived table d:");
+        assert!(lines.iter().any(|line| line.starts_with("Hash aggregate count(0) grouped by ts")), "{:?}", lines);
+    }
+
+    #[test]
+    fn caches_only_partial_results_within_the_size_limit() {
+        let (catalog, _) = catalog();
+        let query = parse_query(b"select count_distinct(x) from t;").unwrap().1;
+        // The sets of distinct values of each batch take a few KB, the result a few bytes
+        for &(max_bytes, entries) in &[(1 << 20, 11), (1024, 1)] {
+            let cache = Arc::new(ResultCache::new(max_bytes, 100));
+            let options = ExecutionOptions { cache: cache.clone(), ..options(2) };
+            for _ in 0..2 {
+                let result = query.execute(&catalog, &options).unwrap();
+                assert_eq!(result.rows, vec![vec![ValueType::Integer(100)]]);
+            }
+            assert!(cache.describe().starts_with(&format!("{} entries", entries)), "{}", cache.describe());
+        }
+    }
+
+    /// Runs a query on a sample drawn with `seed`.
+    fn run_sampled(catalog: &Catalog, query: &str, seed: u64) -> QueryResult {
+        let query = parse_query(format!("{};", query).as_bytes()).unwrap().1;
+        query.validate().unwrap();
+        let options = ExecutionOptions { sample_seed: Some(seed), ..options(2) };
+        query.execute(catalog, &options).unwrap()
+    }
+
+    #[test]
+    fn sampled_intervals_cover_true_values() {
+        let (catalog, total) = catalog();
+        let runs = 400;
+        let mut covered = [0, 0];
+        for seed in 0..runs {
+            let result = run_sampled(&catalog, "select sum(x), count(0) from t sample 20%", seed);
+            for (col, &truth) in [total as f64, 10000.0].iter().enumerate() {
+                let interval = result.intervals[0][col].unwrap();
+                if interval.low <= truth && truth <= interval.high {
+                    covered[col] += 1;
+                }
+            }
+        }
+        // 95% intervals, with a margin of more than 3 standard deviations of the coverage over 400 runs
+        for &count in &covered {
+            let coverage = count as f64 / runs as f64;
+            assert!(coverage > 0.9 && coverage < 0.99, "coverage {}", coverage);
+        }
+    }
+
+    #[test]
+    fn timestamps_are_not_scaled() {
+        let mut table = BatchStore::new(None);
+        for batch in 0..4 {
+            let records = (0..1000)
+                .map(|i| vec![("ts".to_string(), ValueType::Timestamp(1_000_000 + batch * 1000 + i))])
+                .collect();
+            table.push(columnarize(records)).unwrap();
+        }
+        let mut catalog = Catalog::new();
+        catalog.add("e".to_string(), table);
+
+        let result = run_sampled(&catalog, "select sum(ts), count(0) from e sample 50%", 7);
+        assert_eq!(run_sampled(&catalog, "select sum(ts), count(0) from e sample 50%", 7).rows, result.rows);
+        // The same seed samples the same rows, whose timestamps add up to the unscaled sum
+        let sampled = run_sampled(&catalog, "select ts from e sample 50%", 7).rows;
+        let sum: u64 = sampled
+            .iter()
+            .map(|row| match row[0] {
+                ValueType::Timestamp(t) => t,
+                ref other => panic!("Unexpected timestamp {:?}", other),
+            })
+            .sum();
+        assert!(sampled.len() > 1000 && sampled.len() < 3000);
+        assert_eq!(result.rows[0][0], ValueType::Timestamp(sum));
+        assert!(result.intervals[0][0].is_none());
+        assert!(result.intervals[0][1].is_some());
+    }
 }
@@ -0,0 +1,203 @@
+use heapsize::HeapSizeOf;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use aggregator::Accumulator;
+use group_table::{accumulators_bytes, key_bytes};
+use value::ValueType;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    /// Result of a normalized query, with the ids of all batches it reads.
+    Result(Arc<String>, Vec<usize>),
+    /// Partial result of a normalized query on the batch with the last id, with the ids of the batches of other
+    /// tables it reads, e.g. in joins and subqueries.
+    Partial(Arc<String>, Vec<usize>, usize),
+}
+
+/// Rows of a result or of a partial result, which also holds the groups of aggregations.
+#[derive(Clone)]
+pub struct CachedRows {
+    pub rows: Vec<Vec<ValueType>>,
+    pub groups: Vec<(Vec<ValueType>, Vec<Accumulator>)>,
+}
+
+/// Keeps results of recent queries and the partial results of the batches they scanned, evicting the least
+/// recently used entries beyond a maximum size and number of entries. Keys hold the ids of the batches an entry
+/// was computed from, so entries are no longer used once a table is loaded again, and after appending batches to
+/// a table only the new ones are scanned.
+pub struct ResultCache {
+    max_bytes: usize,
+    max_entries: usize,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: HashMap<CacheKey, Entry>,
+    bytes: usize,
+    clock: usize,
+    hits: usize,
+    misses: usize,
+}
+
+struct Entry {
+    rows: CachedRows,
+    bytes: usize,
+    last_used: usize,
+}
+
+impl CachedRows {
+    pub fn bytes(&self) -> usize {
+        self.rows.heap_size_of_children() +
+            self.groups
+                .iter()
+                .map(|&(ref key, ref accumulators)| key_bytes(key) + accumulators_bytes(accumulators))
+                .sum::<usize>()
+    }
+}
+
+impl CacheKey {
+    fn batch_ids(&self) -> Vec<usize> {
+        match self {
+            &CacheKey::Result(_, ref ids) => ids.clone(),
+            &CacheKey::Partial(_, ref ids, id) => ids.iter().cloned().chain(Some(id)).collect(),
+        }
+    }
+}
+
+impl ResultCache {
+    pub fn new(max_bytes: usize, max_entries: usize) -> ResultCache {
+        ResultCache {
+            max_bytes: max_bytes,
+            max_entries: max_entries,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                bytes: 0,
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<CachedRows> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let rows = state.entries.get_mut(key).map(|entry| {
+            entry.last_used = clock;
+            entry.rows.clone()
+        });
+        if rows.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+        rows
+    }
+
+    /// Whether an entry of `bytes` bytes fits into the cache.
+    pub fn admits(&self, bytes: usize) -> bool {
+        self.max_entries > 0 && bytes <= self.max_bytes
+    }
+
+    pub fn put(&self, key: CacheKey, rows: CachedRows) {
+        let bytes = rows.bytes();
+        if !self.admits(bytes) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let entry = Entry {
+            rows: rows,
+            bytes: bytes,
+            last_used: state.clock,
+        };
+        state.bytes += bytes;
+        if let Some(replaced) = state.entries.insert(key, entry) {
+            state.bytes -= replaced.bytes;
+        }
+        while state.bytes > self.max_bytes || state.entries.len() > self.max_entries {
+            let victim = state.entries
+                .iter()
+                .min_by_key(|&(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            let evicted = state.entries.remove(&victim).unwrap();
+            state.bytes -= evicted.bytes;
+        }
+    }
+
+    /// Removes the entries computed from batches other than `batch_ids`, e.g. of tables that were loaded again.
+    pub fn retain(&self, batch_ids: &HashSet<usize>) {
+        let mut state = self.state.lock().unwrap();
+        let stale: Vec<CacheKey> = state.entries
+            .keys()
+            .filter(|key| key.batch_ids().iter().any(|id| !batch_ids.contains(id)))
+            .cloned()
+            .collect();
+        for key in stale {
+            let evicted = state.entries.remove(&key).unwrap();
+            state.bytes -= evicted.bytes;
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.bytes = 0;
+    }
+
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().unwrap();
+        format!(
+            "{} entries taking {:.2} of {:.2} MB (at most {} entries), {} hits, {} misses",
+            state.entries.len(),
+            state.bytes as f64 / 1024f64 / 1024f64,
+            self.max_bytes as f64 / 1024f64 / 1024f64,
+            self.max_entries,
+            state.hits,
+            state.misses
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::Result(Arc::new(name.to_string()), vec![1])
+    }
+
+    fn rows(n: usize) -> CachedRows {
+        CachedRows {
+            rows: vec![vec![ValueType::Integer(1)]; n],
+            groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn admits_entries_within_the_limits_and_evicts_the_least_recently_used() {
+        let bytes = rows(10).bytes();
+        let cache = ResultCache::new(2 * bytes, 10);
+        assert!(!cache.admits(2 * bytes + 1));
+        cache.put(key("huge"), rows(100));
+        assert!(cache.get(&key("huge")).is_none());
+
+        cache.put(key("a"), rows(10));
+        cache.put(key("b"), rows(10));
+        assert!(cache.get(&key("a")).is_some());
+        cache.put(key("c"), rows(10));
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("c")).is_some());
+
+        cache.retain(&HashSet::new());
+        assert!(cache.get(&key("c")).is_none());
+    }
+}
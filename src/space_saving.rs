@@ -0,0 +1,113 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Read, Result, Write};
+
+use spill::{read_u64, read_value, write_u64, write_value};
+use value::ValueType;
+
+/// Space-Saving summary that tracks the approximate counts of the most frequent values with a fixed number
+/// of counters. Each counter overestimates the true count of its value by at most its `error`, which in turn
+/// is bounded by the number of inserted values divided by the number of counters.
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<ValueType, (u64, u64)>,
+    by_count: BTreeSet<(u64, ValueType)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity: capacity,
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: &ValueType) {
+        if let Some(&(count, error)) = self.counters.get(value) {
+            self.set(value.clone(), count + 1, error);
+        } else if self.counters.len() < self.capacity {
+            self.set(value.clone(), 1, 0);
+        } else {
+            // replace the value with the smallest count, whose count becomes the error of the new value
+            let (min_count, min_value) = self.by_count.iter().next().cloned().unwrap();
+            self.by_count.remove(&(min_count, min_value.clone()));
+            self.counters.remove(&min_value);
+            self.set(value.clone(), min_count + 1, min_count);
+        }
+    }
+
+    /// Merges two summaries. A value missing from a full summary may have occurred up to that summary's
+    /// minimum count times, which is added to both its count and its error.
+    pub fn merge(&mut self, other: SpaceSaving) {
+        let min_self = self.min_count();
+        let min_other = other.min_count();
+        let mut merged: HashMap<ValueType, (u64, u64)> = HashMap::new();
+        for (value, (count, error)) in self.counters.drain() {
+            let (other_count, other_error) = other.counters.get(&value).cloned().unwrap_or((min_other, min_other));
+            merged.insert(value, (count + other_count, error + other_error));
+        }
+        for (value, (count, error)) in other.counters {
+            merged.entry(value).or_insert((count + min_self, error + min_self));
+        }
+        let mut entries: Vec<(ValueType, (u64, u64))> = merged.into_iter().collect();
+        entries.sort_by(|&(ref v1, (c1, _)), &(ref v2, (c2, _))| c2.cmp(&c1).then(v1.cmp(v2)));
+        entries.truncate(self.capacity);
+        self.by_count.clear();
+        for (value, (count, error)) in entries {
+            self.set(value, count, error);
+        }
+    }
+
+    /// Returns the `k` values with the highest estimated counts, together with count and maximum overestimation.
+    pub fn top(&self, k: usize) -> Vec<(ValueType, u64, u64)> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(k)
+            .map(|&(count, ref value)| (value.clone(), count, self.counters[value].1))
+            .collect()
+    }
+
+    /// Number of counters in use.
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_u64(writer, self.capacity as u64)?;
+        write_u64(writer, self.counters.len() as u64)?;
+        for (value, &(count, error)) in &self.counters {
+            write_value(writer, value)?;
+            write_u64(writer, count)?;
+            write_u64(writer, error)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<SpaceSaving> {
+        let mut summary = SpaceSaving::new(read_u64(reader)? as usize);
+        for _ in 0..read_u64(reader)? {
+            let value = read_value(reader)?;
+            let count = read_u64(reader)?;
+            let error = read_u64(reader)?;
+            summary.set(value, count, error);
+        }
+        Ok(summary)
+    }
+
+    fn min_count(&self) -> u64 {
+        if self.counters.len() < self.capacity {
+            0
+        } else {
+            self.by_count.iter().next().map_or(0, |&(count, _)| count)
+        }
+    }
+
+    fn set(&mut self, value: ValueType, count: u64, error: u64) {
+        if let Some((old_count, _)) = self.counters.insert(value.clone(), (count, error)) {
+            self.by_count.remove(&(old_count, value.clone()));
+        }
+        self.by_count.insert((count, value));
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use aggregator::Accumulator;
+use columns::{rebuild_column, Batch};
+use hyperloglog::HyperLogLog;
+use moments::{CoMoments, Moments};
+use space_saving::SpaceSaving;
+use tdigest::TDigest;
+use value::{OrderedFloat, ValueType};
+
+const TAG_NULL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_TIMESTAMP: u8 = 2;
+const TAG_INTEGER: u8 = 3;
+const TAG_STR: u8 = 4;
+const TAG_SET: u8 = 5;
+const TAG_FLOAT: u8 = 6;
+
+/// Most elements preallocated for a length read from a file. Lengths are not trusted, so that corrupt data fails
+/// with an error at the end of the file rather than with a huge allocation.
+const MAX_PREALLOCATED: u64 = 1 << 16;
+
+const TAG_VALUE: u8 = 0;
+const TAG_AVERAGE: u8 = 1;
+const TAG_DISTINCT: u8 = 2;
+const TAG_SKETCH: u8 = 3;
+const TAG_DIGEST: u8 = 4;
+const TAG_VALUES: u8 = 5;
+const TAG_MOMENTS: u8 = 6;
+const TAG_COMOMENTS: u8 = 7;
+const TAG_HEAVY_HITTERS: u8 = 8;
+
+pub fn write_batch(path: &Path, batch: &Batch) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_u64(&mut writer, batch.cols.len() as u64)?;
+    for col in &batch.cols {
+        let values: Vec<ValueType> = col.iter().collect();
+        write_str(&mut writer, col.get_name())?;
+        write_u64(&mut writer, values.len() as u64)?;
+        for value in &values {
+            write_value(&mut writer, value)?;
+        }
+    }
+    writer.flush()
+}
+
+pub fn read_batch(path: &Path) -> Result<Batch> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let ncols = read_u64(&mut reader)?;
+    let mut cols = Vec::with_capacity(capacity(ncols));
+    for _ in 0..ncols {
+        let name = read_string(&mut reader)?;
+        let len = read_u64(&mut reader)?;
+        let mut values = Vec::with_capacity(capacity(len));
+        for _ in 0..len {
+            values.push(read_value(&mut reader)?);
+        }
+        cols.push(rebuild_column(name, values));
+    }
+    Ok(Batch { cols: cols })
+}
+
+pub fn write_value<W: Write>(writer: &mut W, value: &ValueType) -> Result<()> {
+    match value {
+        &ValueType::Null => writer.write_all(&[TAG_NULL]),
+        &ValueType::Bool(b) => writer.write_all(&[TAG_BOOL, b as u8]),
+        &ValueType::Timestamp(t) => {
+            writer.write_all(&[TAG_TIMESTAMP])?;
+            write_u64(writer, t)
+        }
+        &ValueType::Integer(i) => {
+            writer.write_all(&[TAG_INTEGER])?;
+            write_u64(writer, i as u64)
+        }
+        &ValueType::Float(OrderedFloat(x)) => {
+            writer.write_all(&[TAG_FLOAT])?;
+            write_u64(writer, x.to_bits())
+        }
+        &ValueType::Str(ref s) => {
+            writer.write_all(&[TAG_STR])?;
+            write_str(writer, s)
+        }
+        &ValueType::Set(ref set) => {
+            writer.write_all(&[TAG_SET])?;
+            write_u64(writer, set.len() as u64)?;
+            for s in set.iter() {
+                write_str(writer, s)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn read_value<R: Read>(reader: &mut R) -> Result<ValueType> {
+    let mut tag = [0u8];
+    reader.read_exact(&mut tag)?;
+    let value = match tag[0] {
+        TAG_NULL => ValueType::Null,
+        TAG_BOOL => {
+            reader.read_exact(&mut tag)?;
+            ValueType::Bool(tag[0] != 0)
+        }
+        TAG_TIMESTAMP => ValueType::Timestamp(read_u64(reader)?),
+        TAG_INTEGER => ValueType::Integer(read_u64(reader)? as i64),
+        TAG_FLOAT => ValueType::Float(OrderedFloat(f64::from_bits(read_u64(reader)?))),
+        TAG_STR => ValueType::Str(Arc::new(read_string(reader)?)),
+        TAG_SET => {
+            let len = read_u64(reader)?;
+            let mut set = Vec::with_capacity(capacity(len));
+            for _ in 0..len {
+                set.push(read_string(reader)?);
+            }
+            ValueType::Set(Arc::new(set))
+        }
+        t => return Err(Error::new(ErrorKind::InvalidData, format!("unknown value tag {}", t))),
+    };
+    Ok(value)
+}
+
+pub fn write_accumulator<W: Write>(writer: &mut W, accumulator: &Accumulator) -> Result<()> {
+    match accumulator {
+        &Accumulator::Value(ref value) => {
+            writer.write_all(&[TAG_VALUE])?;
+            write_value(writer, value)
+        }
+        &Accumulator::Average(ref sum, count) => {
+            writer.write_all(&[TAG_AVERAGE])?;
+            write_value(writer, sum)?;
+            write_u64(writer, count as u64)
+        }
+        &Accumulator::Distinct(ref set) => {
+            writer.write_all(&[TAG_DISTINCT])?;
+            write_u64(writer, set.len() as u64)?;
+            for value in set {
+                write_value(writer, value)?;
+            }
+            Ok(())
+        }
+        &Accumulator::Sketch(ref sketch) => {
+            writer.write_all(&[TAG_SKETCH])?;
+            sketch.write_to(writer)
+        }
+        &Accumulator::Digest(ref digest) => {
+            writer.write_all(&[TAG_DIGEST])?;
+            digest.write_to(writer)
+        }
+        &Accumulator::Values(ref values) => {
+            writer.write_all(&[TAG_VALUES])?;
+            write_u64(writer, values.len() as u64)?;
+            for &x in values {
+                write_f64(writer, x)?;
+            }
+            Ok(())
+        }
+        &Accumulator::Moments(ref moments) => {
+            writer.write_all(&[TAG_MOMENTS])?;
+            moments.write_to(writer)
+        }
+        &Accumulator::CoMoments(ref comoments) => {
+            writer.write_all(&[TAG_COMOMENTS])?;
+            comoments.write_to(writer)
+        }
+        &Accumulator::HeavyHitters(ref summary) => {
+            writer.write_all(&[TAG_HEAVY_HITTERS])?;
+            summary.write_to(writer)
+        }
+    }
+}
+
+pub fn read_accumulator<R: Read>(reader: &mut R) -> Result<Accumulator> {
+    let mut tag = [0u8];
+    reader.read_exact(&mut tag)?;
+    let accumulator = match tag[0] {
+        TAG_VALUE => Accumulator::Value(read_value(reader)?),
+        TAG_AVERAGE => {
+            let sum = read_value(reader)?;
+            Accumulator::Average(sum, read_u64(reader)? as i64)
+        }
+        TAG_DISTINCT => {
+            let len = read_u64(reader)?;
+            let mut set = HashSet::with_capacity(capacity(len));
+            for _ in 0..len {
+                set.insert(read_value(reader)?);
+            }
+            Accumulator::Distinct(set)
+        }
+        TAG_SKETCH => Accumulator::Sketch(HyperLogLog::read_from(reader)?),
+        TAG_DIGEST => Accumulator::Digest(TDigest::read_from(reader)?),
+        TAG_VALUES => {
+            let len = read_u64(reader)?;
+            let mut values = Vec::with_capacity(capacity(len));
+            for _ in 0..len {
+                values.push(read_f64(reader)?);
+            }
+            Accumulator::Values(values)
+        }
+        TAG_MOMENTS => Accumulator::Moments(Moments::read_from(reader)?),
+        TAG_COMOMENTS => Accumulator::CoMoments(CoMoments::read_from(reader)?),
+        TAG_HEAVY_HITTERS => Accumulator::HeavyHitters(SpaceSaving::read_from(reader)?),
+        t => return Err(Error::new(ErrorKind::InvalidData, format!("unknown accumulator tag {}", t))),
+    };
+    Ok(accumulator)
+}
+
+pub fn write_f64<W: Write>(writer: &mut W, x: f64) -> Result<()> {
+    write_u64(writer, x.to_bits())
+}
+
+pub fn read_f64<R: Read>(reader: &mut R) -> Result<f64> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+
+pub fn write_u64<W: Write>(writer: &mut W, n: u64) -> Result<()> {
+    let mut bytes = [0u8; 8];
+    for i in 0..8 {
+        bytes[i] = (n >> (8 * i)) as u8;
+    }
+    writer.write_all(&bytes)
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    let mut n = 0u64;
+    for i in 0..8 {
+        n |= (bytes[i] as u64) << (8 * i);
+    }
+    Ok(n)
+}
+
+fn write_str<W: Write>(writer: &mut W, s: &str) -> Result<()> {
+    write_u64(writer, s.len() as u64)?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let len = read_u64(reader)?;
+    let mut bytes = Vec::with_capacity(capacity(len));
+    if reader.take(len).read_to_end(&mut bytes)? as u64 != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "string ends early"));
+    }
+    String::from_utf8(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Capacity to preallocate for `len` elements read from a file.
+pub fn capacity(len: u64) -> usize {
+    len.min(MAX_PREALLOCATED) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aggregator::{finalize_group, Aggregator};
+
+    fn roundtrip(accumulator: &Accumulator) -> Accumulator {
+        let mut bytes = Vec::new();
+        write_accumulator(&mut bytes, accumulator).unwrap();
+        let mut reader = &bytes[..];
+        let read = read_accumulator(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        read
+    }
+
+    /// Index of the variant, so that a new variant without a test fails to compile.
+    fn variant(accumulator: &Accumulator) -> usize {
+        match accumulator {
+            &Accumulator::Value(_) => 0,
+            &Accumulator::Average(..) => 1,
+            &Accumulator::Distinct(_) => 2,
+            &Accumulator::Sketch(_) => 3,
+            &Accumulator::Digest(_) => 4,
+            &Accumulator::Values(_) => 5,
+            &Accumulator::Moments(_) => 6,
+            &Accumulator::CoMoments(_) => 7,
+            &Accumulator::HeavyHitters(_) => 8,
+        }
+    }
+
+    fn numbers() -> Vec<ValueType> {
+        let mut state = 7u64;
+        (0..300)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ValueType::Integer((state >> 33) as i64 % 60 - 20)
+            })
+            .collect()
+    }
+
+    fn strings() -> Vec<ValueType> {
+        numbers().iter().map(|n| ValueType::Str(Arc::new(format!("page{}", n)))).collect()
+    }
+
+    #[test]
+    fn roundtrips_every_accumulator() {
+        let aggregators = vec![
+            (Aggregator::Sum, numbers()),
+            (Aggregator::Min, strings()),
+            (Aggregator::SumOfSquares, numbers()),
+            (Aggregator::Avg, numbers()),
+            (Aggregator::CountDistinct, strings()),
+            (Aggregator::ApproxCountDistinct(10), strings()),
+            (Aggregator::Median, numbers()),
+            (Aggregator::ExactPercentile(0.9), numbers()),
+            (Aggregator::VarSamp, numbers()),
+            (Aggregator::Corr, numbers()),
+            (Aggregator::ApproxTopK(3), strings()),
+        ];
+        let mut variants = HashSet::new();
+        for (aggregator, values) in aggregators {
+            let mut accumulator = aggregator.zero();
+            for (i, value) in values.iter().enumerate() {
+                match aggregator {
+                    Aggregator::Corr => aggregator.reduce_pair(&mut accumulator, value, &ValueType::Integer(i as i64)),
+                    _ => aggregator.reduce(&mut accumulator, value),
+                }
+            }
+            let read = roundtrip(&accumulator);
+            variants.insert(variant(&accumulator));
+            assert_eq!(variant(&read), variant(&accumulator));
+            if let (&Accumulator::Distinct(ref original), &Accumulator::Distinct(ref set)) = (&accumulator, &read) {
+                assert_eq!(set, original);
+            }
+            assert_eq!(
+                finalize_group(&vec![aggregator], Vec::new(), vec![read]),
+                finalize_group(&vec![aggregator], Vec::new(), vec![accumulator]),
+                "{:?}",
+                aggregator
+            );
+        }
+        assert_eq!(variants.len(), 9);
+    }
+
+    #[test]
+    fn roundtrips_every_value_type() {
+        let values = vec![
+            ValueType::Null,
+            ValueType::Bool(true),
+            ValueType::Timestamp(1500000000),
+            ValueType::Integer(-42),
+            ValueType::Float(OrderedFloat(-0.5)),
+            ValueType::Str(Arc::new("caf\u{e9}".to_string())),
+            ValueType::Set(Arc::new(vec!["a".to_string(), "".to_string()])),
+        ];
+        for value in values {
+            match roundtrip(&Accumulator::Average(value.clone(), 3)) {
+                Accumulator::Average(read, 3) => assert_eq!(read, value),
+                other => panic!("Read {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        let err = read_accumulator(&mut &[42u8][..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = read_accumulator(&mut &[TAG_VALUE, 42][..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let invalid_utf8 = [TAG_VALUE, TAG_STR, 1, 0, 0, 0, 0, 0, 0, 0, 0xff];
+        assert_eq!(read_accumulator(&mut &invalid_utf8[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+        let truncated = [TAG_VALUE, TAG_INTEGER, 1];
+        assert_eq!(read_accumulator(&mut &truncated[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_the_data() {
+        let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        for &tag in &[TAG_STR, TAG_SET] {
+            let mut bytes = vec![TAG_VALUE, tag];
+            bytes.extend_from_slice(&huge);
+            assert_eq!(read_accumulator(&mut &bytes[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        }
+        for &tag in &[TAG_DISTINCT, TAG_VALUES] {
+            let mut bytes = vec![tag];
+            bytes.extend_from_slice(&huge);
+            assert_eq!(read_accumulator(&mut &bytes[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        }
+        let mut sketch = vec![TAG_SKETCH];
+        sketch.extend_from_slice(&huge);
+        assert_eq!(read_accumulator(&mut &sketch[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
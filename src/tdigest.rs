@@ -0,0 +1,273 @@
+use std::cmp::Ordering;
+use std::io::{Read, Result, Write};
+
+use spill::{read_f64, read_u64, write_f64, write_u64};
+
+const COMPRESSION: f64 = 100.0;
+const BUFFER_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// Merging t-digest for estimating quantiles. Values are clustered into at most a few hundred centroids
+/// which are kept small near the tails, so that extreme quantiles like p99 stay accurate.
+/// Digests of different batches can be merged.
+#[derive(Debug, Clone)]
+pub struct TDigest {
+    centroids: Vec<Centroid>,
+    buffer: Vec<Centroid>,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    pub fn new() -> TDigest {
+        TDigest {
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            min: ::std::f64::INFINITY,
+            max: ::std::f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(Centroid {
+            mean: value,
+            weight: 1.0,
+        });
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.compress();
+        }
+    }
+
+    pub fn merge(&mut self, other: TDigest) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.buffer.extend(other.centroids);
+        self.buffer.extend(other.buffer);
+        self.compress();
+    }
+
+    /// Number of centroids, including those not yet compressed.
+    pub fn len(&self) -> usize {
+        self.centroids.len() + self.buffer.len()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_f64(writer, self.min)?;
+        write_f64(writer, self.max)?;
+        write_u64(writer, self.len() as u64)?;
+        for centroid in self.centroids.iter().chain(self.buffer.iter()) {
+            write_f64(writer, centroid.mean)?;
+            write_f64(writer, centroid.weight)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<TDigest> {
+        let mut digest = TDigest::new();
+        digest.min = read_f64(reader)?;
+        digest.max = read_f64(reader)?;
+        for _ in 0..read_u64(reader)? {
+            let mean = read_f64(reader)?;
+            let weight = read_f64(reader)?;
+            digest.buffer.push(Centroid {
+                mean: mean,
+                weight: weight,
+            });
+        }
+        Ok(digest)
+    }
+
+    /// Returns the estimated value at quantile `q` (between 0 and 1), or `None` if the digest is empty.
+    pub fn quantile(mut self, q: f64) -> Option<f64> {
+        self.compress();
+        let centroids = &self.centroids;
+        if centroids.is_empty() {
+            return None;
+        }
+        if centroids.len() == 1 {
+            return Some(centroids[0].mean);
+        }
+        let total: f64 = centroids.iter().map(|c| c.weight).sum();
+        let index = q * total;
+        let first = centroids[0];
+        if index < first.weight / 2.0 {
+            return Some(interpolate(self.min, first.mean, index / (first.weight / 2.0)));
+        }
+        let mut cumulative = 0.0;
+        for pair in centroids.windows(2) {
+            let left = cumulative + pair[0].weight / 2.0;
+            let right = cumulative + pair[0].weight + pair[1].weight / 2.0;
+            if index < right {
+                return Some(interpolate(pair[0].mean, pair[1].mean, (index - left) / (right - left)));
+            }
+            cumulative += pair[0].weight;
+        }
+        let last = centroids[centroids.len() - 1];
+        let left = total - last.weight / 2.0;
+        Some(interpolate(last.mean, self.max, ((index - left) / (last.weight / 2.0)).min(1.0)))
+    }
+
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = Vec::with_capacity(self.centroids.len() + self.buffer.len());
+        all.extend(self.centroids.drain(..));
+        all.extend(self.buffer.drain(..));
+        all.sort_by(|c1, c2| c1.mean.partial_cmp(&c2.mean).unwrap_or(Ordering::Equal));
+        let total: f64 = all.iter().map(|c| c.weight).sum();
+
+        let mut merged = Vec::new();
+        let mut current = all[0];
+        let mut weight_so_far = 0.0;
+        for &next in &all[1..] {
+            let q0 = weight_so_far / total;
+            let q2 = (weight_so_far + current.weight + next.weight) / total;
+            let limit = total * max_size(q0).min(max_size(q2));
+            if current.weight + next.weight <= limit {
+                let weight = current.weight + next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                weight_so_far += current.weight;
+                merged.push(current);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+}
+
+/// Maximum weight of a centroid at quantile `q` as a fraction of the total weight.
+fn max_size(q: f64) -> f64 {
+    4.0 * q * (1.0 - q) / COMPRESSION
+}
+
+fn interpolate(from: f64, to: f64, t: f64) -> f64 {
+    from + (to - from) * t
+}
+
+/// Exact quantile of `values` with linear interpolation between the closest ranks.
+pub fn exact_quantile(mut values: Vec<f64>, q: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|x1, x2| x1.partial_cmp(x2).unwrap_or(Ordering::Equal));
+    let position = q * (values.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    Some(interpolate(values[lower], values[upper], position - lower as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random values in [0, 1) from a linear congruential generator.
+    fn uniform(count: usize, seed: u64) -> Vec<f64> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 11) as f64 / (1u64 << 53) as f64
+            })
+            .collect()
+    }
+
+    fn digest(values: &[f64]) -> TDigest {
+        let mut digest = TDigest::new();
+        for &value in values {
+            digest.insert(value);
+        }
+        digest
+    }
+
+    /// Asserts that the rank of the estimated quantile `q` of `values` is off by at most `max_error`.
+    fn assert_rank_error(digest: &TDigest, values: &[f64], q: f64, max_error: f64) {
+        let estimate = digest.clone().quantile(q).unwrap();
+        let rank = values.iter().filter(|&&value| value < estimate).count() as f64 / values.len() as f64;
+        assert!(
+            (rank - q).abs() <= max_error,
+            "quantile {} estimated as {} with rank {}",
+            q,
+            estimate,
+            rank
+        );
+    }
+
+    #[test]
+    fn estimates_quantiles_accurately() {
+        // Exponentially distributed, so that the tails are skewed
+        let values: Vec<f64> = uniform(100_000, 1).into_iter().map(|u| -(1.0 - u).ln()).collect();
+        let mut digest = digest(&values);
+        digest.compress();
+        assert!(digest.len() < 1000, "{} centroids", digest.len());
+        for &q in &[0.1, 0.25, 0.5, 0.75, 0.9] {
+            assert_rank_error(&digest, &values, q, 0.01);
+        }
+        for &q in &[0.001, 0.01, 0.99, 0.999] {
+            assert_rank_error(&digest, &values, q, 0.001);
+        }
+    }
+
+    #[test]
+    fn extreme_quantiles_are_min_and_max() {
+        let values = uniform(10_000, 2);
+        let min = values.iter().cloned().fold(::std::f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(::std::f64::NEG_INFINITY, f64::max);
+        let digest = digest(&values);
+        assert_eq!(digest.clone().quantile(0.0), Some(min));
+        assert_eq!(digest.quantile(1.0), Some(max));
+    }
+
+    #[test]
+    fn merged_digests_estimate_quantiles_of_all_values() {
+        let values = uniform(100_000, 3);
+        let mut merged = TDigest::new();
+        for chunk in values.chunks(7_000) {
+            merged.merge(digest(chunk));
+        }
+        assert!(merged.len() < 1000, "{} centroids", merged.len());
+        for &q in &[0.01, 0.1, 0.5, 0.9, 0.99] {
+            assert_rank_error(&merged, &values, q, 0.01);
+        }
+    }
+
+    #[test]
+    fn handles_empty_and_single_values() {
+        assert_eq!(TDigest::new().quantile(0.5), None);
+        assert_eq!(digest(&[42.0]).quantile(0.9), Some(42.0));
+        let mut merged = TDigest::new();
+        merged.merge(TDigest::new());
+        assert_eq!(merged.quantile(0.5), None);
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let values = uniform(5_000, 4);
+        let original = digest(&values);
+        let mut bytes = Vec::new();
+        original.write_to(&mut bytes).unwrap();
+        let read = TDigest::read_from(&mut &bytes[..]).unwrap();
+        for &q in &[0.0, 0.3, 0.5, 0.97, 1.0] {
+            assert_eq!(read.clone().quantile(q), original.clone().quantile(q));
+        }
+    }
+
+    #[test]
+    fn exact_quantile_interpolates_between_ranks() {
+        assert_eq!(exact_quantile(vec![], 0.5), None);
+        assert_eq!(exact_quantile(vec![3.0, 1.0, 2.0, 4.0], 0.5), Some(2.5));
+        assert_eq!(exact_quantile(vec![3.0, 1.0, 2.0, 4.0], 0.0), Some(1.0));
+        assert_eq!(exact_quantile(vec![3.0, 1.0, 2.0, 4.0], 1.0), Some(4.0));
+        assert_eq!(exact_quantile(vec![10.0, 20.0], 0.25), Some(12.5));
+    }
+}
@@ -0,0 +1,184 @@
+const SECONDS_PER_DAY: i64 = 86_400;
+/// 1970-01-01 was a Thursday, weeks start on the following Monday.
+const FIRST_MONDAY: i64 = 4 * SECONDS_PER_DAY;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+/// Buckets of `count` units that start at `origin` seconds after the Unix epoch (or at the first Monday
+/// for weeks). Timestamps are interpreted as seconds since the Unix epoch in UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    count: i64,
+    unit: TimeUnit,
+    origin: i64,
+}
+
+impl Interval {
+    /// Parses intervals like `"15 minutes"`, `"1 hour"` or `"day"`.
+    pub fn parse(interval: &str, origin: i64) -> Result<Interval, String> {
+        let parts: Vec<&str> = interval.split_whitespace().collect();
+        let (count, unit) = match parts.len() {
+            1 => (1, parts[0]),
+            2 => {
+                let count = parts[0].parse::<i64>().map_err(|_| format!("Invalid interval count {}", parts[0]))?;
+                (count, parts[1])
+            }
+            _ => return Err(format!("Invalid interval \"{}\"", interval)),
+        };
+        if count <= 0 {
+            return Err(format!("Interval count must be positive, got {}", count));
+        }
+        let unit = match unit.to_lowercase().trim_end_matches('s') {
+            "second" => TimeUnit::Second,
+            "minute" => TimeUnit::Minute,
+            "hour" => TimeUnit::Hour,
+            "day" => TimeUnit::Day,
+            "week" => TimeUnit::Week,
+            "month" => TimeUnit::Month,
+            _ => return Err(format!("Unknown time unit {}", unit)),
+        };
+        let origin = if unit == TimeUnit::Week { origin + FIRST_MONDAY } else { origin };
+        Ok(Interval {
+            count: count,
+            unit: unit,
+            origin: origin,
+        })
+    }
+
+    /// Returns the start of the bucket containing `timestamp`.
+    pub fn bucket(&self, timestamp: i64) -> i64 {
+        let offset = timestamp - self.origin;
+        let seconds = match self.unit {
+            TimeUnit::Second => 1,
+            TimeUnit::Minute => 60,
+            TimeUnit::Hour => 3600,
+            TimeUnit::Day => SECONDS_PER_DAY,
+            TimeUnit::Week => 7 * SECONDS_PER_DAY,
+            TimeUnit::Month => {
+                let (year, month) = year_month(offset.div_euclid(SECONDS_PER_DAY));
+                let months = (year * 12 + month - 1).div_euclid(self.count) * self.count;
+                let first_day = days_from_civil(months.div_euclid(12), months.rem_euclid(12) + 1, 1);
+                return first_day * SECONDS_PER_DAY + self.origin;
+            }
+        } * self.count;
+        offset.div_euclid(seconds) * seconds + self.origin
+    }
+}
+
+// Conversions between days since the Unix epoch and the proleptic Gregorian calendar,
+// after http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn year_month(days: i64) -> (i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(interval: &str, timestamp: i64) -> i64 {
+        Interval::parse(interval, 0).unwrap().bucket(timestamp)
+    }
+
+    #[test]
+    fn parses_counts_and_plural_units() {
+        assert_eq!(
+            Interval::parse("15 Minutes", 0),
+            Ok(Interval {
+                count: 15,
+                unit: TimeUnit::Minute,
+                origin: 0,
+            })
+        );
+        assert_eq!(Interval::parse("day", 0).map(|interval| interval.unit), Ok(TimeUnit::Day));
+        assert!(Interval::parse("0 hours", 0).is_err());
+        assert!(Interval::parse("two hours", 0).is_err());
+        assert!(Interval::parse("1 fortnight", 0).is_err());
+        assert!(Interval::parse("1 2 days", 0).is_err());
+    }
+
+    #[test]
+    fn buckets_fixed_length_units() {
+        assert_eq!(bucket("1 second", 1_500_000_000), 1_500_000_000);
+        assert_eq!(bucket("15 minutes", 1_500_000_899), 1_500_000_300);
+        assert_eq!(bucket("hour", 7199), 3600);
+        assert_eq!(bucket("day", 1_483_228_799), 1_483_142_400);
+        assert_eq!(bucket("day", 1_483_228_800), 1_483_228_800);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // 1970-01-05 was a Monday
+        assert_eq!(bucket("week", 345_600), 345_600);
+        assert_eq!(bucket("week", 345_599), -259_200);
+        assert_eq!(bucket("2 weeks", 345_600 + 13 * SECONDS_PER_DAY), 345_600);
+    }
+
+    #[test]
+    fn buckets_calendar_months() {
+        // 2016-02-29 23:59:59 falls into February of a leap year, one second later March starts
+        assert_eq!(bucket("month", 1_456_790_399), 1_454_284_800);
+        assert_eq!(bucket("month", 1_456_790_400), 1_456_790_400);
+        // 2016-12-31 23:59:59 and 2017-01-01
+        assert_eq!(bucket("1 month", 1_483_228_799), 1_480_550_400);
+        assert_eq!(bucket("1 month", 1_483_228_800), 1_483_228_800);
+        // Quarters start in January, April, July and October
+        assert_eq!(bucket("3 months", 1_463_313_600), 1_459_468_800);
+        assert_eq!(bucket("12 months", 1_463_313_600), 1_451_606_400);
+    }
+
+    #[test]
+    fn buckets_negative_timestamps() {
+        assert_eq!(bucket("hour", -1), -3600);
+        assert_eq!(bucket("day", -1), -SECONDS_PER_DAY);
+        assert_eq!(bucket("month", -1), -2_678_400);
+        assert_eq!(bucket("week", 0), -259_200);
+        // 1600-02-29 12:00 is in a leap year divisible by 400
+        assert_eq!(bucket("month", -11_670_955_200), -11_673_417_600);
+        assert_eq!(bucket("day", -11_670_955_200), -11_670_955_200 - 12 * 3600);
+    }
+
+    #[test]
+    fn buckets_start_at_origin() {
+        assert_eq!(Interval::parse("15 minutes", 300).unwrap().bucket(1000), 300);
+        assert_eq!(Interval::parse("15 minutes", 300).unwrap().bucket(299), -600);
+        assert_eq!(Interval::parse("day", 6 * 3600).unwrap().bucket(3600), -18 * 3600);
+    }
+
+    #[test]
+    fn converts_between_days_and_dates() {
+        for &(year, month, day) in &[(1970, 1, 1), (1969, 12, 31), (2000, 2, 29), (2016, 3, 1), (1600, 2, 29)] {
+            let days = days_from_civil(year, month, day);
+            assert_eq!(year_month(days), (year, month));
+            assert_eq!(year_month(days - (day - 1)), (year, month));
+        }
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2017, 1, 1), 17_167);
+    }
+}
@@ -1,6 +1,8 @@
 use heapsize::HeapSizeOf;
+use std::cmp::Ordering;
 use std::fmt;
-use std::rc::Rc;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum ValueType {
@@ -8,10 +10,15 @@ pub enum ValueType {
     Bool(bool),
     Timestamp(u64),
     Integer(i64),
-    Str(Rc<String>),
-    Set(Rc<Vec<String>>),
+    Float(OrderedFloat),
+    Str(Arc<String>),
+    Set(Arc<Vec<String>>),
 }
 
+/// `f64` with a total order, so that floats can be used as group keys and sorted.
+#[derive(Debug, Clone, Copy)]
+pub struct OrderedFloat(pub f64);
+
 pub type RecordType = Vec<(String, ValueType)>;
 
 impl fmt::Display for ValueType {
@@ -21,6 +28,7 @@ impl fmt::Display for ValueType {
             &ValueType::Bool(b) => write!(f, "{}", b),
             &ValueType::Timestamp(t) => write!(f, "t{}", t),
             &ValueType::Integer(i) => write!(f, "{}", i),
+            &ValueType::Float(OrderedFloat(x)) => write!(f, "{}", x),
             &ValueType::Str(ref s) => write!(f, "\"{}\"", s),
             &ValueType::Set(ref vec) => write!(f, "{:?}", vec),
         }
@@ -31,9 +39,111 @@ impl HeapSizeOf for ValueType {
     fn heap_size_of_children(&self) -> usize {
         use ValueType::*;
         match self {
-            &Null | &Bool(_) | &Timestamp(_) | &Integer(_) => 0,
+            &Null | &Bool(_) | &Timestamp(_) | &Integer(_) | &Float(_) => 0,
             &Str(ref r) => r.heap_size_of_children(),
             &Set(ref r) => r.heap_size_of_children(),
         }
     }
 }
+
+impl ValueType {
+    fn type_rank(&self) -> u8 {
+        match self {
+            &ValueType::Null => 0,
+            &ValueType::Bool(_) => 1,
+            &ValueType::Timestamp(_) | &ValueType::Integer(_) | &ValueType::Float(_) => 2,
+            &ValueType::Str(_) => 3,
+            &ValueType::Set(_) => 4,
+        }
+    }
+
+    fn numeric_rank(&self) -> u8 {
+        match self {
+            &ValueType::Integer(_) => 0,
+            &ValueType::Timestamp(_) => 1,
+            _ => 2,
+        }
+    }
+
+    /// Compares two numbers by value, exactly if both are integral, or None if either is not a number or is NaN.
+    /// Unlike `cmp`, an integer and a float or timestamp of the same value are equal.
+    pub fn numeric_cmp(&self, other: &ValueType) -> Option<Ordering> {
+        match (self.as_i128(), other.as_i128()) {
+            (Some(i1), Some(i2)) => Some(i1.cmp(&i2)),
+            _ => self.as_f64()?.partial_cmp(&other.as_f64()?),
+        }
+    }
+
+    fn as_i128(&self) -> Option<i128> {
+        match self {
+            &ValueType::Integer(i) => Some(i as i128),
+            &ValueType::Timestamp(t) => Some(t as i128),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            &ValueType::Integer(i) => Some(i as f64),
+            &ValueType::Timestamp(t) => Some(t as f64),
+            &ValueType::Float(OrderedFloat(x)) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Total order used for sorting: nulls first, then booleans, numbers, strings and sets.
+/// Integers, timestamps and floats are compared by numeric value.
+impl Ord for ValueType {
+    fn cmp(&self, other: &ValueType) -> Ordering {
+        use ValueType::*;
+        match (self, other) {
+            (&Bool(b1), &Bool(b2)) => b1.cmp(&b2),
+            (&Integer(i1), &Integer(i2)) => i1.cmp(&i2),
+            (&Timestamp(t1), &Timestamp(t2)) => t1.cmp(&t2),
+            (&Float(f1), &Float(f2)) => f1.cmp(&f2),
+            (&Integer(i), &Timestamp(t)) => (i as i128).cmp(&(t as i128)).then(Ordering::Less),
+            (&Timestamp(t), &Integer(i)) => (t as i128).cmp(&(i as i128)).then(Ordering::Greater),
+            (&Str(ref s1), &Str(ref s2)) => s1.cmp(s2),
+            (&Set(ref s1), &Set(ref s2)) => s1.cmp(s2),
+            (v1, v2) if v1.type_rank() == 2 && v2.type_rank() == 2 => {
+                let x1 = OrderedFloat(v1.as_f64().unwrap());
+                let x2 = OrderedFloat(v2.as_f64().unwrap());
+                x1.cmp(&x2).then(v1.numeric_rank().cmp(&v2.numeric_rank()))
+            }
+            (v1, v2) => v1.type_rank().cmp(&v2.type_rank()),
+        }
+    }
+}
+
+impl PartialOrd for ValueType {
+    fn partial_cmp(&self, other: &ValueType) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedFloat {
+    fn cmp(&self, other: &OrderedFloat) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for OrderedFloat {
+    fn partial_cmp(&self, other: &OrderedFloat) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedFloat {
+    fn eq(&self, other: &OrderedFloat) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedFloat {}
+
+impl Hash for OrderedFloat {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state)
+    }
+}
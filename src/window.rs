@@ -0,0 +1,186 @@
+use aggregator::{Accumulator, Aggregator};
+use expression::Expr;
+use query_engine::compare_keys;
+use value::ValueType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowFunction {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Lag(usize),
+    Lead(usize),
+    Aggregate(Aggregator),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frame {
+    /// The whole partition without order by, otherwise all rows up to the current row and its peers.
+    Default,
+    /// The current row and the given number of preceding rows, or all preceding rows if `None`.
+    Rows(Option<usize>),
+}
+
+/// Window function call like `sum(events) over (partition by user_id order by timestamp)`. Windows are
+/// computed after filtering and aggregation, on the rows the select list is evaluated on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    pub function: WindowFunction,
+    pub args: Vec<Expr>,
+    pub partition_by: Vec<Expr>,
+    pub order_by: Vec<(Expr, bool)>,
+    pub frame: Frame,
+}
+
+impl Window {
+    pub fn exprs(&self) -> Vec<&Expr> {
+        self.args
+            .iter()
+            .chain(self.partition_by.iter())
+            .chain(self.order_by.iter().map(|&(ref expr, _)| expr))
+            .collect()
+    }
+
+    pub fn map_exprs<F: FnMut(&Expr) -> Expr>(&self, mut f: F) -> Window {
+        Window {
+            function: self.function,
+            args: self.args.iter().map(|expr| f(expr)).collect(),
+            partition_by: self.partition_by.iter().map(|expr| f(expr)).collect(),
+            order_by: self.order_by.iter().map(|&(ref expr, desc)| (f(expr), desc)).collect(),
+            frame: self.frame,
+        }
+    }
+
+    /// Computes the window function for every row. All expressions must be compiled against `rows`.
+    pub fn compute(&self, rows: &Vec<Vec<ValueType>>) -> Vec<ValueType> {
+        let descending: Vec<bool> = self.order_by.iter().map(|&(_, desc)| desc).collect();
+        let keys: Vec<(Vec<ValueType>, Vec<ValueType>)> = rows.iter()
+            .map(|row| {
+                (
+                    self.partition_by.iter().map(|expr| expr.eval(row)).collect(),
+                    self.order_by.iter().map(|&(ref expr, _)| expr.eval(row)).collect(),
+                )
+            })
+            .collect();
+        let mut indices: Vec<usize> = (0..rows.len()).collect();
+        indices.sort_by(|&i, &j| {
+            keys[i].0.cmp(&keys[j].0).then_with(|| compare_keys(&keys[i].1, &keys[j].1, &descending))
+        });
+
+        let mut result = vec![ValueType::Null; rows.len()];
+        let mut start = 0;
+        while start < indices.len() {
+            let mut end = start + 1;
+            while end < indices.len() && keys[indices[end]].0 == keys[indices[start]].0 {
+                end += 1;
+            }
+            let partition = &indices[start..end];
+            let peers: Vec<bool> = (0..partition.len())
+                .map(|pos| pos > 0 && keys[partition[pos - 1]].1 == keys[partition[pos]].1)
+                .collect();
+            self.compute_partition(rows, partition, &peers, &mut result);
+            start = end;
+        }
+        result
+    }
+
+    /// `peers[pos]` is true if the row at `pos` has the same order key as the row before it.
+    fn compute_partition(&self, rows: &Vec<Vec<ValueType>>, partition: &[usize], peers: &[bool], result: &mut Vec<ValueType>) {
+        match self.function {
+            WindowFunction::RowNumber => {
+                for (pos, &i) in partition.iter().enumerate() {
+                    result[i] = ValueType::Integer(pos as i64 + 1);
+                }
+            }
+            WindowFunction::Rank | WindowFunction::DenseRank => {
+                let mut rank = 0;
+                let mut dense_rank = 0;
+                for (pos, &i) in partition.iter().enumerate() {
+                    if !peers[pos] {
+                        rank = pos + 1;
+                        dense_rank += 1;
+                    }
+                    let rank = if self.function == WindowFunction::Rank { rank } else { dense_rank };
+                    result[i] = ValueType::Integer(rank as i64);
+                }
+            }
+            WindowFunction::Lag(offset) | WindowFunction::Lead(offset) => {
+                for (pos, &i) in partition.iter().enumerate() {
+                    let other = if self.function == WindowFunction::Lag(offset) {
+                        pos.checked_sub(offset)
+                    } else {
+                        Some(pos + offset).filter(|&other| other < partition.len())
+                    };
+                    result[i] = match other {
+                        Some(other) => self.args[0].eval(&rows[partition[other]]),
+                        None => ValueType::Null,
+                    };
+                }
+            }
+            WindowFunction::Aggregate(aggregator) => self.compute_aggregate(aggregator, rows, partition, peers, result),
+        }
+    }
+
+    fn compute_aggregate(
+        &self,
+        aggregator: Aggregator,
+        rows: &Vec<Vec<ValueType>>,
+        partition: &[usize],
+        peers: &[bool],
+        result: &mut Vec<ValueType>,
+    ) {
+        let reduce = |accumulator: &mut Accumulator, i: usize| {
+            let value = self.args[0].eval(&rows[i]);
+            match self.args.get(1) {
+                Some(arg) => aggregator.reduce_pair(accumulator, &value, &arg.eval(&rows[i])),
+                None => aggregator.reduce(accumulator, &value),
+            }
+        };
+        match self.frame {
+            Frame::Default if self.order_by.is_empty() => {
+                let mut accumulator = aggregator.zero();
+                for &i in partition {
+                    reduce(&mut accumulator, i);
+                }
+                let value = aggregator.finalize(accumulator);
+                for &i in partition {
+                    result[i] = value.clone();
+                }
+            }
+            Frame::Default => {
+                let mut accumulator = aggregator.zero();
+                let mut start = 0;
+                while start < partition.len() {
+                    let mut end = start + 1;
+                    while end < partition.len() && peers[end] {
+                        end += 1;
+                    }
+                    for &i in &partition[start..end] {
+                        reduce(&mut accumulator, i);
+                    }
+                    let value = aggregator.finalize(accumulator.clone());
+                    for &i in &partition[start..end] {
+                        result[i] = value.clone();
+                    }
+                    start = end;
+                }
+            }
+            Frame::Rows(None) => {
+                let mut accumulator = aggregator.zero();
+                for &i in partition {
+                    reduce(&mut accumulator, i);
+                    result[i] = aggregator.finalize(accumulator.clone());
+                }
+            }
+            Frame::Rows(Some(preceding)) => {
+                for (pos, &i) in partition.iter().enumerate() {
+                    let mut accumulator = aggregator.zero();
+                    for &j in &partition[pos.saturating_sub(preceding)..pos + 1] {
+                        reduce(&mut accumulator, j);
+                    }
+                    result[i] = aggregator.finalize(accumulator);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use columns::Batch;
+use expression::{Expr, FuncType};
+use value::ValueType;
+
+/// Selectivity assumed for equality comparisons, for which zone maps carry no information beyond the value range.
+pub const EQUALITY_SELECTIVITY: f64 = 0.1;
+/// Selectivity assumed for conditions that are not comparisons of a column with a constant.
+pub const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+
+/// Minimum and maximum value and number of nulls of each column of a batch, computed when the batch is loaded.
+/// Used to skip batches in which no row can satisfy a filter without reading them.
+pub struct ZoneMap {
+    rows: usize,
+    columns: HashMap<String, Zone>,
+}
+
+struct Zone {
+    /// Smallest and largest non-null value, `None` if all values are null.
+    range: Option<(ValueType, ValueType)>,
+    nulls: usize,
+}
+
+/// Comparison of a column of the scanned table with a constant, as `column op value`.
+#[derive(Debug, Clone)]
+pub struct ColumnPredicate {
+    column: String,
+    op: FuncType,
+    value: ValueType,
+}
+
+impl ZoneMap {
+    pub fn new(batch: &Batch) -> ZoneMap {
+        let mut columns = HashMap::new();
+        for col in &batch.cols {
+            let mut range: Option<(ValueType, ValueType)> = None;
+            let mut nulls = 0;
+            for value in col.iter() {
+                if value == ValueType::Null {
+                    nulls += 1;
+                    continue;
+                }
+                range = match range {
+                    None => Some((value.clone(), value)),
+                    Some((min, max)) => {
+                        if value < min {
+                            Some((value, max))
+                        } else if value > max {
+                            Some((min, value))
+                        } else {
+                            Some((min, max))
+                        }
+                    }
+                };
+            }
+            columns.insert(col.get_name().to_string(), Zone { range: range, nulls: nulls });
+        }
+        ZoneMap {
+            rows: batch.cols.first().map_or(0, |col| col.len()),
+            columns: columns,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Returns false if no row of the batch can satisfy `predicate`. Columns missing from the batch are all null.
+    pub fn may_match(&self, predicate: &ColumnPredicate) -> bool {
+        let range = match self.columns.get(&predicate.column) {
+            Some(&Zone { range: Some((ref min, ref max)), .. }) => (min, max),
+            // Comparisons of nulls with a value are never true
+            _ => return false,
+        };
+        match predicate.op {
+            FuncType::Equals => predicate.value >= *range.0 && predicate.value <= *range.1,
+            FuncType::LT => less(range.0, &predicate.value),
+            FuncType::GT => less(&predicate.value, range.1),
+            _ => true,
+        }
+    }
+
+    /// Estimated fraction of rows that satisfy `predicate`, assuming values are spread uniformly over their range.
+    pub fn selectivity(&self, predicate: &ColumnPredicate) -> f64 {
+        if !self.may_match(predicate) {
+            return 0.0;
+        }
+        let zone = &self.columns[&predicate.column];
+        let non_null = (self.rows - zone.nulls) as f64 / self.rows.max(1) as f64;
+        let &(ref min, ref max) = zone.range.as_ref().unwrap();
+        let fraction = match (predicate.op, min.as_f64(), max.as_f64(), predicate.value.as_f64()) {
+            (FuncType::Equals, _, _, _) => EQUALITY_SELECTIVITY,
+            (_, Some(min), Some(max), Some(_)) if max <= min => 1.0,
+            (FuncType::LT, Some(min), Some(max), Some(value)) => (value - min) / (max - min),
+            (FuncType::GT, Some(min), Some(max), Some(value)) => (max - value) / (max - min),
+            _ => DEFAULT_SELECTIVITY,
+        };
+        non_null * fraction.max(0.0).min(1.0)
+    }
+}
+
+/// Whether `v1 < v2`, comparing numbers of different types by value like `<` in queries.
+fn less(v1: &ValueType, v2: &ValueType) -> bool {
+    match v1.numeric_cmp(v2) {
+        Some(ordering) => ordering == Ordering::Less,
+        None => v1 < v2,
+    }
+}
+
+impl fmt::Display for ColumnPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            FuncType::LT => "<",
+            FuncType::GT => ">",
+            _ => "=",
+        };
+        write!(f, "{} {} {}", self.column, op, self.value)
+    }
+}
+
+impl ColumnPredicate {
+    /// Recognizes `=`, `<` and `>` comparisons between a column and a non-null constant. `resolve` maps column
+    /// references, by name or compiled, to columns of the scanned table.
+    pub fn from_expr<F: Fn(&Expr) -> Option<String>>(expr: &Expr, resolve: F) -> Option<ColumnPredicate> {
+        let (op, expr1, expr2) = match expr {
+            &Expr::Func(op, ref expr1, ref expr2) if op == FuncType::Equals || op == FuncType::LT || op == FuncType::GT => {
+                (op, expr1, expr2)
+            }
+            _ => return None,
+        };
+        let (column, value, op) = match (expr1.as_ref(), expr2.as_ref()) {
+            (&Expr::Const(_), &Expr::Const(_)) => return None,
+            (column, &Expr::Const(ref value)) => (column, value, op),
+            (&Expr::Const(ref value), column) => {
+                let op = match op {
+                    FuncType::LT => FuncType::GT,
+                    FuncType::GT => FuncType::LT,
+                    op => op,
+                };
+                (column, value, op)
+            }
+            _ => return None,
+        };
+        if *value == ValueType::Null {
+            return None;
+        }
+        resolve(column).map(|column| {
+            ColumnPredicate {
+                column: column,
+                op: op,
+                value: value.clone(),
+            }
+        })
+    }
+}